@@ -1,11 +1,24 @@
 // use crate::proto::c7_operation::c7_rps_client;
 
-use crate::cli::subcommands::{PatchParam, QueryParam, SearchParam};
+use crate::cli::subcommands::{
+    AuditParam, GroupEditParam, GroupParam, LearnParam, MatchParam, PatchParam, QueryParam,
+    SearchParam, SnapshotParam, TestFormat,
+};
 
 use super::subcommands;
+use crate::clock::parse_duration;
+use crate::policy::backend::{new_backend, BackendKind};
+use crate::policy::group::GroupKind;
+use crate::policy::responder::Answer;
+use crate::ui::output::OutputFormat;
+use crate::ui::theme::{self, ColorChoice};
 use aclneko::acl::Acl;
-use aclneko::io::{read_policy_file, POLICY_INTERFACE_PATH};
-use clap::{Parser, Subcommand};
+use aclneko::io::read_policy_file;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
 
 // pub struct Command {}
 
@@ -14,9 +27,17 @@ pub struct Cli {
     #[command(subcommand)]
     command: Command,
 
-    /// target policy file
-    #[arg(short,default_value_t=String::from(POLICY_INTERFACE_PATH))]
-    file: String,
+    /// target policy file [default: the policy interface of the backend]
+    #[arg(short)]
+    file: Option<String>,
+
+    /// root directory under which caitsith interfaces are resolved
+    #[arg(long)]
+    root: Option<String>,
+
+    /// policy backend [default: emulator with --root, securityfs otherwise]
+    #[arg(long, value_enum)]
+    backend: Option<BackendKind>,
 
     /// increase verbosity
     #[arg(short, long, default_value_t = false)]
@@ -25,6 +46,19 @@ pub struct Cli {
     /// turn on debug mode
     #[arg(short, long, default_value_t = false)]
     debug: bool,
+
+    /// do not save snapshots before policy modifications
+    #[arg(long, default_value_t = false)]
+    no_snapshot: bool,
+
+    /// output format of list, search, query, dump, diff, stat, check, lint,
+    /// groups, group show and audit
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// use colors in the output; auto respects NO_COLOR and terminals
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
 }
 
 /// An alternative policy management interface for Caitsith
@@ -43,6 +77,67 @@ pub enum Command {
         #[arg(short, long, default_value_t = false)]
         json: bool,
     },
+    /// Compare two policies ACL by ACL
+    Diff {
+        /// policy to compare from (live, boot, a policy file or - for stdin)
+        source: String,
+        /// policy to compare with (defaults to the target policy file)
+        target: Option<String>,
+    },
+    /// Show policy statistics
+    Stat {
+        /// priority or operation of target ACL blocks
+        query: Option<String>,
+    },
+    /// Show or edit members of a group
+    Group {
+        #[command(subcommand)]
+        action: GroupAction,
+    },
+    /// Evaluate a hypothetical request with the policy
+    Check {
+        /// operation of the request (e.g. execute)
+        operation: String,
+        /// attributes of the request (e.g. path=/usr/bin/curl task.uid=1000)
+        attrs: Vec<String>,
+    },
+    /// Test whether a path matches a pattern and explain the match
+    Match {
+        /// try every member of the string_group instead of a pattern
+        #[arg(short, long)]
+        group: Option<String>,
+        /// pattern and path, or path with --group
+        #[arg(required = true, num_args = 1..=2, value_names = ["PATTERN", "PATH"])]
+        args: Vec<String>,
+    },
+    /// Run test cases of hypothetical requests against a policy
+    Test {
+        /// policy to be tested: live, boot, - for stdin or a file path
+        policy: String,
+        /// test files in TOML
+        #[arg(required = true)]
+        tests: Vec<String>,
+        /// output format
+        #[arg(long, value_enum, default_value_t = TestFormat::Text)]
+        format: TestFormat,
+    },
+    /// Report problems in a policy
+    Lint {
+        /// policy source: live, boot, - for stdin or a file path [default: the target policy]
+        source: Option<String>,
+    },
+    /// List groups with their members and references
+    Groups {
+        /// list groups of the kind, inferred from references for undefined groups
+        #[arg(short, long, value_enum)]
+        kind: Option<GroupKind>,
+        /// list groups which are referenced but never defined
+        #[arg(long, default_value_t = false)]
+        undefined: bool,
+        /// list groups which are defined but never referenced
+        #[arg(long, default_value_t = false)]
+        unused: bool,
+    },
     /// Search ACLs
     #[command(alias = "s")]
     Search {
@@ -57,6 +152,18 @@ pub enum Command {
         /// search ACLs with given regex pattern
         #[arg(long, default_value_t = false)]
         regex: bool,
+        /// search ACLs whose path conditions can be satisfied by the path
+        #[arg(long)]
+        path: Option<String>,
+        /// search ACLs whose conditions can be satisfied by the executable
+        #[arg(long)]
+        exe: Option<String>,
+        /// search ACLs whose conditions can be satisfied by the uid
+        #[arg(long)]
+        uid: Option<String>,
+        /// search ACLs whose conditions can be satisfied by the domain
+        #[arg(long)]
+        domain: Option<String>,
     },
     /// Query policy violation
     #[command(alias = "q")]
@@ -64,9 +171,103 @@ pub enum Command {
         /// pattern to filter targets for interactive triage
         #[arg(short, long)]
         pattern: Option<String>,
+        /// answer queries automatically with rules in a TOML file
+        #[arg(long)]
+        rules: Option<String>,
+        /// journal of query sessions [default: query-journal.log in the state directory]
+        #[arg(long)]
+        journal: Option<String>,
+        /// show the journal of query sessions instead of querying
+        #[arg(long, default_value_t = false)]
+        history: bool,
+        /// handle queries in a full-screen interface with the list of pending queries
+        #[arg(long, default_value_t = false, conflicts_with = "rules")]
+        tui: bool,
+        /// append text read from the query interface to the file as a transcript
+        #[arg(long)]
+        record: Option<String>,
+        /// parse a recorded transcript (- for stdin) and print the queries in it
+        #[arg(long, conflicts_with_all = ["rules", "tui", "history", "record"])]
+        transcript: Option<String>,
+        /// answer a query automatically if no command is given in the duration, such as 30s or 5m
+        #[arg(long, value_parser = parse_duration, conflicts_with = "rules")]
+        timeout: Option<Duration>,
+        /// answer for queries timed out
+        #[arg(long, value_enum, default_value_t = Answer::Deny, requires = "timeout")]
+        on_timeout: Answer,
+        /// answer for queries left when the session stops with q, SIGINT or SIGTERM
+        #[arg(long, default_value = "deny", value_parser = exit_answer_parser())]
+        on_exit: Answer,
         // #[arg(short, long, default_value_t = true)]
         // color: bool,
     },
+    /// Permit violations and propose a policy patch from them
+    Learn {
+        /// read violations from a saved audit log (- for stdin) instead of the query interface
+        #[arg(long)]
+        log: Option<String>,
+        /// stop after learning at least the number of violations from the query interface
+        #[arg(short, long)]
+        count: Option<usize>,
+        /// write the proposed patch to the file, updated for every violation
+        #[arg(long)]
+        out: Option<String>,
+        /// highest priority of proposed rules, lowered to precede deny rules of the block
+        #[arg(long, default_value_t = 10)]
+        priority: u16,
+        /// number of names in a directory to be generalized into a wildcard
+        #[arg(long, default_value_t = 3)]
+        min_siblings: usize,
+    },
+    /// Derive a patch from the rules added in a query journal
+    Replay {
+        /// query journal (- for stdin)
+        journal: String,
+        /// write the patch to the file
+        #[arg(long)]
+        out: Option<String>,
+    },
+    /// Filter, group and count records of an audit log
+    Audit {
+        /// audit log to be read (- for stdin)
+        source: String,
+        /// select records with the result (e.g. denied)
+        #[arg(long)]
+        result: Option<String>,
+        /// select records whose operation matches the regex
+        #[arg(long)]
+        operation: Option<String>,
+        /// select records whose task.exe matches the regex
+        #[arg(long)]
+        exe: Option<String>,
+        /// select records whose task.domain matches the regex
+        #[arg(long)]
+        domain: Option<String>,
+        /// select records whose task.uid matches the regex
+        #[arg(long)]
+        uid: Option<String>,
+        /// select records whose path matches the regex
+        #[arg(long)]
+        path: Option<String>,
+        /// select records whose field matches the regex (e.g. --where path.type=^socket$)
+        #[arg(long = "where", value_name = "FIELD=REGEX")]
+        filters: Vec<String>,
+        /// select records at or after the time (YYYY/MM/DD[ hh:mm:ss] or today)
+        #[arg(long)]
+        since: Option<String>,
+        /// select records at or before the time (YYYY/MM/DD[ hh:mm:ss] or today)
+        #[arg(long)]
+        until: Option<String>,
+        /// count records grouped by the fields (e.g. exe,operation)
+        #[arg(short, long, value_delimiter = ',')]
+        group_by: Vec<String>,
+        /// show only the N largest groups
+        #[arg(long, requires = "group_by")]
+        top: Option<usize>,
+        /// output records or counts in CSV
+        #[arg(long, default_value_t = false)]
+        csv: bool,
+    },
     /// Apply policy patch
     #[command(alias = "a")]
     Apply {
@@ -81,6 +282,21 @@ pub enum Command {
         /// do not confirm changes for the policy modification
         #[arg(short, long, default_value_t = false)]
         yes: bool,
+        /// show the resulting changes without modifying the policy
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+        /// revert the patch unless `acquery confirm` is run within the seconds
+        #[arg(long, value_name = "SECS")]
+        confirm_within: Option<u64>,
+    },
+    /// Confirm the change applied with --confirm-within
+    Confirm {
+        /// revert the pending change right now
+        #[arg(long, default_value_t = false)]
+        revert: bool,
+        /// wait for the deadline and revert the change unless confirmed
+        #[arg(long, default_value_t = false, hide = true)]
+        watch: bool,
     },
     /// Remove policy patch
     #[command(alias = "r")]
@@ -99,6 +315,25 @@ pub enum Command {
         /// do not confirm changes for the policy modification
         #[arg(short, long, default_value_t = false)]
         yes: bool,
+        /// show the resulting changes without modifying the policy
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Manage snapshots of the live policy
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
+    /// Roll back the policy to a snapshot
+    Rollback {
+        /// snapshot name to roll back to
+        name: String,
+        /// do not confirm changes for the policy modification
+        #[arg(short, long, default_value_t = false)]
+        yes: bool,
+        /// show the policy lines to be written without modifying the policy
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
     },
     /// Flush all preset policy
     Clear {},
@@ -106,73 +341,337 @@ pub enum Command {
     Reload {},
 }
 
+#[derive(Subcommand, Debug)]
+pub enum GroupAction {
+    /// Add a member to a group in the live policy
+    Add {
+        /// group name
+        name: String,
+        /// path pattern, number (range) or IP address (range) to be added
+        member: String,
+        /// kind of the group, required for a new group
+        #[arg(short, long, value_enum)]
+        kind: Option<GroupKind>,
+        /// do not confirm changes for the policy modification
+        #[arg(short, long, default_value_t = false)]
+        yes: bool,
+        /// show the policy line to be written without modifying the policy
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Remove a member from a group in the live policy
+    #[command(alias = "rm")]
+    Remove {
+        /// group name
+        name: String,
+        /// member to be removed
+        member: String,
+        /// do not confirm changes for the policy modification
+        #[arg(short, long, default_value_t = false)]
+        yes: bool,
+        /// show the policy line to be written without modifying the policy
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Show a group with its members and references
+    Show {
+        /// group name
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SnapshotAction {
+    /// Save the live policy as a snapshot
+    Save {
+        /// snapshot name, which must not exist yet [default: current time]
+        name: Option<String>,
+        /// description for the snapshot
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    /// List snapshots
+    #[command(alias = "ls")]
+    List {},
+    /// Show the policy saved in a snapshot
+    Show {
+        /// snapshot name
+        name: String,
+    },
+    /// Remove a snapshot
+    #[command(alias = "rm")]
+    Remove {
+        /// snapshot name
+        name: String,
+    },
+}
+
 pub fn run() -> Result<(), String> {
     let args = Cli::parse();
+    theme::init(args.color);
+    let kind = args.backend.unwrap_or(match args.root {
+        Some(_) => BackendKind::Emulator,
+        None => BackendKind::Securityfs,
+    });
+    let backend = new_backend(kind, Path::new(args.root.as_deref().unwrap_or("/")));
+    let file = args
+        .file
+        .unwrap_or(backend.policy_interface_path().display().to_string());
     let mut acl = Acl::new();
     match args.command {
-        Command::Query { pattern } => {}
+        Command::Query { .. }
+        | Command::Lint { .. }
+        | Command::Test { .. }
+        | Command::Snapshot { .. }
+        | Command::Confirm { .. }
+        | Command::Audit { .. }
+        | Command::Replay { .. }
+        | Command::Match { group: None, .. }
+        | Command::Group {
+            action: GroupAction::Add { .. } | GroupAction::Remove { .. },
+        }
+        | Command::Diff { .. } => {}
         _ => {
-            acl = read_policy_file(&args.file)?;
+            acl = read_policy_file(&file).map_err(|e| format!("{}: {}", file, e))?;
         }
     }
-    let args = Cli::parse();
     let cmd = subcommands::Subcommands {
         acl: &acl,
+        backend,
         is_verbose: args.verbose,
         debug: args.debug,
+        snapshot: !args.no_snapshot,
+        output: args.output,
+        file: file.clone(),
     };
     match args.command {
         Command::List {
             patches: for_patches,
         } => cmd.list_cmd(for_patches),
         Command::Dump { json } => cmd.dump_cmd(json),
+        Command::Diff { source, target } => cmd.diff_cmd(&source, &target.unwrap_or(file)),
+        Command::Stat { query } => cmd.stat_cmd(&file, query),
+        Command::Groups {
+            kind,
+            undefined,
+            unused,
+        } => cmd.groups_cmd(GroupParam {
+            kind,
+            undefined,
+            unused,
+        }),
+        Command::Group { action } => match action {
+            GroupAction::Add {
+                name,
+                member,
+                kind,
+                yes,
+                dry_run,
+            } => cmd.group_edit_cmd(GroupEditParam::Add { name, member, kind }, yes, dry_run),
+            GroupAction::Remove {
+                name,
+                member,
+                yes,
+                dry_run,
+            } => cmd.group_edit_cmd(GroupEditParam::Remove { name, member }, yes, dry_run),
+            GroupAction::Show { name } => cmd.group_show_cmd(&name),
+        },
+        Command::Check { operation, attrs } => cmd.check_cmd(&operation, &attrs),
+        Command::Match { group, args } => match (group, args.as_slice()) {
+            (Some(g), [path]) => cmd.match_cmd(&MatchParam::Group(g), path),
+            (None, [pattern, path]) => cmd.match_cmd(&MatchParam::Pattern(pattern.clone()), path),
+            (Some(_), _) => Err("a path is required with --group".to_string()),
+            (None, _) => Err("a pattern and a path are required".to_string()),
+        },
+        Command::Test {
+            policy,
+            tests,
+            format,
+        } => cmd.test_cmd(&policy, &tests, format),
+        Command::Lint { source } => cmd.lint_cmd(&source.unwrap_or(file)),
         Command::Search {
             pattern,
             rule,
             headeronly,
             regex,
+            path,
+            exe,
+            uid,
+            domain,
         } => cmd.search_cmd(
             pattern,
             SearchParam {
                 header_only: headeronly,
                 search_rule: rule,
                 with_regex: regex,
+                subjects: search_subjects(path, exe, uid, domain),
             },
         ),
+        Command::Audit {
+            source,
+            result,
+            operation,
+            exe,
+            domain,
+            uid,
+            path,
+            filters,
+            since,
+            until,
+            group_by,
+            top,
+            csv,
+        } => {
+            let mut conds: Vec<(String, String)> = [
+                ("result", result.map(|r| format!("^{}$", regex::escape(&r)))),
+                ("operation", operation),
+                ("task.exe", exe),
+                ("task.domain", domain),
+                ("task.uid", uid),
+                ("path", path),
+            ]
+            .into_iter()
+            .filter_map(|(k, v)| v.map(|v| (k.to_string(), v)))
+            .collect();
+            for f in filters {
+                let (k, v) = f
+                    .split_once('=')
+                    .ok_or(format!("invalid filter (expected FIELD=REGEX): {}", f))?;
+                conds.push((k.to_string(), v.to_string()));
+            }
+            cmd.audit_cmd(AuditParam {
+                source,
+                conds,
+                since,
+                until,
+                group_by,
+                top,
+                csv,
+            })
+        }
         Command::Apply {
             source,
             atomic,
             op,
             yes,
+            dry_run,
+            confirm_within,
         } => cmd.apply_cmd(
             source.as_ref(),
             PatchParam {
-                atomic: atomic,
+                atomic,
                 operation: op,
                 assume_yes: yes,
                 unmerge: false,
+                dry_run,
+                confirm_within,
             },
         ),
+        Command::Confirm { revert, watch } => cmd.confirm_cmd(revert, watch),
         Command::Remove {
             source,
             atomic,
             unmerge,
             op,
             yes,
+            dry_run,
         } => cmd.remove_cmd(
             source.as_ref(),
             PatchParam {
-                atomic: atomic,
+                atomic,
                 operation: op,
                 assume_yes: yes,
-                unmerge: unmerge,
+                unmerge,
+                dry_run,
+                confirm_within: None,
             },
         ),
-        Command::Query { pattern } => cmd.query_cmd(QueryParam {
-            pattern: pattern,
-            color: true,
+        Command::Learn {
+            log,
+            count,
+            out,
+            priority,
+            min_siblings,
+        } => cmd.learn_cmd(LearnParam {
+            log,
+            count,
+            out,
+            priority,
+            min_siblings,
         }),
+        Command::Replay { journal, out } => cmd.replay_cmd(&journal, out),
+        Command::Query {
+            pattern,
+            rules,
+            journal,
+            history,
+            tui,
+            record,
+            transcript,
+            timeout,
+            on_timeout,
+            on_exit,
+        } => cmd.query_cmd(QueryParam {
+            pattern,
+            rules,
+            journal,
+            history,
+            tui,
+            record,
+            transcript,
+            timeout,
+            on_timeout,
+            on_exit,
+            color: theme::current().stderr,
+        }),
+        Command::Snapshot { action } => cmd.snapshot_cmd(match action {
+            SnapshotAction::Save { name, message } => SnapshotParam::Save { name, message },
+            SnapshotAction::List {} => SnapshotParam::List,
+            SnapshotAction::Show { name } => SnapshotParam::Show(name),
+            SnapshotAction::Remove { name } => SnapshotParam::Remove(name),
+        }),
+        Command::Rollback { name, yes, dry_run } => cmd.rollback_cmd(&name, yes, dry_run),
         Command::Reload {} => cmd.reload_cmd(),
         Command::Clear {} => cmd.clear_cmd(),
     }
 }
+
+/// exit_answer_parser parses answers for queries left at the end of a query
+/// session. Permitting them is not allowed, since nobody has reviewed them.
+///
+fn exit_answer_parser() -> impl TypedValueParser<Value = Answer> {
+    PossibleValuesParser::new([Answer::Deny.as_str(), Answer::Reevaluate.as_str()])
+        .map(|s| Answer::from_str(&s, true).unwrap())
+}
+
+/// search_subjects returns the alternative sets of attributes for `search`.
+/// A path is searched as each of `path`, `old_path` and `new_path`, since a
+/// request has only some of them.
+///
+fn search_subjects(
+    path: Option<String>,
+    exe: Option<String>,
+    uid: Option<String>,
+    domain: Option<String>,
+) -> Vec<BTreeMap<String, String>> {
+    let subject: BTreeMap<String, String> = [
+        ("task.exe", exe),
+        ("task.uid", uid),
+        ("task.domain", domain),
+    ]
+    .into_iter()
+    .filter_map(|(k, v)| v.map(|v| (k.to_string(), v)))
+    .collect();
+    match path {
+        Some(p) => ["path", "old_path", "new_path"]
+            .iter()
+            .map(|k| {
+                let mut s = subject.clone();
+                s.insert(k.to_string(), p.clone());
+                s
+            })
+            .collect(),
+        None if subject.is_empty() => vec![],
+        None => vec![subject],
+    }
+}
@@ -1,7 +1,21 @@
+use crate::policy::audit::{AuditEvent, GroupCount};
+use crate::policy::backend::PolicyBackend;
+use crate::policy::diff::{AclDiff, BlockChange, LineTag};
+use crate::policy::group::GroupReport;
+use crate::policy::journal::{JournalAction, JournalEntry};
+use crate::policy::pattern::Explanation;
+use crate::policy::stat::{AclStat, Preamble};
+use crate::policy::suite::CaseResult;
+use crate::ui::theme::{paint, Style};
 use aclneko::acl::Acl;
-use aclneko::syntax::{Op, Resource, Verb};
-use std::io::stdin;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{stdin, Read};
+use std::path::PathBuf;
 use std::str;
+use std::str::FromStr;
+
+pub const INDENT: &str = "    ";
 
 pub fn prompt(msg: &str) -> bool {
     eprint!("{}", msg);
@@ -22,95 +36,406 @@ pub fn prompt(msg: &str) -> bool {
     }
 }
 
-// pub fn show_stat(mut acl: Acl, line: Option<&str>) -> Result<(), String> {
-//     let mut is_op_query = false;
-//     match line {
-//         Some(n) => {
-//             acl = acl.get_acl_by_header(n.clone())?;
-//             println!("query: {}", n);
-//             if Op::from(n) != Op::Error {
-//                 is_op_query = true;
-//             }
-//         }
-//         None => {}
-//     };
-//
-//     let mut c = 0;
-//     for i in acl.rule_len() {
-//         c += i;
-//     }
-//     println!("total ACLs: {}", acl.len());
-//     println!("total rules: {}", c);
-//
-//     let mut a = 0;
-//     let mut d = 0;
-//     for v in acl.rule_count_verb(Verb::Allow) {
-//         a += v;
-//     }
-//     for v in acl.rule_count_verb(Verb::Deny) {
-//         d += v;
-//     }
-//     println!("allow: {}  deny: {}", a, d);
-//     println!();
-//
-//     let mut op_buf = vec![];
-//     for o in Op::list() {
-//         let op_count = acl.count_op(o);
-//         if op_count != 0 {
-//             op_buf.push((o, op_count));
-//         }
-//     }
-//     if is_op_query == false {
-//         println!("[operation statics for headers]");
-//         let mut i = 1;
-//         for o in op_buf {
-//             if i % 4 != 0 {
-//                 print!("  {}: {}\t", o.0.as_str(), o.1);
-//             } else {
-//                 println!("  {}: {}", o.0.as_str(), o.1);
-//             }
-//             i += 1;
-//         }
-//         if (i - 1) % 4 != 0 {
-//             println!();
-//         }
-//         println!();
-//     }
-//
-//     println!("[resource statics for headers]");
-//     let mut res_buf = vec![];
-//     let mut i = 1;
-//     for r in Resource::list() {
-//         let filter = move |header: AclHeader| {
-//             for attr in &header.attr {
-//                 if attr.0 == r {
-//                     return true;
-//                 }
-//             }
-//             false
-//         };
-//         let res_count = acl.count(filter);
-//         if res_count != 0 {
-//             res_buf.push((r, res_count));
-//         }
-//     }
-//     for r in res_buf {
-//         if i % 4 != 0 {
-//             print!("  {}: {}\t", r.0.as_str(), r.1);
-//         } else {
-//             println!("  {}: {}", r.0.as_str(), r.1);
-//         }
-//         i += 1;
-//     }
-//     if (i - 1) % 4 != 0 {
-//         println!();
-//     }
-//     Ok(())
-// }
-
-// show difference between source Acl and destination Acl.
-//
-// pub fn diff_acls(src: &Acl, dst: &Acl) {
-//     let window_size = if src.len() > dst.len() { src.len() } else { dst.len() } ;
-// }
-//
+/// read_policy_source reads a policy from a source specifier.
+///
+/// `live` is the policy interface of the backend, `boot` is the system policy
+/// file loaded on boot and `-` is stdin. Any other specifier is assumed
+/// to be a path to a policy file or a patch.
+///
+pub fn read_policy_source(backend: &dyn PolicyBackend, source: &str) -> Result<Acl, String> {
+    Acl::from_str(&read_policy_text(backend, source)?)
+}
+
+/// read_policy_text reads raw policy text from a source specifier in the same
+/// way as `read_policy_source`. Lines which Acl does not hold, such as `stat`
+/// and `quota` lines, are kept in the text.
+///
+pub fn read_policy_text(backend: &dyn PolicyBackend, source: &str) -> Result<String, String> {
+    let mut buf = vec![];
+    let path = match source {
+        "live" => backend.policy_interface_path(),
+        "boot" => backend.policy_file_path(),
+        "-" => {
+            stdin().read_to_end(&mut buf).map_err(|e| e.to_string())?;
+            return String::from_utf8(buf).map_err(|e| e.to_string());
+        }
+        _ => PathBuf::from(source),
+    };
+    File::open(&path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .read_to_end(&mut buf)
+        .map_err(|e| e.to_string())?;
+    String::from_utf8(buf).map_err(|e| e.to_string())
+}
+
+/// print_diff prints an AclDiff in unified format with colors.
+/// Changed ACL blocks are headed with `@@` and keep unchanged rules as
+/// context, while added and removed blocks are printed entirely.
+///
+pub fn print_diff(diff: &AclDiff, from: &str, to: &str) {
+    println!("{}", paint(Style::Bold, format!("--- {}", from)));
+    println!("{}", paint(Style::Bold, format!("+++ {}", to)));
+    for b in &diff.blocks {
+        match b.change {
+            BlockChange::Changed => {
+                println!("{}", paint(Style::Changed, format!("@@ {} @@", b.header)))
+            }
+            BlockChange::Added => println!("{}", paint(Style::Added, format!("+{}", b.header))),
+            BlockChange::Removed => {
+                println!("{}", paint(Style::Removed, format!("-{}", b.header)))
+            }
+        }
+        for (tag, rule) in &b.lines {
+            let line = format!("{}{}{}", tag.as_str(), INDENT, rule);
+            match tag {
+                LineTag::Keep => println!("{}", line),
+                LineTag::Add => println!("{}", paint(Style::Added, line)),
+                LineTag::Remove => println!("{}", paint(Style::Removed, line)),
+            }
+        }
+    }
+}
+
+/// show_stat prints the statistics report for the Acl.
+/// Counters in the policy preamble are printed if supplied, and operation
+/// statistics are omitted for an operation query.
+///
+pub fn show_stat(stat: &AclStat, preamble: Option<&Preamble>, is_op_query: bool) {
+    if let Some(p) = preamble.filter(|p| !p.is_empty()) {
+        println!("[policy statistics]");
+        if let Some(v) = p.version {
+            println!("  version: {}", v);
+        }
+        for (name, c) in [
+            ("policy updated", &p.policy_updated),
+            ("requests denied", &p.requests_denied),
+        ] {
+            if let Some(c) = c {
+                match &c.last {
+                    Some(last) => println!("  {}: {} (last: {})", name, c.count, last),
+                    None => println!("  {}: {}", name, c.count),
+                }
+            }
+        }
+        for (kind, size) in &p.memory_used {
+            match p.memory_quota.get(kind) {
+                Some(quota) => println!("  memory used by {}: {} / {}", kind, size, quota),
+                None => println!("  memory used by {}: {}", kind, size),
+            }
+        }
+        for (i, q) in &p.audit_quota {
+            println!(
+                "  audit[{}] quota: allowed={} denied={} unmatched={}",
+                i, q.allowed, q.denied, q.unmatched
+            );
+        }
+        println!();
+    }
+
+    println!("total ACLs: {}", stat.acls);
+    println!("total rules: {}", stat.rules);
+    println!(
+        "allow: {}  deny: {}  audit lines: {}",
+        stat.allow, stat.deny, stat.audit
+    );
+    println!();
+
+    if !is_op_query {
+        println!("[operation statics for headers]");
+        print_columns(&stat.operations);
+        println!();
+    }
+
+    println!("[resource statics for headers]");
+    print_columns(&stat.resources);
+}
+
+/// show_group prints a group with its members and ACL blocks referencing
+/// it, flagging an undefined or unused group.
+///
+pub fn show_group(g: &GroupReport) {
+    match g.kind {
+        Some(k) => print!(
+            "{}",
+            paint(Style::Bold, format!("{} {}", k.as_str(), g.name))
+        ),
+        None => print!("{}", paint(Style::Bold, format!("@{}", g.name))),
+    }
+    match (g.is_undefined(), g.referenced_as) {
+        (true, Some(k)) => print!(
+            " {}",
+            paint(Style::Error, format!("(undefined {})", k.as_str()))
+        ),
+        (true, None) => print!(" {}", paint(Style::Error, "(undefined)")),
+        _ => {}
+    }
+    if g.is_unused() {
+        print!(" {}", paint(Style::Warning, "(unused)"));
+    }
+    println!();
+    if !g.members.is_empty() {
+        println!("  members:");
+        for m in &g.members {
+            println!("{}{}", INDENT, m);
+        }
+    }
+    if !g.references.is_empty() {
+        println!("  references:");
+        for r in &g.references {
+            println!("{}{}", INDENT, r.header);
+            for rule in &r.rules {
+                println!("{}{}{}", INDENT, INDENT, rule);
+            }
+        }
+    }
+}
+
+/// print_explanation prints the text consumed by each token of a pattern,
+/// with the token which failed to match.
+///
+pub fn print_explanation(e: &Explanation) {
+    let width = e
+        .parts
+        .iter()
+        .map(|p| p.0.len())
+        .chain(e.failed.iter().map(|f| f.len()))
+        .max()
+        .unwrap_or(0);
+    for (token, text) in &e.parts {
+        match text.is_empty() {
+            true => println!(
+                "{}{:<width$}  {}",
+                INDENT,
+                token,
+                paint(Style::Dim, "(empty)"),
+                width = width
+            ),
+            false => println!("{}{:<width$}  {}", INDENT, token, text, width = width),
+        }
+    }
+    match &e.failed {
+        Some(f) if e.excluded => println!(
+            "{}{}",
+            INDENT,
+            paint(
+                Style::Removed,
+                format!("{:<width$}  excluded by subtraction", f, width = width)
+            )
+        ),
+        Some(f) => println!(
+            "{}{}",
+            INDENT,
+            paint(
+                Style::Removed,
+                format!("{:<width$}  does not match `{}`", f, e.rest, width = width)
+            )
+        ),
+        None if !e.matched => println!(
+            "{}{}",
+            INDENT,
+            paint(
+                Style::Removed,
+                format!("trailing `{}` is not matched", e.rest)
+            )
+        ),
+        None => {}
+    }
+}
+
+/// print_tap prints results of test cases in TAP version 13.
+///
+pub fn print_tap(results: &[CaseResult]) {
+    println!("TAP version 13");
+    println!("1..{}", results.len());
+    for (i, r) in results.iter().enumerate() {
+        match r.is_ok() {
+            true => println!("ok {} - {}: {}", i + 1, r.file, r.title),
+            false => {
+                println!("not ok {} - {}: {}", i + 1, r.file, r.title);
+                println!("  ---");
+                println!("  message: {:?}", r.failures.join("; "));
+                if let Some(c) = &r.result {
+                    println!("  verdict: {}", c.verdict.as_str());
+                    if let Some(rule) = &c.rule {
+                        println!("  rule: {:?}", rule);
+                    }
+                }
+                println!("  ...");
+            }
+        }
+    }
+}
+
+/// print_junit prints results of test cases in JUnit XML, with a testsuite
+/// for each test file.
+///
+pub fn print_junit(results: &[CaseResult]) {
+    let failures = results.iter().filter(|r| !r.is_ok()).count();
+    println!(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    println!(
+        r#"<testsuites name="acquery" tests="{}" failures="{}">"#,
+        results.len(),
+        failures
+    );
+    let mut files: Vec<&str> = results.iter().map(|r| r.file.as_str()).collect();
+    files.dedup();
+    for f in files {
+        let cases: Vec<&CaseResult> = results.iter().filter(|r| r.file == f).collect();
+        println!(
+            r#"  <testsuite name="{}" tests="{}" failures="{}">"#,
+            escape_xml(f),
+            cases.len(),
+            cases.iter().filter(|r| !r.is_ok()).count()
+        );
+        for c in cases {
+            let name = escape_xml(&c.title);
+            let class = escape_xml(f);
+            match c.is_ok() {
+                true => println!(r#"    <testcase name="{}" classname="{}"/>"#, name, class),
+                false => {
+                    println!(r#"    <testcase name="{}" classname="{}">"#, name, class);
+                    println!(
+                        r#"      <failure message="{}"/>"#,
+                        escape_xml(&c.failures.join("; "))
+                    );
+                    println!("    </testcase>");
+                }
+            }
+        }
+        println!("  </testsuite>");
+    }
+    println!("</testsuites>");
+}
+
+/// print_event prints an audit record in a line with the time, the result,
+/// the operation, the subject and the object.
+///
+pub fn print_event(e: &AuditEvent) {
+    let result = e.result.as_deref().unwrap_or("-");
+    let style = match result {
+        "denied" => Style::Rejected,
+        "allowed" => Style::Permitted,
+        _ => Style::Dim,
+    };
+    println!(
+        "{} {} {} {} ({}, uid={}) {}",
+        paint(Style::Dim, e.time.as_deref().unwrap_or("-")),
+        paint(style, result),
+        paint(Style::Bold, &e.operation),
+        e.field("exe").unwrap_or("-".to_string()),
+        e.field("domain").unwrap_or("-".to_string()),
+        e.field("uid").unwrap_or("-".to_string()),
+        e.object().join(" ")
+    );
+}
+
+/// print_journal_entry prints an entry of the query journal in a line, with
+/// the rule added and the audit record if `verbose`.
+///
+pub fn print_journal_entry(e: &JournalEntry, verbose: bool) {
+    let style = match e.action {
+        JournalAction::Permit => Style::Permitted,
+        JournalAction::Deny => Style::Rejected,
+        JournalAction::Reevaluate => Style::Reevaluated,
+        JournalAction::Add => Style::Added,
+    };
+    let summary = match AuditEvent::parse(0, &e.record) {
+        Some(a) => format!(
+            "{} {} {}",
+            a.operation,
+            a.field("exe").unwrap_or("-".to_string()),
+            a.object().join(" ")
+        ),
+        None => e.record.clone(),
+    };
+    println!(
+        "{} {} Q{} {} {}{}",
+        paint(Style::Dim, &e.time),
+        e.operator,
+        e.query_id,
+        paint(style, e.action.as_str()),
+        summary,
+        e.by.as_ref()
+            .map(|b| paint(Style::Dim, format!(" (by {})", b)))
+            .unwrap_or_default()
+    );
+    if let (Some(h), Some(r)) = (&e.header, &e.rule) {
+        println!(
+            "{}{}",
+            INDENT,
+            paint(Style::Added, format!("{} / {}", h, r))
+        );
+    }
+    if verbose {
+        println!("{}{}", INDENT, paint(Style::Dim, &e.record));
+    }
+}
+
+/// print_counts prints counts of audit records as a table with the grouping
+/// fields.
+///
+pub fn print_counts(fields: &[String], counts: &[GroupCount]) {
+    let width = counts
+        .iter()
+        .map(|c| c.count.to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("count".len());
+    println!(
+        "{}",
+        paint(
+            Style::Bold,
+            format!("{:>width$}  {}", "count", fields.join("  "))
+        )
+    );
+    for c in counts {
+        let values: Vec<&str> = c
+            .values
+            .iter()
+            .map(|v| if v.is_empty() { "-" } else { v.as_str() })
+            .collect();
+        println!("{:>width$}  {}", c.count, values.join("  "));
+    }
+}
+
+/// print_csv prints the header and rows in CSV, quoting fields with commas,
+/// quotes or newlines.
+///
+pub fn print_csv(header: &[String], rows: impl Iterator<Item = Vec<String>>) {
+    let line = |fields: &[String]| {
+        fields
+            .iter()
+            .map(|f| match f.contains([',', '"', '\n', '\r']) {
+                true => format!("\"{}\"", f.replace('"', "\"\"")),
+                false => f.clone(),
+            })
+            .collect::<Vec<String>>()
+            .join(",")
+    };
+    println!("{}", line(header));
+    for r in rows {
+        println!("{}", line(&r));
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn print_columns(counts: &BTreeMap<String, usize>) {
+    let mut i = 1;
+    for (k, v) in counts {
+        if i % 4 != 0 {
+            print!("  {}: {}\t", k, v);
+        } else {
+            println!("  {}: {}", k, v);
+        }
+        i += 1;
+    }
+    if (i - 1) % 4 != 0 {
+        println!();
+    }
+}
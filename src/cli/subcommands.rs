@@ -1,23 +1,63 @@
 use super::functions;
-use crate::ui::query as pquery;
+use crate::clock::{format_timestamp, now};
+use crate::policy::audit::{count_by, parse_events, validate_time, AuditEvent, EventFilter};
+use crate::policy::backend::PolicyBackend;
+use crate::policy::check::{check_request, filter_blocks_any, Request, Verdict};
+use crate::policy::confirm::{AppliedPatch, PendingChange, PendingStore};
+use crate::policy::diff::diff_acls;
+use crate::policy::export::{block_entries, header_entry, HeaderEntry};
+use crate::policy::group::{analyze_groups, validate_member, validate_name, GroupKind};
+use crate::policy::journal::{parse_journal, replay, Journal, QUERY_JOURNAL};
+use crate::policy::learn::{header_for, Learner};
+use crate::policy::lint::{lint_policy, Severity};
+use crate::policy::pattern::PathPattern;
+use crate::policy::protocol::parse_queries;
+use crate::policy::responder::{parse_rule_file, Answer, Responder, RESPONDER_LOG};
+use crate::policy::simulate;
+use crate::policy::snapshot::{rollback_plan, SnapshotMeta, SnapshotStore};
+use crate::policy::stat::{parse_preamble, stat_acl};
+use crate::policy::suite::{parse_test_file, run_case};
+use crate::policy::{sorted_blocks, BlockLines};
+use crate::ui::output::{print_structured, OutputFormat};
+use crate::ui::query::{self as pquery, Violation};
+use crate::ui::theme::{epaint, paint, Style};
 use aclneko::acl::Acl;
-use aclneko::io::{self as policyio, read_policy_file, POLICY_FILE_PATH};
+use aclneko::syntax::{Matcher, Op};
 // use clap::{App, Arg, ArgMatches, Command};
+use clap::ValueEnum;
 use serde_json::json;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
 pub struct Subcommands<'a> {
     pub acl: &'a Acl,
+    pub backend: Box<dyn PolicyBackend>,
     pub is_verbose: bool,
     pub debug: bool,
+    pub snapshot: bool,
+    pub output: OutputFormat,
+    pub file: String,
 }
 
 pub struct SearchParam {
     pub header_only: bool,
     pub search_rule: bool,
     pub with_regex: bool,
+    /// alternative sets of attributes of a subject to find ACLs whose
+    /// conditions can be satisfied with any of them
+    pub subjects: Vec<BTreeMap<String, String>>,
+}
+
+/// MatchParam is a pattern or a string_group to be matched with a path.
+///
+pub enum MatchParam {
+    Pattern(String),
+    Group(String),
 }
 
 pub struct PatchParam {
@@ -25,19 +65,96 @@ pub struct PatchParam {
     pub atomic: bool,
     pub unmerge: bool,
     pub assume_yes: bool,
+    pub dry_run: bool,
+    pub confirm_within: Option<u64>,
+}
+
+/// TestFormat is the output format of `test` subcommand.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestFormat {
+    Text,
+    Tap,
+    Junit,
+}
+
+pub struct GroupParam {
+    pub kind: Option<GroupKind>,
+    pub undefined: bool,
+    pub unused: bool,
+}
+
+pub enum GroupEditParam {
+    Add {
+        name: String,
+        member: String,
+        kind: Option<GroupKind>,
+    },
+    Remove {
+        name: String,
+        member: String,
+    },
+}
+
+pub enum SnapshotParam {
+    Save {
+        name: Option<String>,
+        message: Option<String>,
+    },
+    List,
+    Show(String),
+    Remove(String),
+}
+
+pub struct LearnParam {
+    pub log: Option<String>,
+    pub count: Option<usize>,
+    pub out: Option<String>,
+    pub priority: u16,
+    pub min_siblings: usize,
+}
+
+/// AuditParam is the audit log with `field`, `regex` conditions to select
+/// records, and how to summarize them.
+///
+pub struct AuditParam {
+    pub source: String,
+    pub conds: Vec<(String, String)>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub group_by: Vec<String>,
+    pub top: Option<usize>,
+    pub csv: bool,
 }
 
 pub struct QueryParam {
     pub pattern: Option<String>,
     pub color: bool,
+    pub rules: Option<String>,
+    pub journal: Option<String>,
+    pub history: bool,
+    pub tui: bool,
+    pub record: Option<String>,
+    pub transcript: Option<String>,
+    pub timeout: Option<Duration>,
+    pub on_timeout: Answer,
+    pub on_exit: Answer,
 }
 
 impl<'a> Subcommands<'_> {
     pub fn list_cmd(self, for_patches: bool) -> Result<(), String> {
         if for_patches {
-            return policyio::list_registered_patches();
+            return self.backend.list_registered_patches();
         }
 
+        if self.output.is_structured() {
+            let m = Matcher::new();
+            let headers: Vec<HeaderEntry> = sorted_blocks(self.acl)
+                .iter()
+                .map(|b| header_entry(&m, b))
+                .collect();
+            return print_structured(self.output, &headers);
+        }
         let list = self.acl.parse_acl_headers();
         if self.is_verbose {
             for h in list {
@@ -52,7 +169,9 @@ impl<'a> Subcommands<'_> {
     }
 
     pub fn dump_cmd(self, with_json_format: bool) -> Result<(), String> {
-        if with_json_format {
+        if self.output.is_structured() {
+            print_structured(self.output, &self.acl.data)?;
+        } else if with_json_format {
             println!("{}", json!(&self.acl.data));
         } else {
             self.acl.dump_table();
@@ -60,10 +179,371 @@ impl<'a> Subcommands<'_> {
         Ok(())
     }
 
+    /// subcommand `diff`: compare two policies ACL by ACL and report added,
+    /// removed and changed ACL blocks and rules.
+    ///
+    pub fn diff_cmd(self, source: &str, target: &str) -> Result<(), String> {
+        let src = functions::read_policy_source(self.backend.as_ref(), source)?;
+        let dst = functions::read_policy_source(self.backend.as_ref(), target)?;
+        let diff = diff_acls(&src, &dst);
+        if self.output.is_structured() {
+            return print_structured(self.output, &diff);
+        }
+        if diff.is_empty() {
+            if self.is_verbose {
+                eprintln!("no differences");
+            }
+            return Ok(());
+        }
+        functions::print_diff(&diff, source, target);
+        if self.is_verbose {
+            eprintln!(
+                "{} added, {} removed, {} changed",
+                diff.added, diff.removed, diff.changed
+            );
+        }
+        Ok(())
+    }
+
+    /// subcommand `stat`: show statistics for ACL blocks and rules, with
+    /// counters and quotas in the policy preamble.
+    /// A query may be a priority or an operation to filter ACL blocks.
+    ///
+    pub fn stat_cmd(self, source: &str, query: Option<String>) -> Result<(), String> {
+        let (acl, preamble) = match &query {
+            Some(q) => (self.acl.parse_acl_by_header(q.trim())?, None),
+            None => (
+                self.acl.clone(),
+                Some(parse_preamble(&functions::read_policy_text(
+                    self.backend.as_ref(),
+                    source,
+                )?)),
+            ),
+        };
+        let stat = stat_acl(&acl);
+        if self.output.is_structured() {
+            return print_structured(
+                self.output,
+                &json!({ "query": query, "acl": stat, "stat": preamble }),
+            );
+        }
+        if let Some(q) = &query {
+            println!("query: {}", q);
+        }
+        let is_op_query = query.is_some_and(|q| Op::from(q.as_str()) != Op::Error);
+        functions::show_stat(&stat, preamble.as_ref(), is_op_query);
+        Ok(())
+    }
+
+    /// subcommand `groups`: list groups with their members and ACL blocks
+    /// referencing them, flagging undefined and unused groups.
+    ///
+    pub fn groups_cmd(self, param: GroupParam) -> Result<(), String> {
+        let groups: Vec<_> = analyze_groups(self.acl)
+            .into_iter()
+            .filter(|g| param.kind.is_none() || g.kind_or_referenced() == param.kind)
+            .filter(|g| !param.undefined || g.is_undefined())
+            .filter(|g| !param.unused || g.is_unused())
+            .collect();
+        if self.output.is_structured() {
+            return print_structured(self.output, &groups);
+        }
+
+        for g in groups {
+            functions::show_group(&g);
+        }
+        Ok(())
+    }
+
+    /// subcommand `group show`: show a group with its members and references.
+    ///
+    pub fn group_show_cmd(self, name: &str) -> Result<(), String> {
+        let g = analyze_groups(self.acl)
+            .into_iter()
+            .find(|g| g.name == name)
+            .ok_or(format!("no such group: {}", name))?;
+        match self.output.is_structured() {
+            true => print_structured(self.output, &g),
+            false => {
+                functions::show_group(&g);
+                Ok(())
+            }
+        }
+    }
+
+    /// subcommand `group add` and `group remove`: add a member to a group or
+    /// remove a member from a group in the live policy.
+    ///
+    /// The kind of an existing group is detected from the policy, and `kind`
+    /// is required to add a member to a new group.
+    ///
+    pub fn group_edit_cmd(
+        mut self,
+        param: GroupEditParam,
+        assume_yes: bool,
+        dry_run: bool,
+    ) -> Result<(), String> {
+        // groups are edited in the live policy even if a policy file is given
+        let live = self.backend.read_policy()?;
+        let (line, style) = match param {
+            GroupEditParam::Add { name, member, kind } => {
+                validate_name(&name)?;
+                let existing = GroupKind::list()
+                    .into_iter()
+                    .find(|k| k.groups(&live).contains_key(&name));
+                let kind = match (existing, kind) {
+                    (Some(e), Some(k)) if e != k => {
+                        return Err(format!("{} is defined as {}", name, e.as_str()))
+                    }
+                    (Some(e), _) => e,
+                    (None, Some(k)) => k,
+                    (None, None) => {
+                        return Err(format!(
+                            "no such group: {}\ngive --kind to define a new group",
+                            name
+                        ))
+                    }
+                };
+                validate_member(kind, &member)?;
+                if kind
+                    .groups(&live)
+                    .get(&name)
+                    .is_some_and(|m| m.contains(&member))
+                {
+                    return Err(format!("{} is already a member of {}", member, name));
+                }
+                (
+                    format!("{} {} {}", kind.as_str(), name, member),
+                    Style::Added,
+                )
+            }
+            GroupEditParam::Remove { name, member } => {
+                let kind = GroupKind::list()
+                    .into_iter()
+                    .find(|k| k.groups(&live).contains_key(&name))
+                    .ok_or(format!("no such group: {}", name))?;
+                if !kind.groups(&live)[&name].contains(&member) {
+                    return Err(format!("{} is not a member of {}", member, name));
+                }
+                (
+                    format!("delete {} {} {}", kind.as_str(), name, member),
+                    Style::Removed,
+                )
+            }
+        };
+
+        if dry_run {
+            println!("{}", paint(style, &line));
+            return Ok(());
+        }
+        let msg = format!("======== GROUP ========\n{}\n", epaint(style, &line))
+            + "=======================\n\n"
+            + "Are you sure to modify the group? [y/n]: ";
+        if assume_yes || functions::prompt(&msg) {
+            self.save_auto_snapshot("group")?;
+            self.backend.write_policy(&(line + "\n"))
+        } else {
+            eprintln!("canceled.");
+            Ok(())
+        }
+    }
+
+    /// subcommand `check`: evaluate a hypothetical request with the policy
+    /// and show the matched ACL blocks, the deciding rule and the verdict.
+    ///
+    pub fn check_cmd(self, operation: &str, attrs: &[String]) -> Result<(), String> {
+        let req = Request::parse(operation, attrs)?;
+        let res = check_request(self.acl, &req);
+        if self.output.is_structured() {
+            return print_structured(self.output, &res);
+        }
+
+        for t in &res.trace {
+            if !t.matched && !self.is_verbose {
+                continue;
+            }
+            match t.matched {
+                true => println!("{}", paint(Style::Bold, &t.header)),
+                false => println!(
+                    "{}",
+                    paint(Style::Dim, format!("{} (not matched)", t.header))
+                ),
+            }
+            if let Some(r) = &t.rule {
+                let style = match r.split_whitespace().nth(1) {
+                    Some("deny") => Style::Removed,
+                    _ => Style::Added,
+                };
+                println!("{}{}", functions::INDENT, paint(style, r));
+            }
+            if !t.missing.is_empty() {
+                println!(
+                    "{}{}",
+                    functions::INDENT,
+                    paint(
+                        Style::Warning,
+                        format!("missing attributes: {}", t.missing.join(" "))
+                    )
+                );
+            }
+        }
+        if res.trace.iter().all(|t| !t.matched) {
+            println!("no ACL matched the request");
+        }
+        let style = match res.verdict {
+            Verdict::Allow => Style::Added,
+            Verdict::Deny => Style::Removed,
+        };
+        print!("verdict: {}", paint(style, res.verdict.as_str()));
+        match (&res.acl, &res.rule) {
+            (Some(a), Some(r)) => print!(" by `{}` in `{}`", r, a),
+            _ => print!(" (no rule matched)"),
+        }
+        if let Some(t) = &res.transition {
+            print!(", transition to {}", t);
+        }
+        println!();
+        Ok(())
+    }
+
+    /// subcommand `match`: test whether a path matches a pattern, or any
+    /// member of a string_group, and explain which part of the path each
+    /// token of the pattern consumed. It fails if nothing matches.
+    ///
+    pub fn match_cmd(self, param: &MatchParam, path: &str) -> Result<(), String> {
+        let patterns = match param {
+            MatchParam::Pattern(p) => vec![p.clone()],
+            MatchParam::Group(g) => self
+                .acl
+                .data
+                .string_group
+                .get(g)
+                .cloned()
+                .ok_or(format!("string_group not found: {}", g))?,
+        };
+
+        let mut matched = false;
+        for p in &patterns {
+            let e = PathPattern::new(p)?.explain(path);
+            match e.matched {
+                true => println!("{} {} {}", p, paint(Style::Added, "matches"), path),
+                false => println!("{} {} {}", p, paint(Style::Removed, "does not match"), path),
+            }
+            if e.matched || self.is_verbose || patterns.len() == 1 {
+                functions::print_explanation(&e);
+            }
+            matched |= e.matched;
+        }
+        match matched {
+            true => Ok(()),
+            false => Err(format!("no pattern matched {}", path)),
+        }
+    }
+
+    /// subcommand `test`: evaluate test cases in TOML files with a policy and
+    /// report the results. It fails if any case fails.
+    ///
+    pub fn test_cmd(
+        self,
+        policy: &str,
+        tests: &[String],
+        format: TestFormat,
+    ) -> Result<(), String> {
+        let acl = functions::read_policy_source(self.backend.as_ref(), policy)?;
+        let mut results = vec![];
+        for t in tests {
+            let text = std::fs::read_to_string(t).map_err(|e| format!("{}: {}", t, e))?;
+            let file = parse_test_file(&text).map_err(|e| format!("{}: {}", t, e))?;
+            results.extend(file.test.iter().map(|c| run_case(&acl, t, c)));
+        }
+
+        let failures = results.iter().filter(|r| !r.is_ok()).count();
+        match format {
+            TestFormat::Tap => functions::print_tap(&results),
+            TestFormat::Junit => functions::print_junit(&results),
+            TestFormat::Text => {
+                for r in &results {
+                    match r.is_ok() {
+                        true => println!("{} {}: {}", paint(Style::Added, "PASS"), r.file, r.title),
+                        false => {
+                            println!("{} {}: {}", paint(Style::Removed, "FAIL"), r.file, r.title);
+                            for f in &r.failures {
+                                println!("{}{}", functions::INDENT, f);
+                            }
+                        }
+                    }
+                }
+                println!("{} passed, {} failed", results.len() - failures, failures);
+            }
+        }
+        match failures {
+            0 => Ok(()),
+            n => Err(format!("{} of {} tests failed", n, results.len())),
+        }
+    }
+
+    /// subcommand `lint`: report problems in a policy with line numbers and
+    /// severities. It fails if any warning or error is found.
+    ///
+    pub fn lint_cmd(self, source: &str) -> Result<(), String> {
+        let text = functions::read_policy_text(self.backend.as_ref(), source)?;
+        let findings = lint_policy(&text);
+        let errors = findings
+            .iter()
+            .filter(|f| f.severity == Severity::Error)
+            .count();
+        let warnings = findings
+            .iter()
+            .filter(|f| f.severity == Severity::Warning)
+            .count();
+
+        if self.output.is_structured() {
+            print_structured(
+                self.output,
+                &json!({
+                    "source": source,
+                    "errors": errors,
+                    "warnings": warnings,
+                    "findings": findings,
+                }),
+            )?;
+        } else {
+            for f in &findings {
+                let style = match f.severity {
+                    Severity::Error => Style::Error,
+                    Severity::Warning => Style::Warning,
+                    Severity::Info => Style::Info,
+                };
+                println!(
+                    "{}:{}: {}[{}]: {}",
+                    source,
+                    f.line,
+                    paint(style, f.severity.as_str()),
+                    f.code,
+                    f.message
+                );
+            }
+            if self.is_verbose || !findings.is_empty() {
+                eprintln!("{} errors, {} warnings", errors, warnings);
+            }
+        }
+        match errors + warnings {
+            0 => Ok(()),
+            _ => Err(format!(
+                "{} problems found in {}",
+                errors + warnings,
+                source
+            )),
+        }
+    }
+
     /// subcommand `search`:search rules which has corresponding a header or
     /// a rule which matches given query.
     ///
     pub fn search_cmd(self, query: Option<String>, param: SearchParam) -> Result<(), String> {
+        if !param.subjects.is_empty() {
+            return self.search_subject(query, &param);
+        }
         let mut q = String::new();
 
         match query {
@@ -85,8 +565,17 @@ impl<'a> Subcommands<'_> {
 
         if param.header_only {
             let list = self.acl.parse_acl_headers_by_pattern(q.as_str());
-            if list.len() == 0 {
+            if list.is_empty() {
                 return Err(String::from("no policy found"));
+            } else if self.output.is_structured() {
+                let headers: Vec<String> = list.iter().map(|h| h.to_string()).collect();
+                let m = Matcher::new();
+                let entries: Vec<HeaderEntry> = sorted_blocks(self.acl)
+                    .iter()
+                    .filter(|b| headers.contains(&b.header))
+                    .map(|b| header_entry(&m, b))
+                    .collect();
+                return print_structured(self.output, &entries);
             } else {
                 for h in list {
                     println!("{}", h);
@@ -95,32 +584,29 @@ impl<'a> Subcommands<'_> {
             }
         }
 
-        let set: Acl;
-        match param.search_rule {
-            true => {
-                set = match param.with_regex {
-                    true => self.acl.parse_acl_by_rule_with_regex(&q)?,
-                    false => self.acl.parse_acl_by_rule(&q.trim_end())?,
-                }
-            }
-            false => {
-                set = match param.with_regex {
-                    true => {
-                        if self.is_verbose {
-                            println!("matching mode: regex")
-                        }
-                        self.acl.parse_acl_by_header_with_regex(&q)?
+        let set: Acl = match param.search_rule {
+            true => match param.with_regex {
+                true => self.acl.parse_acl_by_rule_with_regex(&q)?,
+                false => self.acl.parse_acl_by_rule(q.trim_end())?,
+            },
+            false => match param.with_regex {
+                true => {
+                    if self.is_verbose {
+                        println!("matching mode: regex")
                     }
-                    false => {
-                        if self.is_verbose {
-                            println!("matching mode: query")
-                        }
-                        self.acl.parse_acl_by_header(&q.trim_end())?
+                    self.acl.parse_acl_by_header_with_regex(&q)?
+                }
+                false => {
+                    if self.is_verbose {
+                        println!("matching mode: query")
                     }
+                    self.acl.parse_acl_by_header(q.trim_end())?
                 }
-            }
-        }
-        if set.len() != 0 {
+            },
+        };
+        if set.len() != 0 && self.output.is_structured() {
+            self.print_blocks(&sorted_blocks(&set))
+        } else if set.len() != 0 {
             println!("{}", set);
             Ok(())
         } else {
@@ -128,7 +614,70 @@ impl<'a> Subcommands<'_> {
         }
     }
 
-    pub fn apply_cmd(self, source: Option<&'a String>, param: PatchParam) -> Result<(), String> {
+    /// search_subject prints ACL blocks and rules whose conditions can be
+    /// satisfied by the subject. A query narrows down blocks by the header.
+    ///
+    fn search_subject(self, query: Option<String>, param: &SearchParam) -> Result<(), String> {
+        let headers: Option<Vec<String>> = match &query {
+            Some(q) if param.with_regex => Some(
+                self.acl
+                    .parse_acl_by_header_with_regex(q)?
+                    .parse_acl_headers()
+                    .iter()
+                    .map(|h| h.to_string())
+                    .collect(),
+            ),
+            Some(q) => Some(
+                self.acl
+                    .parse_acl_headers_by_pattern(q.trim_end())
+                    .iter()
+                    .map(|h| h.to_string())
+                    .collect(),
+            ),
+            None => None,
+        };
+        let blocks: Vec<BlockLines> = filter_blocks_any(self.acl, &param.subjects)
+            .into_iter()
+            .filter(|b| headers.as_ref().is_none_or(|h| h.contains(&b.header)))
+            .collect();
+        if blocks.is_empty() {
+            return Err("no ACLs found".to_string());
+        }
+        if self.output.is_structured() {
+            return match param.header_only {
+                true => {
+                    let m = Matcher::new();
+                    let headers: Vec<HeaderEntry> =
+                        blocks.iter().map(|b| header_entry(&m, b)).collect();
+                    print_structured(self.output, &headers)
+                }
+                false => self.print_blocks(&blocks),
+            };
+        }
+        for b in blocks {
+            println!("{}", b.header);
+            if !param.header_only {
+                for r in b.rules {
+                    println!("{}{}", functions::INDENT, r);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// print_blocks prints ACL blocks in the structured output format, with
+    /// line numbers in the target policy file if it is readable.
+    ///
+    fn print_blocks(&self, blocks: &[BlockLines]) -> Result<(), String> {
+        let source = fs::read_to_string(&self.file).ok();
+        print_structured(self.output, &block_entries(blocks, source.as_deref()))
+    }
+
+    pub fn apply_cmd(
+        mut self,
+        source: Option<&'a String>,
+        param: PatchParam,
+    ) -> Result<(), String> {
         let mut read_from_stdin = false;
         match source {
             None => read_from_stdin = true,
@@ -136,6 +685,10 @@ impl<'a> Subcommands<'_> {
             _ => {}
         }
 
+        if param.confirm_within.is_some() && !param.dry_run {
+            self.ensure_confirmable()?;
+        }
+
         if read_from_stdin {
             if self.is_verbose || self.debug {
                 eprintln!("reading policy from stdin...");
@@ -143,26 +696,41 @@ impl<'a> Subcommands<'_> {
             if param.operation.is_some() {
                 eprintln!("operation intrusion is not supported for stdin");
             }
-            return policyio::apply_acl_stdin();
+            let patch = functions::read_policy_source(self.backend.as_ref(), "-")?;
+            if param.dry_run {
+                return self.show_simulation(simulate::apply_acl(&patch, self.acl));
+            }
+            let applied = self.applied_patch(&patch, param.confirm_within)?;
+            let snapshot = self.save_auto_snapshot("apply")?;
+            self.backend.apply_acl(&patch)?;
+            return self.start_confirm_timer(snapshot, applied, "-", param.confirm_within);
         }
         // If apply subcommand is invoked with an argument with exist path,
         // it assumes the file as a policy patch and try to apply it.
-        let mut patch = policyio::read_policy_file(source.unwrap())?;
+        let mut patch = functions::read_policy_source(self.backend.as_ref(), source.unwrap())?;
 
         if let Some(op) = param.operation {
             patch.set_op(&op)?;
         }
 
-        let msg = format!("======== PATCH ========\n\x1B[32m{}\x1B[0m", patch)
+        if param.dry_run {
+            return self.show_simulation(match param.atomic {
+                true => simulate::apply_acl_atomic(&patch, self.acl)?,
+                false => simulate::apply_acl(&patch, self.acl),
+            });
+        }
+
+        let msg = format!("======== PATCH ========\n{}", epaint(Style::Added, &patch))
             + "=======================\n\n"
             + "Are you sure to apply this policy patch? [y/n]: ";
         if param.assume_yes || functions::prompt(&msg) {
-            let res: Result<(), String>;
+            let applied = self.applied_patch(&patch, param.confirm_within)?;
+            let snapshot = self.save_auto_snapshot("apply")?;
             match param.atomic {
-                true => res = policyio::apply_acl_atomic(patch.clone()),
-                false => res = policyio::apply_acl(patch.clone()),
+                true => self.backend.apply_acl_atomic(&patch)?,
+                false => self.backend.apply_acl(&patch)?,
             }
-            res
+            self.start_confirm_timer(snapshot, applied, source.unwrap(), param.confirm_within)
         } else {
             eprintln!("canceled.");
             Ok(())
@@ -171,7 +739,11 @@ impl<'a> Subcommands<'_> {
 
     /// subcommand `remove`: removes a policy patch from applied policy
     ///
-    pub fn remove_cmd(self, source: Option<&'a String>, param: PatchParam) -> Result<(), String> {
+    pub fn remove_cmd(
+        mut self,
+        source: Option<&'a String>,
+        param: PatchParam,
+    ) -> Result<(), String> {
         let mut read_from_stdin = false;
         match source {
             None => read_from_stdin = true,
@@ -183,32 +755,48 @@ impl<'a> Subcommands<'_> {
             if self.is_verbose {
                 eprintln!("reading policy header from stdin...");
             }
+            let patch = functions::read_policy_source(self.backend.as_ref(), "-")?;
+            if param.dry_run {
+                return self.show_simulation(match param.unmerge {
+                    true => simulate::clear_rule(&patch, self.acl),
+                    false => simulate::clear_acl(&patch, self.acl),
+                });
+            }
+            self.save_auto_snapshot("remove")?;
             match param.unmerge {
-                true => return policyio::unmerge_acl_from_stdin(&self.acl),
-                false => return policyio::clear_acl_from_stdin(),
+                true => return self.backend.clear_rule(&patch, self.acl),
+                false => return self.backend.clear_acl(&patch),
             }
         }
-        let mut patch = policyio::read_policy_file(&source.unwrap()).map_err(|e| e.to_string())?;
-        match param.operation {
-            Some(op) => {
-                patch.set_op(&op)?;
-            }
-            None => {}
+        let mut patch = functions::read_policy_source(self.backend.as_ref(), source.unwrap())?;
+        if let Some(op) = param.operation {
+            patch.set_op(&op)?;
         }
-        let msg = format!("======== PATCH ========\n\x1B[31m{}\x1B[0m", patch)
-            + "=======================\n\n"
+        if param.dry_run {
+            return self.show_simulation(match param.atomic {
+                true => simulate::remove_acl_atomic(&patch, self.acl)?,
+                false => match param.unmerge {
+                    true => simulate::unmerge_acl(&patch, self.acl)?,
+                    false => simulate::remove_acl(&patch, self.acl)?,
+                },
+            });
+        }
+        let msg = format!(
+            "======== PATCH ========\n{}",
+            epaint(Style::Removed, &patch)
+        ) + "=======================\n\n"
             + "Are you sure to remove this header? [y/n]: ";
         if param.assume_yes || functions::prompt(&msg) {
-            let res: Result<(), String>;
-            match param.atomic {
-                true => res = policyio::remove_acl_atomic(&patch, &self.acl),
+            self.save_auto_snapshot("remove")?;
+            let res = match param.atomic {
+                true => self.backend.remove_acl_atomic(&patch, self.acl),
                 false => match param.unmerge {
-                    true => res = policyio::unmerge_acl(&patch, &self.acl),
-                    false => res = policyio::remove_acl(&patch, &self.acl),
+                    true => self.backend.unmerge_acl(&patch, self.acl),
+                    false => self.backend.remove_acl(&patch, self.acl),
                 },
-            }
+            };
             if self.is_verbose {
-                println!("\x1B[7mremoved\x1B[0m");
+                println!("{}", paint(Style::Notice, "removed"));
             }
             res
         } else {
@@ -217,50 +805,775 @@ impl<'a> Subcommands<'_> {
         }
     }
 
+    /// show_simulation prints the difference between the current policy and
+    /// the policy computed by a dry-run, without touching the policy interface.
+    ///
+    fn show_simulation(&self, result: Acl) -> Result<(), String> {
+        let diff = diff_acls(self.acl, &result);
+        if diff.is_empty() {
+            eprintln!("dry-run: no changes for the policy");
+        } else {
+            functions::print_diff(&diff, "current", "dry-run");
+        }
+        Ok(())
+    }
+
+    /// save_auto_snapshot saves the live policy as an automatic snapshot
+    /// before a policy modification, unless snapshots are disabled.
+    ///
+    fn save_auto_snapshot(&self, reason: &str) -> Result<Option<SnapshotMeta>, String> {
+        if !self.snapshot {
+            return Ok(None);
+        }
+        let text = functions::read_policy_text(self.backend.as_ref(), "live")?;
+        let store = SnapshotStore::new(&self.backend.state_dir());
+        let meta = store.save_auto(&text, "live", reason).map_err(|e| {
+            format!(
+                "{}\nuse --no-snapshot to modify the policy without a snapshot",
+                e
+            )
+        })?;
+        if self.is_verbose {
+            eprintln!("snapshot saved: {}", meta.name);
+        }
+        Ok(Some(meta))
+    }
+
+    /// ensure_confirmable checks that a change can be applied with
+    /// `--confirm-within`, which falls back to an automatic snapshot when the
+    /// change cannot be reverted by removing the patch.
+    ///
+    fn ensure_confirmable(&self) -> Result<(), String> {
+        if !self.snapshot {
+            return Err(String::from(
+                "--confirm-within cannot be used with --no-snapshot",
+            ));
+        }
+        if PendingStore::new(&self.backend.state_dir())
+            .read()?
+            .is_some()
+        {
+            return Err(String::from(
+                "another change is pending confirmation; run `acquery confirm` first",
+            ));
+        }
+        Ok(())
+    }
+
+    /// applied_patch returns the part of the patch which applying it adds to
+    /// the live policy, when the change is to be confirmed.
+    ///
+    fn applied_patch(
+        &self,
+        patch: &Acl,
+        within: Option<u64>,
+    ) -> Result<Option<AppliedPatch>, String> {
+        if within.is_none() {
+            return Ok(None);
+        }
+        let (acls, rules) = simulate::applied_part(patch, &self.backend.read_policy()?);
+        Ok(Some(AppliedPatch {
+            acls: acls.to_string(),
+            rules: rules.to_string(),
+        }))
+    }
+
+    /// start_confirm_timer saves the applied change as pending and starts a
+    /// detached `confirm --watch` process, which reverts the change unless it
+    /// is confirmed within the seconds.
+    ///
+    fn start_confirm_timer(
+        &self,
+        snapshot: Option<SnapshotMeta>,
+        applied: Option<AppliedPatch>,
+        source: &str,
+        within: Option<u64>,
+    ) -> Result<(), String> {
+        let (snapshot, applied, within) = match (snapshot, applied, within) {
+            (Some(s), Some(a), Some(w)) => (s, a, w),
+            _ => return Ok(()),
+        };
+        let state_dir = self.backend.state_dir();
+        let change = PendingChange::new(&snapshot.name, source, within, applied);
+        PendingStore::new(&state_dir).save(&change)?;
+
+        let hint = "run `acquery confirm --revert` to revert the change";
+        let exe = std::env::current_exe().map_err(|e| format!("{}\n{}", e, hint))?;
+        let root = fs::canonicalize(self.backend.root())
+            .map_err(|e| format!("{}: {}\n{}", self.backend.root().display(), e, hint))?;
+        let log_path = state_dir.join("confirm.log");
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .map_err(|e| format!("{}: {}\n{}", log_path.display(), e, hint))?;
+        Command::new(exe)
+            .arg("--root")
+            .arg(root)
+            .arg("--backend")
+            .arg(self.backend.kind().to_possible_value().unwrap().get_name())
+            .args(["confirm", "--watch"])
+            .current_dir("/")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(log)
+            .spawn()
+            .map_err(|e| format!("failed to start the confirmation timer: {}\n{}", e, hint))?;
+
+        eprintln!(
+            "the change will be reverted at {} unless `acquery confirm` is run",
+            format_timestamp(change.deadline)
+        );
+        Ok(())
+    }
+
+    /// revert_pending reverts the pending change, unless it has been
+    /// confirmed or reverted by another invocation.
+    ///
+    /// The applied part of the patch is removed from the live policy, so that
+    /// other changes made meanwhile are kept. The policy is rolled back to the
+    /// snapshot only if the patch cannot be removed.
+    ///
+    fn revert_pending(&mut self, store: &PendingStore) -> Result<Option<PendingChange>, String> {
+        let change = match store.claim()? {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let removed = match &change.applied {
+            Some(a) => self.remove_applied(a),
+            None => Err(String::from("the applied patch is not recorded")),
+        };
+        match removed {
+            Ok(_) => eprintln!(
+                "{}: reverted the change from {}",
+                format_timestamp(now()),
+                change.source
+            ),
+            Err(e) => {
+                eprintln!("cannot remove the patch: {}", e);
+                let (_, text) =
+                    SnapshotStore::new(&self.backend.state_dir()).read(&change.snapshot)?;
+                let plan = rollback_plan(&self.backend.read_policy()?, &Acl::from_str(&text)?);
+                if !plan.is_empty() {
+                    self.backend.write_policy(&(plan.join("\n") + "\n"))?;
+                }
+                eprintln!(
+                    "{}: reverted the change from {} to {}",
+                    format_timestamp(now()),
+                    change.source,
+                    change.snapshot
+                );
+            }
+        }
+        Ok(Some(change))
+    }
+
+    //remove_applied removes ACL blocks added by a patch and unmerges rules
+    //added to existing blocks, checking both against the live policy first.
+    fn remove_applied(&mut self, applied: &AppliedPatch) -> Result<(), String> {
+        let live = self.backend.read_policy()?;
+        let (acls, rules) = (
+            Acl::from_str(&applied.acls)?,
+            Acl::from_str(&applied.rules)?,
+        );
+        simulate::remove_acl(&acls, &live)?;
+        simulate::unmerge_acl(&rules, &live)?;
+        if acls.len() > 0 {
+            self.backend.remove_acl(&acls, &live)?;
+        }
+        if rules.len() > 0 {
+            self.backend.unmerge_acl(&rules, &live)?;
+        }
+        Ok(())
+    }
+
+    /// subcommand `confirm`: confirm the change applied with
+    /// `--confirm-within`, or revert it.
+    ///
+    /// With `--watch`, it waits for the deadline in a new session and reverts
+    /// the change unless confirmed meanwhile.
+    ///
+    pub fn confirm_cmd(mut self, revert: bool, watch: bool) -> Result<(), String> {
+        let store = PendingStore::new(&self.backend.state_dir());
+        if watch {
+            // detach from the terminal so that a lost session does not kill the timer
+            let _ = nix::unistd::setsid();
+            loop {
+                match store.read()? {
+                    None => return Ok(()),
+                    Some(c) if c.is_expired() => break,
+                    Some(_) => thread::sleep(Duration::from_secs(1)),
+                }
+            }
+            return self.revert_pending(&store).map(|_| ());
+        }
+
+        let reverted = |store: &PendingStore| match store.read_reverted() {
+            Ok(Some(c)) => format!("the change from {} has been reverted", c.source),
+            _ => String::from("no change is pending confirmation"),
+        };
+        match store.read()? {
+            None => Err(reverted(&store)),
+            Some(c) if revert || c.is_expired() => match self.revert_pending(&store)? {
+                Some(_) if revert => Ok(()),
+                Some(_) => Err(String::from(
+                    "the deadline has passed and the change has been reverted",
+                )),
+                None => Err(reverted(&store)),
+            },
+            Some(_) => match store.confirm()? {
+                Some(c) => {
+                    eprintln!("confirmed the change from {}", c.source);
+                    Ok(())
+                }
+                None => Err(reverted(&store)),
+            },
+        }
+    }
+
+    /// subcommand `snapshot`: save, list, show or remove snapshots of the
+    /// live policy.
+    ///
+    pub fn snapshot_cmd(self, param: SnapshotParam) -> Result<(), String> {
+        let store = SnapshotStore::new(&self.backend.state_dir());
+        match param {
+            SnapshotParam::Save { name, message } => {
+                let text = functions::read_policy_text(self.backend.as_ref(), "live")?;
+                let meta = store.save(name.as_deref(), &text, "live", message)?;
+                println!("{}", meta.name);
+            }
+            SnapshotParam::List => {
+                for m in store.list()? {
+                    println!(
+                        "{}\t{}\t{} ACLs\t{} rules\t{}",
+                        m.name,
+                        format_timestamp(m.created),
+                        m.acls,
+                        m.rules,
+                        m.message.unwrap_or_default()
+                    );
+                }
+            }
+            SnapshotParam::Show(name) => {
+                let (meta, text) = store.read(&name)?;
+                if self.is_verbose {
+                    eprintln!("{}", json!(meta));
+                }
+                print!("{}", text);
+            }
+            SnapshotParam::Remove(name) => store.remove(&name)?,
+        }
+        Ok(())
+    }
+
+    /// subcommand `rollback`: return the policy to a snapshot with the minimal
+    /// sequence of deletions and additions.
+    ///
+    pub fn rollback_cmd(
+        mut self,
+        name: &str,
+        assume_yes: bool,
+        dry_run: bool,
+    ) -> Result<(), String> {
+        let store = SnapshotStore::new(&self.backend.state_dir());
+        let (meta, text) = store.read(name)?;
+        let plan = rollback_plan(self.acl, &Acl::from_str(&text)?);
+        if plan.is_empty() {
+            eprintln!("the policy is identical to the snapshot {}", meta.name);
+            return Ok(());
+        }
+
+        let style = |l: &str| match l.starts_with("delete ") {
+            true => Style::Removed,
+            false => Style::Added,
+        };
+        if dry_run {
+            for l in &plan {
+                println!("{}", paint(style(l), l));
+            }
+            return Ok(());
+        }
+        let mut styled = String::new();
+        for l in &plan {
+            styled += &format!("{}\n", epaint(style(l), l));
+        }
+        let msg = format!("======= ROLLBACK =======\n{}", styled)
+            + "========================\n\n"
+            + &format!(
+                "Are you sure to roll back the policy to {} ({})? [y/n]: ",
+                meta.name,
+                format_timestamp(meta.created)
+            );
+        if assume_yes || functions::prompt(&msg) {
+            self.save_auto_snapshot("rollback")?;
+            self.backend.write_policy(&(plan.join("\n") + "\n"))
+        } else {
+            eprintln!("canceled.");
+            Ok(())
+        }
+    }
+
     /// subcommand `query`: query policy violation
     ///
     pub fn query_cmd(self, param: QueryParam) -> Result<(), String> {
         if self.is_verbose {
             eprintln!("target pattern: {:?}", param.pattern.as_ref());
         }
-        if let Some(pattern) = param.pattern {
-            let mut query_listener = pquery::Query::new(&pattern).map_err(|e| e.to_string())?;
-            query_listener.listen_policy_violation()
-        } else {
-            let mut query_listener = pquery::Query::new(".").map_err(|e| e.to_string())?;
-            query_listener.listen_policy_violation()
+        if let Some(t) = &param.transcript {
+            let text = functions::read_policy_text(self.backend.as_ref(), t)?;
+            let batch = parse_queries(&text);
+            if self.output.is_structured() {
+                return print_structured(self.output, &batch);
+            }
+            for n in &batch.notices {
+                println!("{}", paint(Style::Dim, n));
+            }
+            for q in &batch.queries {
+                println!(
+                    "{} {}",
+                    paint(Style::Bold, format!("Q{}-{}", q.id, q.retry)),
+                    q.message
+                );
+            }
+            return Ok(());
+        }
+        let journal_path = match &param.journal {
+            Some(j) => PathBuf::from(j),
+            None => self.backend.state_dir().join(QUERY_JOURNAL),
+        };
+        if param.history {
+            let text = fs::read_to_string(&journal_path)
+                .map_err(|e| format!("{}: {}", journal_path.display(), e))?;
+            let entries =
+                parse_journal(&text).map_err(|e| format!("{}: {}", journal_path.display(), e))?;
+            if self.output.is_structured() {
+                return print_structured(self.output, &entries);
+            }
+            for e in &entries {
+                functions::print_journal_entry(e, self.is_verbose);
+            }
+            return Ok(());
+        }
+        let pattern = param.pattern.unwrap_or(String::from("."));
+        let responder = match &param.rules {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+                let rules = parse_rule_file(&text).map_err(|e| format!("{}: {}", path, e))?;
+                Some(Responder::new(rules).map_err(|e| format!("{}: {}", path, e))?)
+            }
+            None => None,
+        };
+        let decision_log = responder.as_ref().map(|r| match &r.log {
+            Some(l) => PathBuf::from(l),
+            None => self.backend.state_dir().join(RESPONDER_LOG),
+        });
+        for dir in [decision_log.as_deref(), Some(journal_path.as_path())]
+            .into_iter()
+            .flatten()
+            .filter_map(|l| l.parent())
+        {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        if self.is_verbose {
+            if let Some(l) = &decision_log {
+                eprintln!("decision log: {}", l.display());
+            }
+            eprintln!("journal: {}", journal_path.display());
+        }
+        let mut query_listener = pquery::Query::new(self.backend, &pattern)?;
+        query_listener.responder = responder;
+        query_listener.decision_log = decision_log;
+        query_listener.journal = Some(Journal::new(journal_path));
+        query_listener.transcript = param.record.map(PathBuf::from);
+        query_listener.answer_timeout = param.timeout;
+        query_listener.on_timeout = param.on_timeout;
+        query_listener.on_exit = param.on_exit;
+        query_listener.styled = param.color && !self.output.is_structured();
+        query_listener.output = self.output;
+        match param.tui {
+            true if self.output.is_structured() => {
+                Err(String::from("--tui cannot be used with --output"))
+            }
+            true => query_listener.run_tui(),
+            false => query_listener.listen_policy_violation(),
+        }
+    }
+
+    /// subcommand `replay`: derive a patch from the rules added interactively
+    /// in a query journal.
+    ///
+    pub fn replay_cmd(self, journal: &str, out: Option<String>) -> Result<(), String> {
+        let text = functions::read_policy_text(self.backend.as_ref(), journal)?;
+        let entries = parse_journal(&text).map_err(|e| format!("{}: {}", journal, e))?;
+        let patch = replay(&entries);
+        if self.is_verbose {
+            eprintln!(
+                "{} rules added in {} entries",
+                patch.lines().filter(|l| l.starts_with(' ')).count(),
+                entries.len()
+            );
+        }
+        match out {
+            Some(path) => fs::write(&path, patch).map_err(|e| format!("{}: {}", path, e)),
+            None => {
+                print!("{}", patch);
+                Ok(())
+            }
+        }
+    }
+
+    /// subcommand `learn`: permit policy violations from the query interface,
+    /// or read them from a saved audit log, and propose a patch allowing
+    /// them. With `out`, the patch is rewritten for every violation, so that
+    /// it is kept when the command is interrupted.
+    ///
+    pub fn learn_cmd(mut self, param: LearnParam) -> Result<(), String> {
+        let mut learner = Learner::new(param.priority, param.min_siblings);
+        let warn = |learner: &Learner| {
+            for (header, deny) in learner.preceding_denies(self.acl) {
+                eprintln!(
+                    "{}: `{}` in `{}` is evaluated before the proposed rules; no priority precedes it",
+                    epaint(Style::Warning, "warning"),
+                    deny,
+                    header
+                );
+            }
+        };
+        let write_patch = |learner: &Learner| -> Result<(), String> {
+            let patch = learner.propose(self.acl);
+            match &param.out {
+                Some(path) => fs::write(path, patch).map_err(|e| format!("{}: {}", path, e)),
+                None => {
+                    print!("{}", patch);
+                    Ok(())
+                }
+            }
+        };
+
+        if let Some(log) = &param.log {
+            let text = functions::read_policy_text(self.backend.as_ref(), log)?;
+            for l in text.lines().filter(|l| l.contains(" / ")) {
+                let v = Violation::parse("", l);
+                if v.attrs.get("result").is_some_and(|r| r == "allowed") {
+                    continue;
+                }
+                if let Some(op) = &v.operation {
+                    learner.observe(&header_for(&[self.acl], op, &v.attrs), &v.attrs);
+                }
+            }
+            eprintln!("{} violations learned", learner.count());
+            warn(&learner);
+            return write_patch(&learner);
+        }
+
+        if param.out.is_none() && param.count.is_none() {
+            return Err("--out or --count is required to learn from the query interface".into());
+        }
+        eprintln!("learning policy violations...");
+        while param.count.is_none_or(|c| learner.count() < c) {
+            let text = self.backend.wait_query()?;
+            for q in parse_queries(&text).queries {
+                let id = q.id.clone();
+                let v = Violation::from_query(&q);
+                let op = match &v.operation {
+                    Some(op) => op.clone(),
+                    None => {
+                        // the violation cannot be learned, but it must not be left pending
+                        self.backend.answer_query(&id, Answer::Permit.code())?;
+                        eprintln!(
+                            "{} {} (not learned)",
+                            epaint(Style::Permitted, "permitted"),
+                            v.message
+                        );
+                        continue;
+                    }
+                };
+                let selected = self
+                    .backend
+                    .select_query(&id)
+                    .ok()
+                    .and_then(|t| Acl::from_str(&t).ok())
+                    .unwrap_or(Acl::new());
+                let header = header_for(&[&selected, self.acl], &op, &v.attrs);
+                self.backend.answer_query(&id, Answer::Permit.code())?;
+                learner.observe(&header, &v.attrs);
+                eprintln!("{} {}", epaint(Style::Permitted, "permitted"), v.message);
+                if param.out.is_some() {
+                    write_patch(&learner)?;
+                }
+            }
+        }
+        warn(&learner);
+        match param.out {
+            Some(_) => Ok(()),
+            None => write_patch(&learner),
         }
     }
 
+    /// subcommand `audit`: select records of an audit log and print them,
+    /// or count them grouped by fields with `group_by`.
+    ///
+    pub fn audit_cmd(self, param: AuditParam) -> Result<(), String> {
+        if param.csv && self.output.is_structured() {
+            return Err("--csv cannot be used with --output".into());
+        }
+        let today = format_timestamp(now()).chars().take(10).collect::<String>();
+        let bound = |t: Option<String>| -> Result<Option<String>, String> {
+            match t.as_deref() {
+                Some("today") => Ok(Some(today.clone())),
+                Some(t) => validate_time(t).map(|_| Some(t.to_string())),
+                None => Ok(None),
+            }
+        };
+        let mut filter = EventFilter {
+            since: bound(param.since)?,
+            until: bound(param.until)?,
+            ..Default::default()
+        };
+        for (field, pattern) in &param.conds {
+            filter.add(field, pattern)?;
+        }
+
+        let text = functions::read_policy_text(self.backend.as_ref(), &param.source)?;
+        let events: Vec<AuditEvent> = parse_events(&text)
+            .into_iter()
+            .filter(|e| filter.is_match(e))
+            .collect();
+        if self.is_verbose {
+            eprintln!("{} records selected", events.len());
+        }
+
+        if !param.group_by.is_empty() {
+            let counts = count_by(&events, &param.group_by, param.top);
+            if self.output.is_structured() {
+                return print_structured(self.output, &counts);
+            }
+            match param.csv {
+                true => functions::print_csv(
+                    &[param.group_by.clone(), vec!["count".to_string()]].concat(),
+                    counts
+                        .iter()
+                        .map(|c| [c.values.clone(), vec![c.count.to_string()]].concat()),
+                ),
+                false => functions::print_counts(&param.group_by, &counts),
+            }
+            return Ok(());
+        }
+
+        if self.output.is_structured() {
+            return print_structured(self.output, &events);
+        }
+        match param.csv {
+            true => {
+                let mut attrs: Vec<&String> = events.iter().flat_map(|e| e.attrs.keys()).collect();
+                attrs.sort();
+                attrs.dedup();
+                let mut header: Vec<String> = ["line", "time", "result", "priority", "operation"]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect();
+                header.extend(attrs.iter().map(|a| a.to_string()));
+                functions::print_csv(
+                    &header,
+                    events.iter().map(|e| {
+                        let mut row = vec![e.line.to_string()];
+                        row.extend(header[1..].iter().map(|f| e.field(f).unwrap_or_default()));
+                        row
+                    }),
+                );
+            }
+            false => events.iter().for_each(functions::print_event),
+        }
+        Ok(())
+    }
+
     /// subcommand: `clear`: clear the system policy (dangerous)
     ///
-    pub fn clear_cmd(self) -> Result<(), String> {
+    pub fn clear_cmd(mut self) -> Result<(), String> {
         if self.is_verbose {
             println!("targets:");
             self.acl.list_acl_headers();
         }
-        policyio::clear_acl(&read_policy_file(POLICY_FILE_PATH)?)
+        let system_policy = self.backend.read_policy_file()?;
+        self.save_auto_snapshot("clear")?;
+        self.backend.clear_acl(&system_policy)
     }
 
     /// subcommand: `reload`: discard any paches and reload the default system policy
     ///
-    pub fn reload_cmd(self) -> Result<(), String> {
-        let mut reader =
-            BufReader::new(File::open(policyio::POLICY_FILE_PATH).map_err(|e| e.to_string())?);
-        let mut sbuf = vec![];
-        reader.read_until(0, &mut sbuf).map_err(|e| e.to_string())?;
-        let new_acl = Acl::from_str(std::str::from_utf8(&sbuf).unwrap())?;
+    pub fn reload_cmd(mut self) -> Result<(), String> {
+        let new_acl = self.backend.read_policy_file()?;
         if self.is_verbose {
-            println!("clear targets:\x1B[31m");
-            self.acl.list_acl_headers();
-            println!("\x1B[0m");
+            println!("clear targets:");
+            for h in self.acl.parse_acl_headers() {
+                println!("{}", paint(Style::Removed, h));
+            }
+            println!();
         }
-        policyio::clear_acl(&read_policy_file(POLICY_FILE_PATH)?)?;
+        self.save_auto_snapshot("reload")?;
+        self.backend.clear_acl(&new_acl)?;
         if self.is_verbose {
-            println!("reload targets:\x1B[32m");
-            new_acl.list_acl_headers();
-            print!("\x1B[0m");
+            println!("reload targets:");
+            for h in new_acl.parse_acl_headers() {
+                println!("{}", paint(Style::Added, h));
+            }
+        }
+        self.backend.apply_acl(&new_acl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::backend::{sample_emulator, Emulator};
+    use crate::policy::format_blocks;
+
+    const INET: &str = include_str!("../../fixtures/sample-patch-inet.acl");
+    const READ: &str = include_str!("../../fixtures/sample-patch-read.acl");
+
+    fn subcommands<'a>(acl: &'a Acl, name: &str) -> Subcommands<'a> {
+        Subcommands {
+            acl,
+            backend: Box::new(sample_emulator(name)),
+            is_verbose: false,
+            debug: false,
+            snapshot: true,
+            output: OutputFormat::Text,
+            file: String::new(),
         }
-        policyio::apply_acl(new_acl)
+    }
+
+    //apply_pending applies the patch as `--confirm-within` does, without
+    //starting the timer, and returns the policy before the change.
+    fn apply_pending(s: &mut Subcommands, patch: &str) -> Acl {
+        let before = s.backend.read_policy().unwrap();
+        let patch = Acl::from_str(patch).unwrap();
+        let text = functions::read_policy_text(s.backend.as_ref(), "live").unwrap();
+        let snapshot = SnapshotStore::new(&s.backend.state_dir())
+            .save_auto(&text, "live", "apply")
+            .unwrap();
+        let applied = s.applied_patch(&patch, Some(60)).unwrap().unwrap();
+        s.backend.apply_acl(&patch).unwrap();
+        let change = PendingChange::new(&snapshot.name, "test", 60, applied);
+        PendingStore::new(&s.backend.state_dir())
+            .save(&change)
+            .unwrap();
+        before
+    }
+
+    #[test]
+    fn revert_removes_applied_patch() {
+        let acl = Acl::new();
+        let mut s = subcommands(&acl, "revert-applied");
+        let before = apply_pending(&mut s, INET);
+        // a change made meanwhile is kept
+        s.backend.apply_acl(&Acl::from_str(READ).unwrap()).unwrap();
+
+        let store = PendingStore::new(&s.backend.state_dir());
+        assert!(s.revert_pending(&store).unwrap().is_some());
+        let expected = simulate::apply_acl(&Acl::from_str(READ).unwrap(), &before);
+        assert_eq!(
+            format_blocks(&s.backend.read_policy().unwrap()),
+            format_blocks(&expected)
+        );
+        assert!(store.read().unwrap().is_none());
+        assert!(store.read_reverted().unwrap().is_some());
+    }
+
+    #[test]
+    fn revert_unmerges_rules_added_to_blocks() {
+        let acl = Acl::new();
+        let mut s = subcommands(&acl, "revert-rules");
+        s.backend
+            .apply_acl(
+                &Acl::from_str(
+                    "10 acl inet_stream_connect task.exe=\"/usr/bin/curl\"\n    0 deny\n",
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let before = s.backend.read_policy().unwrap();
+        apply_pending(&mut s, INET);
+
+        let store = PendingStore::new(&s.backend.state_dir());
+        assert!(s.revert_pending(&store).unwrap().is_some());
+        assert_eq!(
+            format_blocks(&s.backend.read_policy().unwrap()),
+            format_blocks(&before)
+        );
+    }
+
+    #[test]
+    fn revert_falls_back_to_snapshot() {
+        let acl = Acl::new();
+        let mut s = subcommands(&acl, "revert-fallback");
+        let before = apply_pending(&mut s, INET);
+        // the applied block is gone, so the patch cannot be removed
+        s.backend.clear_acl(&Acl::from_str(INET).unwrap()).unwrap();
+        s.backend.apply_acl(&Acl::from_str(READ).unwrap()).unwrap();
+
+        let store = PendingStore::new(&s.backend.state_dir());
+        assert!(s.revert_pending(&store).unwrap().is_some());
+        assert_eq!(
+            format_blocks(&s.backend.read_policy().unwrap()),
+            format_blocks(&before)
+        );
+    }
+
+    #[test]
+    fn confirm_and_revert_race() {
+        let acl = Acl::new();
+        let mut s = subcommands(&acl, "revert-race");
+        apply_pending(&mut s, INET);
+        let applied = s.backend.read_policy().unwrap();
+
+        // a confirmation arriving after the revert has claimed the change
+        let store = PendingStore::new(&s.backend.state_dir());
+        assert!(store.claim().unwrap().is_some());
+        assert!(store.confirm().unwrap().is_none());
+        assert!(s.revert_pending(&store).unwrap().is_none());
+        assert_eq!(
+            format_blocks(&s.backend.read_policy().unwrap()),
+            format_blocks(&applied)
+        );
+        let err = s.confirm_cmd(false, false).unwrap_err();
+        assert!(err.contains("has been reverted"), "{}", err);
+
+        // a revert arriving after the change has been confirmed
+        let mut s = subcommands(&acl, "revert-race-late");
+        apply_pending(&mut s, INET);
+        let applied = s.backend.read_policy().unwrap();
+        let store = PendingStore::new(&s.backend.state_dir());
+        assert!(store.confirm().unwrap().is_some());
+        assert!(s.revert_pending(&store).unwrap().is_none());
+        assert_eq!(
+            format_blocks(&s.backend.read_policy().unwrap()),
+            format_blocks(&applied)
+        );
+    }
+
+    #[test]
+    fn group_edit_reads_live_policy() {
+        // a policy file given with `-f`, which lacks the groups
+        let acl = Acl::new();
+        let s = subcommands(&acl, "group-live");
+        let root = s.backend.root().to_path_buf();
+        let add = GroupEditParam::Add {
+            name: String::from("udom-session"),
+            member: String::from("/usr/bin/fish"),
+            kind: None,
+        };
+        s.group_edit_cmd(add, true, false).unwrap();
+        let live = Emulator::new(&root).read_policy().unwrap();
+        assert!(live.data.string_group["udom-session"].contains(&String::from("/usr/bin/fish")));
+
+        let s = subcommands(&acl, "group-live-kind");
+        let add = GroupEditParam::Add {
+            name: String::from("uid-normal"),
+            member: String::from("3000"),
+            kind: Some(GroupKind::String),
+        };
+        let err = s.group_edit_cmd(add, true, true).unwrap_err();
+        assert!(err.contains("defined as number_group"), "{}", err);
     }
 }
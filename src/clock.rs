@@ -0,0 +1,67 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// now returns the current unix time in seconds.
+///
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// parse_duration parses a positive duration in seconds, minutes or hours
+/// such as `30s`, `5m` and `1h`. A number without a unit is in seconds.
+///
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let n: u64 = n.parse().map_err(|_| format!("invalid duration: {}", s))?;
+    let secs = match unit {
+        "s" => Some(n),
+        "m" => n.checked_mul(60),
+        "h" => n.checked_mul(3600),
+        _ => return Err(format!("invalid duration unit (expected s, m or h): {}", s)),
+    };
+    match secs {
+        Some(0) => Err(format!("duration must be positive: {}", s)),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Err(format!("duration too large: {}", s)),
+    }
+}
+
+/// civil returns (year, month, day, hour, minute, second) in UTC for the
+/// unix time.
+///
+fn civil(secs: u64) -> (i64, u64, u64, u64, u64, u64) {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // days to civil date, from Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u64;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// format_timestamp formats the unix time as `YYYY/MM/DD hh:mm:ss` in UTC,
+/// in the same form as timestamps in caitsith.
+///
+pub fn format_timestamp(secs: u64) -> String {
+    let (y, m, d, hh, mm, ss) = civil(secs);
+    format!("{:04}/{:02}/{:02} {:02}:{:02}:{:02}", y, m, d, hh, mm, ss)
+}
+
+/// format_compact formats the unix time as `YYYYMMDD-hhmmss` in UTC, which
+/// is usable for file names.
+///
+pub fn format_compact(secs: u64) -> String {
+    let (y, m, d, hh, mm, ss) = civil(secs);
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", y, m, d, hh, mm, ss)
+}
@@ -1,15 +1,14 @@
 mod cli;
+mod clock;
+mod policy;
 mod ui;
 
 use crate::cli::command;
 use std::process::exit;
 
 fn main() {
-    match command::run() {
-        Err(e) => {
-            eprintln!("{}", e);
-            exit(1)
-        }
-        _ => {}
+    if let Err(e) = command::run() {
+        eprintln!("{}", e);
+        exit(1)
     }
 }
@@ -0,0 +1,81 @@
+pub mod audit;
+pub mod backend;
+pub mod check;
+pub mod confirm;
+pub mod diff;
+pub mod export;
+pub mod group;
+pub mod journal;
+pub mod learn;
+pub mod lint;
+pub mod pattern;
+pub mod protocol;
+pub mod responder;
+pub mod simulate;
+pub mod snapshot;
+pub mod stat;
+pub mod suggest;
+pub mod suite;
+
+use aclneko::acl::Acl;
+use aclneko::syntax::Op;
+
+/// BlockLines is a plain text view of an ACL block, holding the header line
+/// and its rule lines with the keys used to order blocks.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockLines {
+    pub priority: u16,
+    pub op: Op,
+    pub header: String,
+    pub rules: Vec<String>,
+}
+
+/// sorted_blocks returns every ACL block in the Acl as BlockLines, ordered by
+/// priority and operation in the same way as `Acl::parse_acl_headers`.
+///
+/// Rule lines are trimmed and keep the order in which they are defined.
+///
+pub fn sorted_blocks(acl: &Acl) -> Vec<BlockLines> {
+    let mut res: Vec<BlockLines> = acl
+        .data
+        .values()
+        .map(|b| BlockLines {
+            priority: b.header.priority,
+            op: b.header.op,
+            header: b.header.to_string(),
+            rules: b
+                .rule
+                .iter()
+                .map(|r| r.to_string().trim().to_string())
+                .collect(),
+        })
+        .collect();
+    res.sort_by(|v, w| (v.priority, v.op, &v.header).cmp(&(w.priority, w.op, &w.header)));
+    res
+}
+
+/// format_blocks returns the text of ACL blocks in the Acl, ordered with
+/// `sorted_blocks`.
+///
+pub fn format_blocks(acl: &Acl) -> String {
+    let mut res = String::new();
+    for b in sorted_blocks(acl) {
+        res += &format!("{}\n", b.header);
+        for r in b.rules {
+            res += &format!("    {}\n", r);
+        }
+    }
+    res
+}
+
+/// temp_root returns a new empty directory named after the test, to be used
+/// as the root directory of a backend.
+///
+#[cfg(test)]
+pub fn temp_root(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("acquery-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,431 @@
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// OBJECT_ATTRS are attributes of a request which name the object of the
+/// operation, in the order printed in the summary of an event.
+///
+pub const OBJECT_ATTRS: [&str; 10] = [
+    "path", "old_path", "new_path", "source", "target", "ip", "port", "fstype", "exec", "name",
+];
+
+/// FieldKind is the category of a field of an audit record. Fields before
+/// ` / ` are in the header, and fields after the operation are categorized
+/// by their names.
+///
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKind {
+    Header,
+    Request,
+    Task,
+    Path,
+    PathParent,
+    Transition,
+}
+
+impl FieldKind {
+    pub fn list() -> Vec<FieldKind> {
+        vec![
+            FieldKind::Header,
+            FieldKind::Request,
+            FieldKind::Task,
+            FieldKind::Path,
+            FieldKind::PathParent,
+            FieldKind::Transition,
+        ]
+    }
+
+    /// of returns the category of a field after the operation: `task.*`,
+    /// attributes of path objects such as `path.uid` and `old_path.type`,
+    /// those of their parent directories such as `path.parent.perm`,
+    /// `transition`, or arguments of the request such as `path` and `port`.
+    ///
+    fn of(key: &str) -> FieldKind {
+        match key.split_once('.') {
+            Some(("task", _)) => FieldKind::Task,
+            Some((_, attr)) if attr.starts_with("parent.") => FieldKind::PathParent,
+            Some(_) => FieldKind::Path,
+            None if key == "transition" => FieldKind::Transition,
+            None => FieldKind::Request,
+        }
+    }
+}
+
+/// AuditField is a `key=value` field of an audit record. The value is kept
+/// as written in the record without the quotes, so that escape sequences of
+/// caitsith such as `\040` stay usable in the policy.
+///
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditField {
+    pub kind: FieldKind,
+    pub key: String,
+    pub value: String,
+    pub quoted: bool,
+}
+
+impl AuditField {
+    /// text returns the field as written in the record.
+    ///
+    pub fn text(&self) -> String {
+        match self.quoted {
+            true => format!("{}=\"{}\"", self.key, self.value),
+            false => format!("{}={}", self.key, self.value),
+        }
+    }
+}
+
+/// AuditRecord is an audit record of caitsith, as written in the audit log
+/// and the query interface:
+///
+/// ```text
+/// #2026/10/18 09:12:01# global-pid=3720 result=denied priority=1000 / write path="/tmp/a" task.exe="/usr/bin/eog" ...
+/// ```
+///
+/// Fields are kept in the order of the record with their categories.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub time: Option<String>,
+    pub operation: String,
+    pub fields: Vec<AuditField>,
+}
+
+/// Token is a word of an audit record.
+///
+enum Token {
+    Word(String),
+    Field(String, String, bool),
+}
+
+impl AuditRecord {
+    /// parse parses an audit record. Values may be quoted with `"`, in which
+    /// whitespace and `\"` are kept in the value. It fails for records
+    /// without ` / ` and the operation, or with an unterminated quote.
+    ///
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut rest = text.trim();
+        let mut time = None;
+        if let Some((t, r)) = rest.strip_prefix('#').and_then(|r| r.split_once('#')) {
+            time = Some(t.to_string());
+            rest = r;
+        }
+        let mut fields = vec![];
+        let mut operation = None;
+        let mut in_header = true;
+        for token in tokenize(rest)? {
+            match token {
+                Token::Word(w) if in_header && w == "/" => in_header = false,
+                Token::Word(w) if !in_header && operation.is_none() => operation = Some(w),
+                Token::Word(w) => return Err(format!("unexpected word: {}", w)),
+                Token::Field(key, value, quoted) => fields.push(AuditField {
+                    kind: match in_header {
+                        true => FieldKind::Header,
+                        false => FieldKind::of(&key),
+                    },
+                    key,
+                    value,
+                    quoted,
+                }),
+            }
+        }
+        if in_header {
+            return Err("missing ` / ` after the header".to_string());
+        }
+        Ok(AuditRecord {
+            time,
+            operation: operation.ok_or("missing operation")?,
+            fields,
+        })
+    }
+
+    /// get returns the value of the first field with the key.
+    ///
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|f| f.key == key)
+            .map(|f| f.value.as_str())
+    }
+
+    pub fn result(&self) -> Option<&str> {
+        self.get("result")
+    }
+
+    pub fn priority(&self) -> Option<u16> {
+        self.get("priority").and_then(|p| p.parse().ok())
+    }
+
+    /// fields_of returns fields of the category in the order of the record.
+    ///
+    pub fn fields_of(&self, kind: FieldKind) -> impl Iterator<Item = &AuditField> {
+        self.fields.iter().filter(move |f| f.kind == kind)
+    }
+
+    /// attrs returns values of all fields by their keys.
+    ///
+    pub fn attrs(&self) -> BTreeMap<String, String> {
+        self.fields
+            .iter()
+            .map(|f| (f.key.clone(), f.value.clone()))
+            .collect()
+    }
+}
+
+/// tokenize splits text into words and `key=value` fields separated by
+/// whitespace. A quoted value ends at the first `"` not escaped with `\`.
+///
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(tokens);
+        }
+        let mut word = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=') {
+            word.push(c);
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            tokens.push(Token::Word(word));
+            continue;
+        }
+        let mut value = String::new();
+        let quoted = chars.next_if_eq(&'"').is_some();
+        match quoted {
+            true => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => {
+                        value.push('\\');
+                        value.extend(chars.next());
+                    }
+                    Some(c) => value.push(c),
+                    None => return Err(format!("unterminated quote: {}=\"{}", word, value)),
+                }
+            },
+            false => {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    value.push(c);
+                }
+            }
+        }
+        tokens.push(Token::Field(word, value, quoted));
+    }
+}
+
+/// AuditEvent is a record of the audit log with its line number, flattened
+/// for filtering and export. Fields other than the timestamp, `result` and
+/// `priority` are held in `attrs`.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct AuditEvent {
+    pub line: usize,
+    pub time: Option<String>,
+    pub result: Option<String>,
+    pub priority: Option<u16>,
+    pub operation: String,
+    pub attrs: BTreeMap<String, String>,
+}
+
+impl AuditEvent {
+    /// parse parses a line of the audit log. It returns None for lines
+    /// which are not audit records.
+    ///
+    pub fn parse(line: usize, text: &str) -> Option<Self> {
+        let record = AuditRecord::parse(text).ok()?;
+        let mut attrs = record.attrs();
+        attrs.remove("result");
+        attrs.remove("priority");
+        Some(AuditEvent {
+            line,
+            time: record.time.clone(),
+            result: record.result().map(|r| r.to_string()),
+            priority: record.priority(),
+            operation: record.operation,
+            attrs,
+        })
+    }
+
+    /// field returns the value of a field by its name. Besides the names of
+    /// the audit log, `time`, `date` (YYYY/MM/DD), `hour` (YYYY/MM/DD hh),
+    /// `result`, `priority`, `operation` and the short names `exe`,
+    /// `domain`, `uid`, `gid`, `pid` and `ppid` for `task.*` are accepted.
+    ///
+    pub fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "time" => self.time.clone(),
+            "date" => self.time.as_ref().map(|t| t.chars().take(10).collect()),
+            "hour" => self.time.as_ref().map(|t| t.chars().take(13).collect()),
+            "result" => self.result.clone(),
+            "priority" => self.priority.map(|p| p.to_string()),
+            "operation" | "op" => Some(self.operation.clone()),
+            "exe" | "domain" | "uid" | "gid" | "pid" | "ppid" => {
+                self.attrs.get(&format!("task.{}", name)).cloned()
+            }
+            _ => self.attrs.get(name).cloned(),
+        }
+    }
+
+    /// object returns `key=value` pairs of OBJECT_ATTRS in the event.
+    ///
+    pub fn object(&self) -> Vec<String> {
+        OBJECT_ATTRS
+            .iter()
+            .filter_map(|k| self.attrs.get(*k).map(|v| format!("{}={}", k, v)))
+            .collect()
+    }
+}
+
+/// parse_events parses audit records in the text, skipping other lines.
+///
+pub fn parse_events(text: &str) -> Vec<AuditEvent> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(i, l)| AuditEvent::parse(i + 1, l))
+        .collect()
+}
+
+/// EventFilter selects events whose fields match all of the regexes, in the
+/// time range. `since` and `until` are prefixes of timestamps, and `until`
+/// includes the events whose timestamp starts with it.
+///
+#[derive(Default)]
+pub struct EventFilter {
+    pub conds: Vec<(String, Regex)>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+impl EventFilter {
+    /// add adds a condition on the field with a regex.
+    ///
+    pub fn add(&mut self, field: &str, pattern: &str) -> Result<(), String> {
+        let re = Regex::new(pattern).map_err(|e| format!("{}: {}", field, e))?;
+        self.conds.push((field.to_string(), re));
+        Ok(())
+    }
+
+    pub fn is_match(&self, e: &AuditEvent) -> bool {
+        let time = e.time.as_deref().unwrap_or_default();
+        if self.since.as_ref().is_some_and(|s| time < s.as_str()) {
+            return false;
+        }
+        if let Some(u) = &self.until {
+            let prefix: String = time.chars().take(u.len()).collect();
+            if prefix > *u {
+                return false;
+            }
+        }
+        self.conds
+            .iter()
+            .all(|(f, re)| e.field(f).is_some_and(|v| re.is_match(&v)))
+    }
+}
+
+/// validate_time checks a time bound in the form of `YYYY/MM/DD`,
+/// `YYYY/MM/DD hh`, `YYYY/MM/DD hh:mm` or `YYYY/MM/DD hh:mm:ss`.
+///
+pub fn validate_time(t: &str) -> Result<(), String> {
+    let re = Regex::new(r"^\d{4}/\d{2}/\d{2}( \d{2}(:\d{2}(:\d{2})?)?)?$").unwrap();
+    match re.is_match(t) {
+        true => Ok(()),
+        false => Err(format!(
+            "invalid time (expected YYYY/MM/DD[ hh:mm:ss]): {}",
+            t
+        )),
+    }
+}
+
+/// GroupCount is the number of events which have the same values of the
+/// grouping fields. A missing field is counted as an empty string.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct GroupCount {
+    #[serde(flatten)]
+    pub key: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub values: Vec<String>,
+    pub count: usize,
+}
+
+/// count_by counts events grouped by the fields, sorted by the count in
+/// descending order and the values. `top` limits the number of groups.
+///
+pub fn count_by(events: &[AuditEvent], fields: &[String], top: Option<usize>) -> Vec<GroupCount> {
+    let mut counts: BTreeMap<Vec<String>, usize> = BTreeMap::new();
+    for e in events {
+        let values = fields
+            .iter()
+            .map(|f| e.field(f).unwrap_or_default())
+            .collect();
+        *counts.entry(values).or_default() += 1;
+    }
+    let mut res: Vec<GroupCount> = counts
+        .into_iter()
+        .map(|(values, count)| GroupCount {
+            key: fields.iter().cloned().zip(values.iter().cloned()).collect(),
+            values,
+            count,
+        })
+        .collect();
+    res.sort_by(|a, b| b.count.cmp(&a.count).then(a.values.cmp(&b.values)));
+    res.truncate(top.unwrap_or(res.len()));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_record() {
+        let r = AuditRecord::parse(
+            r#"#2026/10/18 09:12:01# global-pid=3720 result=denied priority=1000 / write path="/home/alice/a\040b" task.exe="/usr/bin/eog" path.uid=1000 path.parent.perm=0755 transition="user""#,
+        )
+        .unwrap();
+        assert_eq!(r.time.as_deref(), Some("2026/10/18 09:12:01"));
+        assert_eq!(r.operation, "write");
+        assert_eq!(r.result(), Some("denied"));
+        assert_eq!(r.priority(), Some(1000));
+        assert_eq!(r.get("path"), Some(r"/home/alice/a\040b"));
+
+        let kinds: Vec<(&str, FieldKind)> =
+            r.fields.iter().map(|f| (f.key.as_str(), f.kind)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("global-pid", FieldKind::Header),
+                ("result", FieldKind::Header),
+                ("priority", FieldKind::Header),
+                ("path", FieldKind::Request),
+                ("task.exe", FieldKind::Task),
+                ("path.uid", FieldKind::Path),
+                ("path.parent.perm", FieldKind::PathParent),
+                ("transition", FieldKind::Transition),
+            ]
+        );
+        assert!(r.fields[3].quoted);
+        assert!(!r.fields[5].quoted);
+        assert_eq!(r.fields[3].text(), r#"path="/home/alice/a\040b""#);
+    }
+
+    #[test]
+    fn parse_quoted_values() {
+        let r =
+            AuditRecord::parse(r#"result=denied / execute exec.argv[0]="a \"b\" c" path=/bin/sh"#)
+                .unwrap();
+        assert_eq!(r.time, None);
+        assert_eq!(r.get("exec.argv[0]"), Some(r#"a \"b\" c"#));
+        assert_eq!(r.get("path"), Some("/bin/sh"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(AuditRecord::parse("result=denied write path=/tmp/a").is_err());
+        assert!(AuditRecord::parse("result=denied /").is_err());
+        assert!(AuditRecord::parse(r#"result=denied / write path="/tmp/a"#).is_err());
+        assert!(AuditRecord::parse("result=denied / write extra path=/tmp/a").is_err());
+    }
+}
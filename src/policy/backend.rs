@@ -0,0 +1,583 @@
+use super::audit::AuditRecord;
+use super::protocol::parse_queries;
+use super::snapshot::STATE_DIR;
+use super::{format_blocks, simulate};
+use crate::ui::theme::{epaint, Style};
+use aclneko::acl::Acl;
+use aclneko::io::{PATCH_DIR, POLICY_FILE_PATH, POLICY_INTERFACE_PATH, QUERY_INTERFACE_PATH};
+use aclneko::syntax::{AuditMatcher, Category, Matcher, Op};
+use clap::ValueEnum;
+use nix::errno::Errno;
+use nix::poll::{self, PollTimeout};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::{Read, Write};
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// EMULATED_ANSWER_PATH is the file where the emulator records answers for
+/// queries, which are written back to the query interface on caitsith.
+///
+pub const EMULATED_ANSWER_PATH: &str = "/sys/kernel/security/caitsith/answer";
+
+const EMULATED_QUERY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// BackendKind selects an implementation of PolicyBackend.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    /// the policy and query interfaces of caitsith on securityfs
+    Securityfs,
+    /// plain files emulating caitsith interfaces under the root directory
+    Emulator,
+}
+
+/// new_backend returns a PolicyBackend of the kind, which resolves every
+/// interface path under the root directory.
+///
+pub fn new_backend(kind: BackendKind, root: &Path) -> Box<dyn PolicyBackend> {
+    match kind {
+        BackendKind::Securityfs => Box::new(Securityfs::new(root)),
+        BackendKind::Emulator => Box::new(Emulator::new(root)),
+    }
+}
+
+/// PolicyBackend is the interface to the policy and the query interface of
+/// caitsith. Any modification of the policy is done with `write_policy`, which
+/// takes lines in the same syntax as the policy interface, including
+/// `delete` lines.
+///
+/// Policy operations are provided on top of `write_policy` with the same
+/// names and semantics as `aclneko::io`, except `clear_rule` and so
+/// `unmerge_acl`, which remove the rules of the patch as
+/// `simulate::clear_rule` computes. See `clear_rule` for the difference.
+///
+pub trait PolicyBackend {
+    /// kind returns the kind of the backend.
+    fn kind(&self) -> BackendKind;
+
+    /// root returns the directory under which interface paths are resolved.
+    fn root(&self) -> &Path;
+
+    /// write_policy writes policy lines to the policy interface.
+    fn write_policy(&mut self, text: &str) -> Result<(), String>;
+
+    /// wait_query blocks until queries arrive and returns the text read from
+    /// the query interface.
+    fn wait_query(&mut self) -> Result<String, String> {
+        loop {
+            if let Some(text) = self.poll_query(Duration::MAX)? {
+                return Ok(text);
+            }
+        }
+    }
+
+    /// poll_query waits for queries at most for the timeout and returns the
+    /// text read from the query interface, or None if no query arrives or
+    /// the wait is interrupted by a signal.
+    fn poll_query(&mut self, timeout: Duration) -> Result<Option<String>, String>;
+
+    /// answer_query answers a pending query with 1 (permit), 2 (deny) or
+    /// 3 (re-evaluate).
+    fn answer_query(&mut self, query_id: &str, answer: u8) -> Result<(), String>;
+
+    /// select_query returns the policy text related to a pending query.
+    fn select_query(&mut self, query_id: &str) -> Result<String, String>;
+
+    /// add_query_rule appends a rule line into the ACL block related to a
+    /// pending query.
+    fn add_query_rule(&mut self, query_id: &str, rule: &str) -> Result<(), String>;
+
+    fn resolve(&self, path: &str) -> PathBuf {
+        self.root().join(path.trim_start_matches('/'))
+    }
+
+    fn policy_interface_path(&self) -> PathBuf {
+        self.resolve(POLICY_INTERFACE_PATH)
+    }
+
+    fn query_interface_path(&self) -> PathBuf {
+        self.resolve(QUERY_INTERFACE_PATH)
+    }
+
+    fn policy_file_path(&self) -> PathBuf {
+        self.resolve(POLICY_FILE_PATH)
+    }
+
+    fn patch_dir(&self) -> PathBuf {
+        self.resolve(PATCH_DIR)
+    }
+
+    fn state_dir(&self) -> PathBuf {
+        self.resolve(STATE_DIR)
+    }
+
+    /// read_policy reads the live policy from the policy interface.
+    fn read_policy(&self) -> Result<Acl, String> {
+        read_policy_path(&self.policy_interface_path())
+    }
+
+    /// read_policy_file reads the system policy which is loaded on boot.
+    fn read_policy_file(&self) -> Result<Acl, String> {
+        read_policy_path(&self.policy_file_path())
+    }
+
+    fn apply_acl(&mut self, acl: &Acl) -> Result<(), String> {
+        self.write_policy(&(format!("{}", acl) + "\n"))
+    }
+
+    fn apply_acl_atomic(&mut self, acl: &Acl) -> Result<(), String> {
+        match acl.is_atomic() {
+            true => self.apply_acl(acl),
+            false => Err(String::from("Selected ACL is not atomic")),
+        }
+    }
+
+    /// clear_acl remove Acl specified with the headers in supplied Acl instance.
+    fn clear_acl(&mut self, acl: &Acl) -> Result<(), String> {
+        let mut text = String::new();
+        for h in acl.parse_acl_headers() {
+            text += &format!("delete {}\n", h);
+        }
+        self.write_policy(&text)
+    }
+
+    /// remove_acl checks a patch has cooresponding header for target Acl and
+    /// removes ACLs from current system policy with target headers.
+    fn remove_acl(&mut self, patch: &Acl, from: &Acl) -> Result<(), String> {
+        simulate::remove_acl(patch, from)?;
+        self.clear_acl(patch)
+    }
+
+    fn remove_acl_atomic(&mut self, patch: &Acl, from: &Acl) -> Result<(), String> {
+        match patch.is_atomic() {
+            true => self.remove_acl(patch, from),
+            false => Err(String::from("Selected ACL is not atomic")),
+        }
+    }
+
+    /// clear_rule removes rules which is contained in the patch, rewriting
+    /// ACL blocks with the rest of rules: each block with a header of the
+    /// patch is deleted and written again without the rules of the patch.
+    ///
+    /// This differs from `aclneko::io::clear_rule`, which writes a block
+    /// back as a whole whenever it has a rule not in the patch, so that the
+    /// rules of the patch are left in the policy. `remove --unmerge` on the
+    /// securityfs backend removes the rules with this implementation.
+    fn clear_rule(&mut self, patch: &Acl, from: &Acl) -> Result<(), String> {
+        let res = simulate::clear_rule(patch, from);
+        let blocks: Vec<_> = res
+            .data
+            .values()
+            .filter(|b| patch.has_header(&b.header))
+            .collect();
+        let rest = Acl::from(blocks);
+        self.clear_acl(patch)?;
+        self.apply_acl(&rest)
+    }
+
+    /// unmerge_acl checks a patch has cooresponding header for target Acl and
+    /// remove for each rule defined in the patch from ACL blocks.
+    fn unmerge_acl(&mut self, patch: &Acl, from: &Acl) -> Result<(), String> {
+        simulate::unmerge_acl(patch, from)?;
+        self.clear_rule(patch, from)
+    }
+
+    /// list_registered_patches lists predefined patches under the patch directory.
+    fn list_registered_patches(&self) -> Result<(), String> {
+        eprintln!("{}", epaint(Style::Warning, "[registered patches]"));
+        let d = fs::read_dir(self.patch_dir()).map_err(|_| "cannot read patches".to_string())?;
+        for f in d.flatten() {
+            println!("{}", f.file_name().to_str().unwrap_or("-"));
+        }
+        Ok(())
+    }
+}
+
+/// read_policy_path reads and parses a policy from the path.
+///
+pub fn read_policy_path(path: &Path) -> Result<Acl, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Acl::from_str(&text)
+}
+
+/// Securityfs is the backend for caitsith on a running kernel.
+/// The query interface and the policy interface for queries are kept open
+/// during a query session.
+///
+pub struct Securityfs {
+    root: PathBuf,
+    query_interface: Option<File>,
+    policy_interface: Option<File>,
+}
+
+impl Securityfs {
+    pub fn new(root: &Path) -> Self {
+        Securityfs {
+            root: root.to_path_buf(),
+            query_interface: None,
+            policy_interface: None,
+        }
+    }
+
+    fn query_interface(&mut self) -> Result<&mut File, String> {
+        if self.query_interface.is_none() {
+            self.query_interface = Some(open_rw(&self.query_interface_path())?);
+        }
+        Ok(self.query_interface.as_mut().unwrap())
+    }
+
+    fn policy_interface(&mut self) -> Result<&mut File, String> {
+        if self.policy_interface.is_none() {
+            self.policy_interface = Some(open_rw(&self.policy_interface_path())?);
+        }
+        Ok(self.policy_interface.as_mut().unwrap())
+    }
+}
+
+fn open_rw(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+impl PolicyBackend for Securityfs {
+    fn kind(&self) -> BackendKind {
+        BackendKind::Securityfs
+    }
+
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn write_policy(&mut self, text: &str) -> Result<(), String> {
+        let path = self.policy_interface_path();
+        let mut fp = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        fp.write_all(text.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn poll_query(&mut self, timeout: Duration) -> Result<Option<String>, String> {
+        let qi = self.query_interface()?;
+        let qi_readonly = qi.try_clone().map_err(|e| e.to_string())?;
+        let pfd = poll::PollFd::new(qi_readonly.as_fd(), poll::PollFlags::POLLIN);
+        let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+        match poll::poll(&mut [pfd], timeout) {
+            Ok(0) | Err(Errno::EINTR) => return Ok(None),
+            Ok(_) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+        let mut buf = vec![];
+        qi.read_to_end(&mut buf).map_err(|e| e.to_string())?;
+        String::from_utf8(buf).map(Some).map_err(|e| e.to_string())
+    }
+
+    fn answer_query(&mut self, query_id: &str, answer: u8) -> Result<(), String> {
+        let ans = format!("A{}={}\n", query_id, answer);
+        self.query_interface()?
+            .write_all(ans.as_bytes())
+            .map_err(|e| e.to_string())
+    }
+
+    fn select_query(&mut self, query_id: &str) -> Result<String, String> {
+        let pi = self.policy_interface()?;
+        pi.write_all(format!("Q={}\n", query_id).as_bytes())
+            .map_err(|e| e.to_string())?;
+        let mut buf = vec![];
+        pi.read_to_end(&mut buf).map_err(|e| e.to_string())?;
+        String::from_utf8(buf).map_err(|e| e.to_string())
+    }
+
+    fn add_query_rule(&mut self, query_id: &str, rule: &str) -> Result<(), String> {
+        let pi = self.policy_interface()?;
+        pi.write_all(format!("Q={}\n{}", query_id, rule).as_bytes())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Emulator is the backend emulating caitsith interfaces with plain files
+/// under the root directory, for environments without caitsith.
+///
+/// Lines written to the policy interface are interpreted and the resulting
+/// policy is saved to the policy interface file. Queries are read from the
+/// query interface file, which is truncated after reading, and answers are
+/// appended to EMULATED_ANSWER_PATH. The ACL blocks related to a query are
+/// looked up with the operation in its audit line.
+///
+pub struct Emulator {
+    root: PathBuf,
+    pending: HashMap<String, String>,
+}
+
+impl Emulator {
+    pub fn new(root: &Path) -> Self {
+        Emulator {
+            root: root.to_path_buf(),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn query_acl(&self, query_id: &str) -> Result<Acl, String> {
+        let audit = self
+            .pending
+            .get(query_id)
+            .ok_or(format!("no such query: {}", query_id))?;
+        let op = AuditMatcher::new()
+            .parse_operation(audit)
+            .map(|o| Op::from(o.as_str()))
+            .unwrap_or(Op::Error);
+        self.read_policy()?.parse_acl_by_header(op.as_str())
+    }
+}
+
+impl PolicyBackend for Emulator {
+    fn kind(&self) -> BackendKind {
+        BackendKind::Emulator
+    }
+
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn write_policy(&mut self, text: &str) -> Result<(), String> {
+        let path = self.policy_interface_path();
+        let current = fs::read_to_string(&path).unwrap_or_default();
+        let pattern = Matcher::new();
+
+        // lines other than ACL blocks, such as groups and quota, are kept as is
+        let mut preamble = vec![];
+        let mut acl_text = String::new();
+        for line in current.lines() {
+            match pattern.parse_category(line) {
+                Category::Header | Category::Audit | Category::Rule => {
+                    acl_text += line;
+                    acl_text += "\n";
+                }
+                Category::Blank => {}
+                _ => preamble.push(line.to_string()),
+            }
+        }
+        let mut acl = Acl::from_str(&acl_text)?;
+
+        let mut pending = String::new();
+        for line in text.lines() {
+            if let Some(target) = line.strip_prefix("delete ") {
+                acl = simulate::apply_acl(&Acl::from_str(&pending)?, &acl);
+                pending.clear();
+                match pattern.parse_category(target) {
+                    Category::Header => acl = simulate::clear_acl(&Acl::from_str(target)?, &acl),
+                    _ => preamble.retain(|l| l.trim() != target.trim()),
+                }
+                continue;
+            }
+            match pattern.parse_category(line) {
+                Category::Header | Category::Audit | Category::Rule => {
+                    pending += line;
+                    pending += "\n";
+                }
+                Category::NumberGroupDef | Category::IpGroupDef | Category::StringGroupDef => {
+                    if !preamble.iter().any(|l| l.trim() == line.trim()) {
+                        preamble.push(line.trim().to_string());
+                    }
+                }
+                Category::Blank | Category::Comment => {}
+                _ => return Err(format!("unknown syntax: {}", line)),
+            }
+        }
+        acl = simulate::apply_acl(&Acl::from_str(&pending)?, &acl);
+
+        let res = preamble.join("\n") + "\n" + &format_blocks(&acl);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        fs::write(&path, res).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn poll_query(&mut self, timeout: Duration) -> Result<Option<String>, String> {
+        let path = self.query_interface_path();
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            let text = fs::read_to_string(&path).unwrap_or_default();
+            if text.trim().is_empty() {
+                let left = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+                if left.is_some_and(|l| l.is_zero()) {
+                    return Ok(None);
+                }
+                thread::sleep(
+                    left.unwrap_or(EMULATED_QUERY_INTERVAL)
+                        .min(EMULATED_QUERY_INTERVAL),
+                );
+                continue;
+            }
+            fs::write(&path, "").map_err(|e| format!("{}: {}", path.display(), e))?;
+            for q in parse_queries(&text).queries {
+                self.pending.insert(q.id, q.message);
+            }
+            return Ok(Some(text));
+        }
+    }
+
+    fn answer_query(&mut self, query_id: &str, answer: u8) -> Result<(), String> {
+        let path = self.resolve(EMULATED_ANSWER_PATH);
+        let mut fp = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        fp.write_all(format!("A{}={}\n", query_id, answer).as_bytes())
+            .map_err(|e| e.to_string())?;
+        if answer != 3 {
+            self.pending.remove(query_id);
+        }
+        Ok(())
+    }
+
+    fn select_query(&mut self, query_id: &str) -> Result<String, String> {
+        Ok(format_blocks(&self.query_acl(query_id)?))
+    }
+
+    fn add_query_rule(&mut self, query_id: &str, rule: &str) -> Result<(), String> {
+        let acl = self.query_acl(query_id)?;
+        // the rule goes to the block which raised the query, as the kernel does
+        let priority = self
+            .pending
+            .get(query_id)
+            .and_then(|m| AuditRecord::parse(m).ok())
+            .and_then(|r| r.priority());
+        let headers = acl.parse_acl_headers();
+        match headers
+            .iter()
+            .find(|h| Some(h.priority) == priority)
+            .or(headers.first())
+        {
+            Some(h) => self.write_policy(&format!("{}\n{}", h, rule)),
+            None => Err(String::from("ACL header for the violation not detected")),
+        }
+    }
+}
+
+/// sample_emulator returns an Emulator on a new root directory, whose policy
+/// is the sample policy in fixtures.
+///
+#[cfg(test)]
+pub fn sample_emulator(name: &str) -> Emulator {
+    let mut e = Emulator::new(&super::temp_root(name));
+    let path = e.policy_interface_path();
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, include_str!("../../fixtures/sample-policy.acl")).unwrap();
+    // normalize the policy in the format written by the emulator
+    e.write_policy("").unwrap();
+    e
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::sorted_blocks;
+
+    const INET: &str = include_str!("../../fixtures/sample-patch-inet.acl");
+    const INET2: &str = include_str!("../../fixtures/sample-patch-inet2.acl");
+    const READ: &str = include_str!("../../fixtures/sample-patch-read.acl");
+
+    const CURL: &str = r#"10 acl inet_stream_connect task.exe="/usr/bin/curl""#;
+
+    fn acl(text: &str) -> Acl {
+        Acl::from_str(text).unwrap()
+    }
+
+    fn rules_of(acl: &Acl, header: &str) -> Option<Vec<String>> {
+        sorted_blocks(acl)
+            .into_iter()
+            .find(|b| b.header == header)
+            .map(|b| b.rules)
+    }
+
+    #[test]
+    fn apply_and_remove() {
+        let mut e = sample_emulator("apply-remove");
+        let before = e.read_policy().unwrap();
+
+        e.apply_acl(&acl(INET)).unwrap();
+        let applied = e.read_policy().unwrap();
+        assert_eq!(
+            format_blocks(&applied),
+            format_blocks(&simulate::apply_acl(&acl(INET), &before))
+        );
+        assert_eq!(
+            rules_of(&applied, CURL),
+            Some(vec!["0 allow port=443".to_string(), "0 deny".to_string()])
+        );
+
+        e.remove_acl(&acl(INET2), &applied).unwrap();
+        assert_eq!(
+            format_blocks(&e.read_policy().unwrap()),
+            format_blocks(&before)
+        );
+    }
+
+    #[test]
+    fn remove_missing_header_fails() {
+        let mut e = sample_emulator("remove-missing");
+        let before = fs::read_to_string(e.policy_interface_path()).unwrap();
+        let live = e.read_policy().unwrap();
+        assert!(e.remove_acl(&acl(READ), &live).is_err());
+        assert!(e.unmerge_acl(&acl(READ), &live).is_err());
+        assert!(e
+            .apply_acl_atomic(&acl(&format!("{}{}", INET, READ)))
+            .is_err());
+        assert_eq!(
+            fs::read_to_string(e.policy_interface_path()).unwrap(),
+            before
+        );
+    }
+
+    #[test]
+    fn unmerge_and_clear_rule() {
+        let mut e = sample_emulator("unmerge");
+        e.apply_acl(&acl(INET)).unwrap();
+        let applied = e.read_policy().unwrap();
+
+        e.unmerge_acl(&acl(INET2), &applied).unwrap();
+        let unmerged = e.read_policy().unwrap();
+        assert_eq!(rules_of(&unmerged, CURL), Some(vec!["0 deny".to_string()]));
+        assert_eq!(
+            format_blocks(&unmerged),
+            format_blocks(&simulate::unmerge_acl(&acl(INET2), &applied).unwrap())
+        );
+
+        e.clear_rule(&acl(INET), &unmerged).unwrap();
+        assert_eq!(rules_of(&e.read_policy().unwrap(), CURL), Some(vec![]));
+    }
+
+    #[test]
+    fn groups_and_quota_are_kept() {
+        let mut e = sample_emulator("preamble");
+        let preamble = |e: &Emulator| -> Vec<String> {
+            fs::read_to_string(e.policy_interface_path())
+                .unwrap()
+                .lines()
+                .filter(|l| l.contains("_group ") || l.starts_with("quota "))
+                .map(|l| l.to_string())
+                .collect()
+        };
+        let before = preamble(&e);
+        assert!(!before.is_empty());
+
+        e.apply_acl(&acl(INET)).unwrap();
+        e.clear_acl(&acl(INET)).unwrap();
+        assert_eq!(preamble(&e), before);
+
+        e.write_policy("string_group NEW /tmp/\\*\n").unwrap();
+        assert!(preamble(&e).contains(&"string_group NEW /tmp/\\*".to_string()));
+        e.write_policy("delete string_group NEW /tmp/\\*\n")
+            .unwrap();
+        assert_eq!(preamble(&e), before);
+    }
+}
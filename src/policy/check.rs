@@ -0,0 +1,433 @@
+use super::group::parse_number;
+use super::pattern::PathPattern;
+use super::{sorted_blocks, BlockLines};
+use aclneko::acl::Acl;
+use aclneko::syntax::{Cond, Matcher, Op, Resource};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+/// Request is a hypothetical request to be evaluated with the policy, made of
+/// an operation and attributes such as `path=/usr/bin/curl`.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct Request {
+    pub operation: String,
+    pub attrs: BTreeMap<String, String>,
+}
+
+impl Request {
+    /// parse parses an operation and `key=value` attributes. Values may be
+    /// quoted with `"`.
+    ///
+    pub fn parse(operation: &str, attrs: &[String]) -> Result<Self, String> {
+        if Op::from(operation) == Op::Error {
+            return Err(format!("invalid operation: {}", operation));
+        }
+        let mut res = Request {
+            operation: operation.to_string(),
+            attrs: BTreeMap::new(),
+        };
+        for a in attrs {
+            let (k, v) = a
+                .split_once('=')
+                .ok_or(format!("attribute must be key=value: {}", a))?;
+            if k.is_empty() || Resource::from(k).as_str() != k {
+                return Err(format!("invalid attribute: {}", k));
+            }
+            res.attrs
+                .insert(k.to_string(), v.trim_matches('"').to_string());
+        }
+        Ok(res)
+    }
+}
+
+/// Verdict is the result of the evaluation for a request.
+///
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Allow,
+    Deny,
+}
+
+impl Verdict {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verdict::Allow => "allow",
+            Verdict::Deny => "deny",
+        }
+    }
+}
+
+/// AclTrace is the evaluation of an ACL block for the operation. `rule` is
+/// the first matched rule in the block, if the header matches. `missing`
+/// lists attributes referenced by conditions but not given in the request,
+/// for which conditions are evaluated as unmatched.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct AclTrace {
+    pub header: String,
+    pub matched: bool,
+    pub rule: Option<String>,
+    pub missing: Vec<String>,
+}
+
+/// CheckResult is the verdict for a request with the deciding ACL block and
+/// rule, and the domain transition given by matched allow rules.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct CheckResult {
+    pub request: Request,
+    pub verdict: Verdict,
+    pub acl: Option<String>,
+    pub rule: Option<String>,
+    pub transition: Option<String>,
+    pub trace: Vec<AclTrace>,
+}
+
+/// check_request evaluates a request in the same way as caitsith.
+///
+/// ACL blocks for the operation are evaluated in the priority order. In a
+/// block whose header conditions match, rules are evaluated in the priority
+/// order and the first rule whose conditions match decides: `deny` rejects
+/// the request, and `allow` skips the rest of the block. A request is
+/// allowed if no `deny` rule matches.
+///
+pub fn check_request(acl: &Acl, req: &Request) -> CheckResult {
+    let m = Matcher::new();
+    let op = Op::from(req.operation.as_str());
+    let mut res = CheckResult {
+        request: req.clone(),
+        verdict: Verdict::Allow,
+        acl: None,
+        rule: None,
+        transition: None,
+        trace: vec![],
+    };
+
+    for b in sorted_blocks(acl).into_iter().filter(|b| b.op == op) {
+        let mut missing = vec![];
+        let matched = m
+            .parse_attr_list(&b.header)
+            .iter()
+            .all(|a| eval_cond(acl, req, a, &mut missing));
+        let mut trace = AclTrace {
+            header: b.header.clone(),
+            matched,
+            rule: None,
+            missing: vec![],
+        };
+        if matched {
+            let mut rules: Vec<(u16, &String)> = b
+                .rules
+                .iter()
+                .filter(|r| m.is_acl_rule(&format!(" {}", r)))
+                .map(|r| (r.split_whitespace().next().unwrap().parse().unwrap_or(0), r))
+                .collect();
+            rules.sort_by_key(|r| r.0);
+            for (_, r) in rules {
+                let attrs = m.parse_attr_list(r);
+                let (transition, conds): (Vec<_>, Vec<_>) =
+                    attrs.iter().partition(|a| a.0 == Resource::Transition);
+                if conds.iter().all(|a| eval_cond(acl, req, a, &mut missing)) {
+                    trace.rule = Some(r.clone());
+                    let is_deny = r.split_whitespace().nth(1) == Some("deny");
+                    if is_deny || res.rule.is_none() {
+                        res.acl = Some(b.header.clone());
+                        res.rule = Some(r.clone());
+                    }
+                    if is_deny {
+                        res.verdict = Verdict::Deny;
+                    } else if res.transition.is_none() {
+                        res.transition = transition
+                            .first()
+                            .map(|t| t.2.trim_matches('"').to_string());
+                    }
+                    break;
+                }
+            }
+        }
+        missing.sort();
+        missing.dedup();
+        trace.missing = missing;
+        res.trace.push(trace);
+        if res.verdict == Verdict::Deny {
+            res.transition = None;
+            break;
+        }
+    }
+    res
+}
+
+/// filter_blocks returns ACL blocks and rules whose conditions on the given
+/// attributes can be satisfied. Conditions on other attributes are assumed
+/// to be satisfiable. A block is returned if its header has a satisfied
+/// condition on the attributes, with every satisfiable rule, or otherwise
+/// with rules which have a satisfied condition on the attributes.
+///
+pub fn filter_blocks(acl: &Acl, attrs: &BTreeMap<String, String>) -> Vec<BlockLines> {
+    let m = Matcher::new();
+    let satisfiable = |line: &str| {
+        let results: Vec<Option<bool>> = m
+            .parse_attr_list(line)
+            .iter()
+            .filter(|a| a.0 != Resource::Transition)
+            .map(|a| eval_known(acl, attrs, a))
+            .collect();
+        match results.contains(&Some(false)) {
+            true => None,
+            false => Some(results.contains(&Some(true))),
+        }
+    };
+
+    let mut res = vec![];
+    for mut b in sorted_blocks(acl) {
+        let in_header = match satisfiable(&b.header) {
+            Some(r) => r,
+            None => continue,
+        };
+        b.rules.retain(|r| {
+            m.is_acl_rule(&format!(" {}", r))
+                && match satisfiable(r) {
+                    Some(r) => r || in_header,
+                    None => false,
+                }
+        });
+        if in_header || !b.rules.is_empty() {
+            res.push(b);
+        }
+    }
+    res
+}
+
+/// filter_blocks_any returns the union of filter_blocks for alternative sets
+/// of attributes, such as a path given as `path`, `old_path` or `new_path`.
+/// A rule is kept if it is kept for any of the alternatives.
+///
+pub fn filter_blocks_any(acl: &Acl, alternatives: &[BTreeMap<String, String>]) -> Vec<BlockLines> {
+    let found: Vec<BlockLines> = alternatives
+        .iter()
+        .flat_map(|attrs| filter_blocks(acl, attrs))
+        .collect();
+    let mut res = vec![];
+    for mut b in sorted_blocks(acl) {
+        let blocks: Vec<&BlockLines> = found.iter().filter(|f| f.header == b.header).collect();
+        if blocks.is_empty() {
+            continue;
+        }
+        b.rules
+            .retain(|r| blocks.iter().any(|f| f.rules.contains(r)));
+        res.push(b);
+    }
+    res
+}
+
+/// eval_cond evaluates a condition in an ACL header or rule. A condition on
+/// an attribute missing in the request never matches.
+///
+fn eval_cond(
+    acl: &Acl,
+    req: &Request,
+    cond: &(Resource, Cond, String),
+    missing: &mut Vec<String>,
+) -> bool {
+    match eval_known(acl, &req.attrs, cond) {
+        Some(matched) => matched,
+        None => {
+            match req.attrs.contains_key(cond.0.as_str()) {
+                true => missing.push(cond.2.clone()),
+                false => missing.push(cond.0.as_str().to_string()),
+            }
+            false
+        }
+    }
+}
+
+/// eval_known evaluates a condition with the attributes, or returns None if
+/// the condition references an attribute which is not given.
+///
+fn eval_known(
+    acl: &Acl,
+    attrs: &BTreeMap<String, String>,
+    cond: &(Resource, Cond, String),
+) -> Option<bool> {
+    let (res, op, target) = cond;
+    let value = attrs.get(res.as_str())?;
+
+    let matched = if let Some(quoted) = target.strip_prefix('"') {
+        match_string(quoted.trim_end_matches('"'), value)
+    } else if let Some(name) = target.strip_prefix('@') {
+        if let Some(members) = acl.data.string_group.get(name) {
+            members.iter().any(|p| match_string(p, value))
+        } else if let Some(members) = acl.data.number_group.get(name) {
+            members.iter().any(|n| match_number(n, value))
+        } else if let Some(members) = acl.data.ip_group.get(name) {
+            members.iter().any(|i| match_ip(i, value))
+        } else {
+            false
+        }
+    } else if Resource::from(target.as_str()).as_str() == target {
+        let v = attrs.get(target)?;
+        v == value || match_number(v, value)
+    } else if target.contains(':') || target.contains('.') {
+        match_ip(target, value)
+    } else {
+        match_number(target, value)
+    };
+
+    match op {
+        Cond::Ne => Some(!matched),
+        _ => Some(matched),
+    }
+}
+
+fn match_string(pattern: &str, value: &str) -> bool {
+    match PathPattern::new(pattern) {
+        Ok(p) => p.is_match(value),
+        Err(_) => pattern == value,
+    }
+}
+
+fn match_number(range: &str, value: &str) -> bool {
+    let v = match parse_number(value) {
+        Some(v) => v,
+        None => return false,
+    };
+    match range.split_once('-') {
+        Some((lo, hi)) => {
+            matches!((parse_number(lo), parse_number(hi)), (Some(l), Some(h)) if l <= v && v <= h)
+        }
+        None => parse_number(range) == Some(v),
+    }
+}
+
+fn match_ip(range: &str, value: &str) -> bool {
+    let v: IpAddr = match value.parse() {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    match range.split_once('-') {
+        Some((lo, hi)) => match (lo.parse::<IpAddr>(), hi.parse::<IpAddr>()) {
+            (Ok(l), Ok(h)) => l <= v && v <= h,
+            _ => false,
+        },
+        None => range.parse::<IpAddr>() == Ok(v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const POLICY: &str = r#"string_group ETC /etc/\*
+number_group ADMINS 0-10
+0 acl read path=@ETC
+    10 deny task.uid!=@ADMINS
+    20 allow
+10 acl read
+    10 deny path="/etc/shadow"
+20 acl execute path="/usr/bin/\*"
+    10 allow transition="user"
+    20 deny
+"#;
+
+    fn check(operation: &str, attrs: &[&str]) -> CheckResult {
+        let acl = Acl::from_str(POLICY).unwrap();
+        let attrs: Vec<String> = attrs.iter().map(|a| a.to_string()).collect();
+        check_request(&acl, &Request::parse(operation, &attrs).unwrap())
+    }
+
+    #[test]
+    fn deny_rule_decides() {
+        let res = check("read", &["path=/etc/passwd", "task.uid=1000"]);
+        assert_eq!(res.verdict, Verdict::Deny);
+        assert_eq!(res.acl.as_deref(), Some("0 acl read path=@ETC"));
+        assert_eq!(res.rule.as_deref(), Some("10 deny task.uid!=@ADMINS"));
+        assert_eq!(res.trace.len(), 1);
+    }
+
+    #[test]
+    fn allow_skips_the_rest_of_the_block() {
+        let res = check("read", &["path=/etc/passwd", "task.uid=0"]);
+        assert_eq!(res.verdict, Verdict::Allow);
+        assert_eq!(res.rule.as_deref(), Some("20 allow"));
+        assert_eq!(res.trace.len(), 2);
+        assert_eq!(res.trace[1].rule, None);
+    }
+
+    #[test]
+    fn later_block_denies() {
+        let res = check("read", &["path=/etc/shadow", "task.uid=0"]);
+        assert_eq!(res.verdict, Verdict::Deny);
+        assert_eq!(res.acl.as_deref(), Some("10 acl read"));
+    }
+
+    #[test]
+    fn unmatched_header_and_operation() {
+        let res = check("read", &["path=/home/user/a", "task.uid=1000"]);
+        assert_eq!(res.verdict, Verdict::Allow);
+        assert!(!res.trace[0].matched);
+        assert!(res.trace[1].matched);
+
+        let res = check("write", &["path=/etc/shadow"]);
+        assert_eq!(res.verdict, Verdict::Allow);
+        assert!(res.trace.is_empty());
+    }
+
+    #[test]
+    fn missing_attributes_never_match() {
+        let res = check("read", &["path=/etc/passwd"]);
+        assert_eq!(res.verdict, Verdict::Allow);
+        assert_eq!(res.trace[0].missing, vec!["task.uid".to_string()]);
+    }
+
+    #[test]
+    fn transition_of_allow_rule() {
+        let res = check("execute", &["path=/usr/bin/ls"]);
+        assert_eq!(res.verdict, Verdict::Allow);
+        assert_eq!(res.transition.as_deref(), Some("user"));
+
+        let res = check("execute", &["path=/usr/sbin/ls"]);
+        assert_eq!(res.transition, None);
+    }
+
+    #[test]
+    fn filter_blocks_for_alternatives() {
+        let acl = Acl::from_str(
+            r#"100 acl rename old_path="/tmp/\*" new_path="/var/\*"
+    1 allow
+200 acl rename
+    1 deny old_path="/etc/\*"
+    2 allow new_path="/tmp/\*"
+"#,
+        )
+        .unwrap();
+        let alternatives: Vec<BTreeMap<String, String>> = ["path", "old_path", "new_path"]
+            .iter()
+            .map(|k| BTreeMap::from([(k.to_string(), "/tmp/a".to_string())]))
+            .collect();
+
+        let found = filter_blocks_any(&acl, &alternatives);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].rules, vec!["1 allow".to_string()]);
+        assert_eq!(
+            found[1].rules,
+            vec![r#"2 allow new_path="/tmp/\*""#.to_string()]
+        );
+
+        let both = BTreeMap::from([
+            ("old_path".to_string(), "/tmp/a".to_string()),
+            ("new_path".to_string(), "/tmp/a".to_string()),
+        ]);
+        assert_eq!(filter_blocks(&acl, &both).len(), 1);
+    }
+
+    #[test]
+    fn invalid_requests() {
+        assert!(Request::parse("no_such_operation", &[]).is_err());
+        assert!(Request::parse("read", &["path".to_string()]).is_err());
+        assert!(Request::parse("read", &["no_such_attr=1".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use crate::clock::now;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// PendingChange is a policy change which is reverted unless it is confirmed
+/// before the deadline. The applied part of the patch is removed to revert
+/// the change, and the snapshot is rolled back to if it cannot be removed.
+///
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingChange {
+    pub snapshot: String,
+    pub created: u64,
+    pub deadline: u64,
+    pub source: String,
+    #[serde(default)]
+    pub applied: Option<AppliedPatch>,
+}
+
+/// AppliedPatch is the part of a patch added to the policy by a change, in
+/// policy text: `acls` holds ACL blocks added as a whole and `rules` holds
+/// rules added to existing blocks.
+///
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AppliedPatch {
+    pub acls: String,
+    pub rules: String,
+}
+
+impl PendingChange {
+    pub fn new(snapshot: &str, source: &str, within: u64, applied: AppliedPatch) -> Self {
+        let created = now();
+        PendingChange {
+            snapshot: snapshot.to_string(),
+            created,
+            deadline: created + within,
+            source: source.to_string(),
+            applied: Some(applied),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        now() >= self.deadline
+    }
+}
+
+/// PendingStore keeps the PendingChange in `pending.json` under the state
+/// directory, so that another invocation can confirm it.
+///
+/// Confirmation and revert race for the file: `confirm` removes it and
+/// `claim` renames it to `reverted.json`, and only one of them succeeds.
+///
+pub struct PendingStore {
+    pending: PathBuf,
+    reverted: PathBuf,
+}
+
+impl PendingStore {
+    pub fn new(state_dir: &Path) -> Self {
+        PendingStore {
+            pending: state_dir.join("pending.json"),
+            reverted: state_dir.join("reverted.json"),
+        }
+    }
+
+    /// read returns the pending change, if any.
+    ///
+    pub fn read(&self) -> Result<Option<PendingChange>, String> {
+        read_change(&self.pending)
+    }
+
+    /// read_reverted returns the change which was reverted last, if any.
+    ///
+    pub fn read_reverted(&self) -> Result<Option<PendingChange>, String> {
+        read_change(&self.reverted)
+    }
+
+    /// save saves a new pending change. It fails while another change is
+    /// pending.
+    ///
+    pub fn save(&self, change: &PendingChange) -> Result<(), String> {
+        if self.pending.exists() {
+            return Err(String::from(
+                "another change is pending confirmation; run `acquery confirm` first",
+            ));
+        }
+        if let Some(d) = self.pending.parent() {
+            fs::create_dir_all(d).map_err(|e| format!("{}: {}", d.display(), e))?;
+        }
+        let _ = fs::remove_file(&self.reverted);
+        let json = serde_json::to_string_pretty(change).map_err(|e| e.to_string())?;
+        fs::write(&self.pending, json).map_err(|e| format!("{}: {}", self.pending.display(), e))
+    }
+
+    /// confirm drops the pending change and returns it. It returns None if
+    /// no change is pending.
+    ///
+    pub fn confirm(&self) -> Result<Option<PendingChange>, String> {
+        let change = self.read()?;
+        match fs::remove_file(&self.pending) {
+            Ok(_) => Ok(change),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("{}: {}", self.pending.display(), e)),
+        }
+    }
+
+    /// claim takes the pending change for reverting it. It returns None if
+    /// the change has been confirmed or reverted already.
+    ///
+    pub fn claim(&self) -> Result<Option<PendingChange>, String> {
+        let change = self.read()?;
+        match fs::rename(&self.pending, &self.reverted) {
+            Ok(_) => Ok(change),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("{}: {}", self.pending.display(), e)),
+        }
+    }
+}
+
+fn read_change(path: &Path) -> Result<Option<PendingChange>, String> {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
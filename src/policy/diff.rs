@@ -0,0 +1,178 @@
+use super::{sorted_blocks, BlockLines};
+use aclneko::acl::Acl;
+use serde::Serialize;
+use std::cmp::Ordering;
+
+/// BlockChange classifies an ACL block which differs between two policies.
+///
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockChange {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// LineTag marks a rule line in unified output: kept, added or removed.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineTag {
+    Keep,
+    Add,
+    Remove,
+}
+
+impl LineTag {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Keep => " ",
+            Self::Add => "+",
+            Self::Remove => "-",
+        }
+    }
+}
+
+/// BlockDiff holds differences for an ACL block identified by its header.
+/// `lines` keeps the unified view of rules for the block, including
+/// unchanged rules as context.
+///
+#[derive(Serialize, Debug)]
+pub struct BlockDiff {
+    pub change: BlockChange,
+    pub header: String,
+    pub added_rules: Vec<String>,
+    pub removed_rules: Vec<String>,
+    #[serde(skip)]
+    pub lines: Vec<(LineTag, String)>,
+}
+
+/// AclDiff is the result of the ACL-by-ACL comparison of two policies.
+///
+#[derive(Serialize, Debug, Default)]
+pub struct AclDiff {
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+    pub blocks: Vec<BlockDiff>,
+}
+
+impl AclDiff {
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+/// diff_acls shows difference between source Acl and destination Acl.
+///
+/// ACL blocks are paired with their header lines. A block only in `dst` is
+/// added, a block only in `src` is removed and a block in both with different
+/// rules is changed. Blocks are reported in priority order.
+///
+pub fn diff_acls(src: &Acl, dst: &Acl) -> AclDiff {
+    let src = sorted_blocks(src);
+    let dst = sorted_blocks(dst);
+    let mut res = AclDiff::default();
+    let (mut i, mut j) = (0, 0);
+
+    while i < src.len() || j < dst.len() {
+        let order = match (src.get(i), dst.get(j)) {
+            (Some(s), Some(d)) => (s.priority, s.op, &s.header).cmp(&(d.priority, d.op, &d.header)),
+            (Some(_), None) => Ordering::Less,
+            _ => Ordering::Greater,
+        };
+        match order {
+            Ordering::Less => {
+                res.removed += 1;
+                res.blocks.push(whole_block(BlockChange::Removed, &src[i]));
+                i += 1;
+            }
+            Ordering::Greater => {
+                res.added += 1;
+                res.blocks.push(whole_block(BlockChange::Added, &dst[j]));
+                j += 1;
+            }
+            Ordering::Equal => {
+                if src[i].rules != dst[j].rules {
+                    let lines = diff_lines(&src[i].rules, &dst[j].rules);
+                    res.changed += 1;
+                    res.blocks.push(BlockDiff {
+                        change: BlockChange::Changed,
+                        header: src[i].header.clone(),
+                        added_rules: tagged(&lines, LineTag::Add),
+                        removed_rules: tagged(&lines, LineTag::Remove),
+                        lines,
+                    });
+                }
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    res
+}
+
+fn whole_block(change: BlockChange, block: &BlockLines) -> BlockDiff {
+    let tag = match change {
+        BlockChange::Added => LineTag::Add,
+        _ => LineTag::Remove,
+    };
+    let rules = block.rules.clone();
+    BlockDiff {
+        change,
+        header: block.header.clone(),
+        lines: rules.iter().map(|r| (tag, r.clone())).collect(),
+        added_rules: if tag == LineTag::Add {
+            rules.clone()
+        } else {
+            vec![]
+        },
+        removed_rules: if tag == LineTag::Remove {
+            rules
+        } else {
+            vec![]
+        },
+    }
+}
+
+fn tagged(lines: &[(LineTag, String)], tag: LineTag) -> Vec<String> {
+    lines
+        .iter()
+        .filter(|l| l.0 == tag)
+        .map(|l| l.1.clone())
+        .collect()
+}
+
+/// diff_lines makes a unified line diff of two rule lists based on their
+/// longest common subsequence.
+///
+pub fn diff_lines(a: &[String], b: &[String]) -> Vec<(LineTag, String)> {
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut res = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            res.push((LineTag::Keep, a[i].clone()));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            res.push((LineTag::Remove, a[i].clone()));
+            i += 1;
+        } else {
+            res.push((LineTag::Add, b[j].clone()));
+            j += 1;
+        }
+    }
+    res.extend(a[i..].iter().map(|r| (LineTag::Remove, r.clone())));
+    res.extend(b[j..].iter().map(|r| (LineTag::Add, r.clone())));
+    res
+}
@@ -0,0 +1,131 @@
+use super::BlockLines;
+use aclneko::syntax::{Category, Matcher};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// AttrEntry is a condition or an effect such as `transition=` in an ACL
+/// header or rule.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct AttrEntry {
+    pub name: String,
+    pub cond: String,
+    pub value: String,
+}
+
+/// HeaderEntry is a parsed ACL header.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct HeaderEntry {
+    pub priority: u16,
+    pub operation: String,
+    pub attributes: Vec<AttrEntry>,
+    pub text: String,
+}
+
+/// RuleEntry is a parsed rule line in an ACL block.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct RuleEntry {
+    pub line: Option<usize>,
+    pub priority: u16,
+    pub action: String,
+    pub attributes: Vec<AttrEntry>,
+    pub text: String,
+}
+
+/// BlockEntry is a parsed ACL block with the line numbers in the policy
+/// source, which are None if the source is not available.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct BlockEntry {
+    pub line: Option<usize>,
+    pub header: HeaderEntry,
+    pub rules: Vec<RuleEntry>,
+}
+
+/// header_entry parses the header of an ACL block.
+///
+pub fn header_entry(m: &Matcher, b: &BlockLines) -> HeaderEntry {
+    HeaderEntry {
+        priority: b.priority,
+        operation: b.op.as_str().to_string(),
+        attributes: attr_entries(m, &b.header),
+        text: b.header.clone(),
+    }
+}
+
+/// block_entries parses ACL blocks. Line numbers are looked up in the policy
+/// source if given, with the first occurrence of a header or a rule in the
+/// header's blocks.
+///
+pub fn block_entries(blocks: &[BlockLines], source: Option<&str>) -> Vec<BlockEntry> {
+    let m = Matcher::new();
+    let index = source.map(line_index).unwrap_or_default();
+    blocks
+        .iter()
+        .map(|b| {
+            let header = normalize(&b.header);
+            BlockEntry {
+                line: index.get(&(header.clone(), None)).copied(),
+                header: header_entry(&m, b),
+                rules: b
+                    .rules
+                    .iter()
+                    .filter(|r| m.is_acl_rule(&format!(" {}", r)))
+                    .map(|r| {
+                        let mut words = r.split_whitespace();
+                        RuleEntry {
+                            line: index.get(&(header.clone(), Some(normalize(r)))).copied(),
+                            priority: words.next().and_then(|p| p.parse().ok()).unwrap_or(0),
+                            action: words.next().unwrap_or_default().to_string(),
+                            attributes: attr_entries(&m, r),
+                            text: r.clone(),
+                        }
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+fn attr_entries(m: &Matcher, line: &str) -> Vec<AttrEntry> {
+    m.parse_attr_list(line)
+        .into_iter()
+        .map(|(res, cond, value)| AttrEntry {
+            name: res.as_str().to_string(),
+            cond: cond.as_str().to_string(),
+            value,
+        })
+        .collect()
+}
+
+/// line_index maps whitespace-normalized headers, and rules under them, to
+/// their 1-based line numbers in the policy source.
+///
+fn line_index(text: &str) -> HashMap<(String, Option<String>), usize> {
+    let m = Matcher::new();
+    let mut res = HashMap::new();
+    let mut header: Option<String> = None;
+    for (i, l) in text.lines().enumerate() {
+        match m.parse_category(l) {
+            Category::Header => {
+                let h = normalize(l);
+                res.entry((h.clone(), None)).or_insert(i + 1);
+                header = Some(h);
+            }
+            Category::Rule => {
+                if let Some(h) = &header {
+                    res.entry((h.clone(), Some(normalize(l)))).or_insert(i + 1);
+                }
+            }
+            Category::Audit | Category::Blank | Category::Comment => {}
+            _ => header = None,
+        }
+    }
+    res
+}
+
+fn normalize(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
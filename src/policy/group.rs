@@ -0,0 +1,281 @@
+use super::pattern::PathPattern;
+use super::sorted_blocks;
+use aclneko::acl::Acl;
+use aclneko::syntax::{Matcher, Resource};
+use clap::ValueEnum;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+
+/// GroupKind is the kind of a group definition in the policy.
+///
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupKind {
+    /// string_group, a set of path patterns or strings
+    String,
+    /// number_group, a set of numbers or numeric ranges
+    Number,
+    /// ip_group, a set of IPv4/IPv6 addresses or ranges
+    Ip,
+}
+
+impl GroupKind {
+    pub fn list() -> Vec<GroupKind> {
+        vec![GroupKind::String, GroupKind::Number, GroupKind::Ip]
+    }
+
+    /// as_str returns the keyword of the group definition line.
+    ///
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GroupKind::String => "string_group",
+            GroupKind::Number => "number_group",
+            GroupKind::Ip => "ip_group",
+        }
+    }
+
+    /// of_attribute returns the kind of groups an attribute can reference:
+    /// ip_group for `ip`, number_group for ids, modes, device numbers and
+    /// ports, and string_group for the others.
+    ///
+    pub fn of_attribute(r: &Resource) -> GroupKind {
+        let name = r.as_str();
+        match name.rsplit('.').next().unwrap_or(name) {
+            "ip" => GroupKind::Ip,
+            "pid" | "ppid" | "uid" | "euid" | "suid" | "fsuid" | "gid" | "egid" | "sgid"
+            | "fsgid" | "ino" | "perm" | "major" | "minor" | "dev_major" | "dev_minor"
+            | "fsmagic" | "port" => GroupKind::Number,
+            _ => GroupKind::String,
+        }
+    }
+
+    /// groups returns group definitions of the kind in the Acl.
+    ///
+    pub fn groups<'a>(&self, acl: &'a Acl) -> &'a HashMap<String, Vec<String>> {
+        match self {
+            GroupKind::String => &acl.data.string_group,
+            GroupKind::Number => &acl.data.number_group,
+            GroupKind::Ip => &acl.data.ip_group,
+        }
+    }
+}
+
+/// GroupReference is an ACL block referencing a group in its header or in
+/// some of its rules.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct GroupReference {
+    pub header: String,
+    pub in_header: bool,
+    pub rules: Vec<String>,
+}
+
+/// GroupReport is a group with its members and references. A group which is
+/// referenced but never defined has no kind, and `referenced_as` is the kind
+/// inferred from the attribute first referencing the group.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct GroupReport {
+    pub name: String,
+    pub kind: Option<GroupKind>,
+    pub referenced_as: Option<GroupKind>,
+    pub members: Vec<String>,
+    pub references: Vec<GroupReference>,
+}
+
+impl GroupReport {
+    /// kind_or_referenced returns the kind of the definition, or the kind
+    /// inferred from references for an undefined group.
+    ///
+    pub fn kind_or_referenced(&self) -> Option<GroupKind> {
+        self.kind.or(self.referenced_as)
+    }
+
+    pub fn is_undefined(&self) -> bool {
+        self.kind.is_none()
+    }
+
+    pub fn is_unused(&self) -> bool {
+        self.references.is_empty()
+    }
+}
+
+/// analyze_groups returns every group defined or referenced in the Acl,
+/// ordered by kind and name. Undefined groups come last.
+///
+pub fn analyze_groups(acl: &Acl) -> Vec<GroupReport> {
+    let m = Matcher::new();
+    let mut references: BTreeMap<String, Vec<GroupReference>> = BTreeMap::new();
+    let mut kinds: BTreeMap<String, GroupKind> = BTreeMap::new();
+    for b in sorted_blocks(acl) {
+        let mut refs: BTreeMap<String, GroupReference> = BTreeMap::new();
+        for (name, kind) in referenced_groups(&m, &b.header) {
+            kinds.entry(name.clone()).or_insert(kind);
+            refs.entry(name).or_insert(GroupReference {
+                header: b.header.clone(),
+                in_header: true,
+                rules: vec![],
+            });
+        }
+        for r in &b.rules {
+            for (name, kind) in referenced_groups(&m, r) {
+                kinds.entry(name.clone()).or_insert(kind);
+                let e = refs.entry(name).or_insert(GroupReference {
+                    header: b.header.clone(),
+                    in_header: false,
+                    rules: vec![],
+                });
+                if !e.rules.contains(r) {
+                    e.rules.push(r.clone());
+                }
+            }
+        }
+        for (name, r) in refs {
+            references.entry(name).or_default().push(r);
+        }
+    }
+
+    let mut res = vec![];
+    for kind in GroupKind::list() {
+        let mut names: Vec<&String> = kind.groups(acl).keys().collect();
+        names.sort();
+        for name in names {
+            res.push(GroupReport {
+                name: name.clone(),
+                kind: Some(kind),
+                referenced_as: kinds.get(name).copied(),
+                members: kind.groups(acl)[name].clone(),
+                references: references.remove(name).unwrap_or_default(),
+            });
+        }
+    }
+    for (name, refs) in references {
+        res.push(GroupReport {
+            referenced_as: kinds.get(&name).copied(),
+            name,
+            kind: None,
+            members: vec![],
+            references: refs,
+        });
+    }
+    res
+}
+
+/// referenced_groups returns group names referenced with `@` in attributes
+/// of an ACL header or rule line, with the kind of group each attribute takes.
+///
+pub fn referenced_groups(m: &Matcher, line: &str) -> Vec<(String, GroupKind)> {
+    m.parse_attr_list(line)
+        .into_iter()
+        .filter_map(|a| {
+            a.2.strip_prefix('@')
+                .map(|g| (g.to_string(), GroupKind::of_attribute(&a.0)))
+        })
+        .collect()
+}
+
+/// validate_name checks the syntax of a group name.
+///
+pub fn validate_name(name: &str) -> Result<(), String> {
+    let key = Regex::new(r"^[[:alpha:]][-_[:alnum:]]*$").unwrap();
+    match key.is_match(name) {
+        true => Ok(()),
+        false => Err(format!("invalid group name: {}", name)),
+    }
+}
+
+/// validate_member checks the syntax of a group member for the kind: a
+/// caitsith path pattern for string_group, a number or a numeric range for
+/// number_group, and an IPv4/IPv6 address or range for ip_group.
+///
+pub fn validate_member(kind: GroupKind, member: &str) -> Result<(), String> {
+    let valid = match kind {
+        GroupKind::String => is_string_pattern(member),
+        GroupKind::Number => match member.split_once('-') {
+            Some((lo, hi)) => {
+                matches!((parse_number(lo), parse_number(hi)), (Some(l), Some(h)) if l <= h)
+            }
+            None => parse_number(member).is_some(),
+        },
+        GroupKind::Ip => match member.split_once('-') {
+            Some((lo, hi)) => match (lo.parse::<IpAddr>(), hi.parse::<IpAddr>()) {
+                (Ok(l @ IpAddr::V4(_)), Ok(h @ IpAddr::V4(_))) => l <= h,
+                (Ok(l @ IpAddr::V6(_)), Ok(h @ IpAddr::V6(_))) => l <= h,
+                _ => false,
+            },
+            None => member.parse::<IpAddr>().is_ok(),
+        },
+    };
+    match valid {
+        true => Ok(()),
+        false => Err(format!("invalid member for {}: {}", kind.as_str(), member)),
+    }
+}
+
+/// parse_number parses a number in decimal, octal with a leading `0` or
+/// hexadecimal with a leading `0x`, as written in the policy.
+///
+pub fn parse_number(n: &str) -> Option<u64> {
+    match n.strip_prefix("0x") {
+        Some(h) => u64::from_str_radix(h, 16).ok(),
+        None if n.len() > 1 && n.starts_with('0') => u64::from_str_radix(&n[1..], 8).ok(),
+        None => n.parse().ok(),
+    }
+}
+
+/// is_string_pattern checks a caitsith string pattern, which consists of
+/// printable characters except whitespaces, with escapes accepted by
+/// PathPattern.
+///
+fn is_string_pattern(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_graphic()) && PathPattern::new(s).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse_number("0"), Some(0));
+        assert_eq!(parse_number("1000"), Some(1000));
+        assert_eq!(parse_number("0755"), Some(0o755));
+        assert_eq!(parse_number("0x1f"), Some(0x1f));
+        assert_eq!(parse_number("08"), None);
+        assert_eq!(parse_number("0x"), None);
+        assert_eq!(parse_number("-1"), None);
+        assert_eq!(parse_number(""), None);
+    }
+
+    #[test]
+    fn validate_members() {
+        let valid = [
+            (GroupKind::String, "/home/\\*/savoir/\\*"),
+            (GroupKind::String, "user-shell"),
+            (GroupKind::Number, "1000"),
+            (GroupKind::Number, "1000-2000"),
+            (GroupKind::Number, "0x10-0777"),
+            (GroupKind::Ip, "192.168.0.1"),
+            (GroupKind::Ip, "10.0.0.0-10.255.255.255"),
+            (GroupKind::Ip, "::1-::ff"),
+        ];
+        for (kind, m) in valid {
+            assert!(validate_member(kind, m).is_ok(), "{} {}", kind.as_str(), m);
+        }
+        let invalid = [
+            (GroupKind::String, ""),
+            (GroupKind::String, "/tmp/a b"),
+            (GroupKind::Number, "2000-1000"),
+            (GroupKind::Number, "1000-"),
+            (GroupKind::Number, "uid"),
+            (GroupKind::Ip, "192.168.0.256"),
+            (GroupKind::Ip, "10.0.0.255-10.0.0.1"),
+            (GroupKind::Ip, "10.0.0.1-::1"),
+        ];
+        for (kind, m) in invalid {
+            assert!(validate_member(kind, m).is_err(), "{} {}", kind.as_str(), m);
+        }
+    }
+}
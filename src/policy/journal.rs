@@ -0,0 +1,228 @@
+use super::responder::Answer;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// QUERY_JOURNAL is the journal of query sessions under the state directory.
+///
+pub const QUERY_JOURNAL: &str = "query-journal.log";
+
+/// JournalAction is what was done for a query: an answer, or a rule added to
+/// the ACL block of the query.
+///
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalAction {
+    Permit,
+    Deny,
+    Reevaluate,
+    Add,
+}
+
+impl JournalAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JournalAction::Permit => "permit",
+            JournalAction::Deny => "deny",
+            JournalAction::Reevaluate => "reevaluate",
+            JournalAction::Add => "add",
+        }
+    }
+}
+
+impl From<Answer> for JournalAction {
+    fn from(a: Answer) -> Self {
+        match a {
+            Answer::Permit => JournalAction::Permit,
+            Answer::Deny => JournalAction::Deny,
+            Answer::Reevaluate => JournalAction::Reevaluate,
+        }
+    }
+}
+
+/// JournalEntry is a line of the query journal. `header` and `rule` are
+/// given for JournalAction::Add, and `by` names what decided the action
+/// without the operator, such as the filter or a responder rule.
+///
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct JournalEntry {
+    pub time: String,
+    pub query_id: String,
+    pub record: String,
+    pub operation: Option<String>,
+    pub action: JournalAction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    pub operator: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub by: Option<String>,
+}
+
+/// Journal appends entries to the journal file in JSON lines.
+///
+pub struct Journal {
+    pub path: PathBuf,
+    pub operator: String,
+}
+
+impl Journal {
+    /// new creates a journal with the operator of the process, which is
+    /// `SUDO_USER`, `USER` or `LOGNAME` in this order.
+    ///
+    pub fn new(path: PathBuf) -> Self {
+        let operator = ["SUDO_USER", "USER", "LOGNAME"]
+            .iter()
+            .find_map(|k| env::var(k).ok().filter(|v| !v.is_empty()))
+            .unwrap_or(String::from("unknown"));
+        Journal { path, operator }
+    }
+
+    pub fn append(&self, entry: &JournalEntry) -> Result<(), String> {
+        let line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+        let mut fp = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        writeln!(fp, "{}", line).map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+}
+
+/// parse_journal parses the journal text. Blank lines are skipped.
+///
+pub fn parse_journal(text: &str) -> Result<Vec<JournalEntry>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| serde_json::from_str(l).map_err(|e| format!("line {}: {}", i + 1, e)))
+        .collect()
+}
+
+/// SessionSummary counts the queries and the actions of a query session.
+/// Actions are counted by what decided them, which is `operator` or the
+/// first word of `by` such as `filter`, `rules`, `timeout` and `shutdown`.
+///
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct SessionSummary {
+    pub queries: usize,
+    pub actions: BTreeMap<JournalAction, BTreeMap<String, usize>>,
+}
+
+/// summarize summarizes journal entries of a session.
+///
+pub fn summarize(entries: &[JournalEntry]) -> SessionSummary {
+    let ids: BTreeSet<&str> = entries.iter().map(|e| e.query_id.as_str()).collect();
+    let mut actions: BTreeMap<JournalAction, BTreeMap<String, usize>> = BTreeMap::new();
+    for e in entries {
+        let by = match &e.by {
+            Some(b) => b.split(':').next().unwrap_or_default().to_string(),
+            None => String::from("operator"),
+        };
+        *actions.entry(e.action).or_default().entry(by).or_default() += 1;
+    }
+    SessionSummary {
+        queries: ids.len(),
+        actions,
+    }
+}
+
+/// replay derives a patch from the rules added in the journal. Rules are
+/// grouped by their ACL headers in the order of appearance, and duplicates
+/// are written once.
+///
+pub fn replay(entries: &[JournalEntry]) -> String {
+    let mut blocks: Vec<(&str, Vec<&str>)> = vec![];
+    let mut seen: BTreeSet<(&str, &str)> = BTreeSet::new();
+    for e in entries.iter().filter(|e| e.action == JournalAction::Add) {
+        let (header, rule) = match (&e.header, &e.rule) {
+            (Some(h), Some(r)) => (h.as_str(), r.as_str()),
+            _ => continue,
+        };
+        if !seen.insert((header, rule)) {
+            continue;
+        }
+        match blocks.iter_mut().find(|(h, _)| *h == header) {
+            Some((_, rules)) => rules.push(rule),
+            None => blocks.push((header, vec![rule])),
+        }
+    }
+    let mut res = String::new();
+    for (header, rules) in blocks {
+        res += &format!("{}\n", header);
+        for r in rules {
+            res += &format!("    {}\n", r);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JOURNAL: &str = include_str!("../../fixtures/sample-journal.log");
+
+    #[test]
+    fn parse_recorded_journal() {
+        let entries = parse_journal(JOURNAL).unwrap();
+        assert_eq!(entries.len(), 11);
+        let e = &entries[1];
+        assert_eq!((e.query_id.as_str(), e.action), ("2", JournalAction::Add));
+        assert_eq!(e.operation.as_deref(), Some("write"));
+        assert_eq!(
+            e.header.as_deref(),
+            Some("1000 acl write task.uid!=@kid task.domain!=@userns")
+        );
+        assert_eq!(e.by, None);
+        assert_eq!(entries[7].by.as_deref(), Some("filter"));
+
+        // blank lines are skipped, and errors name the line
+        assert_eq!(
+            parse_journal(&format!("\n{}\n", JOURNAL)).unwrap().len(),
+            11
+        );
+        let err = parse_journal(&format!("{}{{\"time\":\n", JOURNAL)).unwrap_err();
+        assert!(err.starts_with("line 12:"), "{}", err);
+    }
+
+    #[test]
+    fn summarize_recorded_journal() {
+        let summary = summarize(&parse_journal(JOURNAL).unwrap());
+        assert_eq!(summary.queries, 10);
+        let count = |action: JournalAction, by: &str| {
+            summary
+                .actions
+                .get(&action)
+                .and_then(|a| a.get(by))
+                .copied()
+                .unwrap_or(0)
+        };
+        assert_eq!(count(JournalAction::Permit, "operator"), 2);
+        assert_eq!(count(JournalAction::Permit, "rules"), 1);
+        assert_eq!(count(JournalAction::Add, "operator"), 4);
+        assert_eq!(count(JournalAction::Reevaluate, "operator"), 1);
+        assert_eq!(count(JournalAction::Reevaluate, "timeout"), 1);
+        assert_eq!(count(JournalAction::Deny, "filter"), 1);
+        assert_eq!(count(JournalAction::Deny, "shutdown"), 1);
+        assert_eq!(count(JournalAction::Deny, "operator"), 0);
+    }
+
+    #[test]
+    fn replay_recorded_journal() {
+        let patch = replay(&parse_journal(JOURNAL).unwrap());
+        assert_eq!(
+            patch,
+            "1000 acl write task.uid!=@kid task.domain!=@userns\n\
+            \x20   10 allow path=\"/home/bob/.ssh/known_hosts\"\n\
+            \x20   10 allow path=\"/home/bob/.ssh/config\"\n\
+            30 acl inet_stream_connect\n\
+            \x20   10 allow task.exe=\"/usr/bin/git\" port=443\n"
+        );
+        assert_eq!(replay(&[]), "");
+    }
+}
@@ -0,0 +1,412 @@
+use super::pattern::PathPattern;
+use super::{sorted_blocks, BlockLines};
+use aclneko::acl::Acl;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// LEARNED_ATTRS are attributes of a request to be written in proposed
+/// rules. Other attributes such as `task.pid` differ for every request.
+///
+pub const LEARNED_ATTRS: [&str; 10] = [
+    "task.exe", "path", "old_path", "new_path", "source", "target", "ip", "port", "fstype", "exec",
+];
+
+/// PATH_ATTRS are attributes generalized into wildcards and string_groups.
+///
+pub const PATH_ATTRS: [&str; 6] = ["path", "old_path", "new_path", "source", "target", "exec"];
+
+/// NUMBER_ATTRS are attributes written without quotes.
+///
+const NUMBER_ATTRS: [&str; 1] = ["port"];
+
+/// Request is the learned attributes of a request, as `key=value` pairs.
+///
+type Request = Vec<(String, String)>;
+
+/// Learner aggregates permitted violations into a policy patch. Requests are
+/// grouped by the ACL header, and rules are prioritized before the deny rules
+/// of the block, at `priority` at most. Paths are generalized:
+///
+/// - a path matched by a string_group is replaced by the group, preferring
+///   the group whose matched member is the longest
+/// - numeric components become `\$`, and long hexadecimal components `\X`
+/// - the user directory of `/home/user/` becomes `\*`
+/// - paths with `min_siblings` or more names in a directory become `dir/\*`
+///
+pub struct Learner {
+    pub priority: u16,
+    pub min_siblings: usize,
+    observed: BTreeMap<String, BTreeSet<Request>>,
+    count: usize,
+}
+
+impl Learner {
+    pub fn new(priority: u16, min_siblings: usize) -> Self {
+        Learner {
+            priority,
+            min_siblings,
+            observed: BTreeMap::new(),
+            count: 0,
+        }
+    }
+
+    /// observe adds a request with its `key=value` fields under the header.
+    ///
+    pub fn observe(&mut self, header: &str, attrs: &BTreeMap<String, String>) {
+        let learned = LEARNED_ATTRS
+            .iter()
+            .filter_map(|k| attrs.get(*k).map(|v| (k.to_string(), v.clone())))
+            .collect();
+        self.observed
+            .entry(header.to_string())
+            .or_default()
+            .insert(learned);
+        self.count += 1;
+    }
+
+    /// count returns the number of observed requests.
+    ///
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// propose returns the proposed patch with generalized rules. Groups are
+    /// looked up in the Acl.
+    ///
+    pub fn propose(&self, acl: &Acl) -> String {
+        let mut res = String::new();
+        let mut headers: Vec<(&String, &BTreeSet<Request>)> = self.observed.iter().collect();
+        headers.sort_by_key(|(h, _)| {
+            let priority: u16 = h
+                .split_whitespace()
+                .next()
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(0);
+            (priority, h.to_string())
+        });
+        let blocks = sorted_blocks(acl);
+        for (header, requests) in headers {
+            let priority = blocks
+                .iter()
+                .find(|b| &b.header == header)
+                .map(|b| allow_priority(b, self.priority))
+                .unwrap_or(self.priority);
+            let generalized: Vec<Request> = requests
+                .iter()
+                .map(|r| {
+                    r.iter()
+                        .map(|(k, v)| match PATH_ATTRS.contains(&k.as_str()) {
+                            true => (k.clone(), generalize_path(acl, v)),
+                            false => (k.clone(), v.clone()),
+                        })
+                        .collect()
+                })
+                .collect();
+            let rules: BTreeSet<String> = collapse_siblings(generalized, self.min_siblings)
+                .iter()
+                .map(|r| format!("{} allow{}", priority, format_attrs(r)))
+                .collect();
+            res += &format!("{}\n", header);
+            for r in rules {
+                res += &format!("    {}\n", r);
+            }
+        }
+        res
+    }
+
+    /// preceding_denies returns deny rules at priority 0 with the header of
+    /// their block, for observed headers in the Acl. No priority precedes
+    /// such a rule, so the proposed rules never take effect.
+    ///
+    pub fn preceding_denies(&self, acl: &Acl) -> Vec<(String, String)> {
+        sorted_blocks(acl)
+            .iter()
+            .filter(|b| self.observed.contains_key(&b.header))
+            .filter_map(|b| match lowest_deny(b) {
+                Some((0, r)) => Some((b.header.clone(), r.to_string())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// format_attrs formats attributes of a rule, each preceded by a space.
+/// Groups, numbers and IP addresses are written without quotes.
+///
+pub fn format_attrs(attrs: &[(String, String)]) -> String {
+    let mut res = String::new();
+    for (k, v) in attrs {
+        if v.starts_with('@') || NUMBER_ATTRS.contains(&k.as_str()) || k == "ip" {
+            res += &format!(" {}={}", k, v);
+        } else {
+            res += &format!(" {}=\"{}\"", k, v);
+        }
+    }
+    res
+}
+
+/// allow_priority returns the priority to evaluate an allow rule before the
+/// deny rules of the block, which is `max` at most. It is 0 for a block with
+/// a deny rule at priority 0, which no priority precedes.
+///
+pub fn allow_priority(block: &BlockLines, max: u16) -> u16 {
+    lowest_deny(block)
+        .map(|(p, _)| p.saturating_sub(1).min(max))
+        .unwrap_or(max)
+}
+
+/// lowest_deny returns the deny rule with the lowest priority in the block.
+///
+fn lowest_deny(block: &BlockLines) -> Option<(u16, &String)> {
+    block
+        .rules
+        .iter()
+        .filter_map(|r| {
+            let mut words = r.split_whitespace();
+            let priority: u16 = words.next()?.parse().ok()?;
+            (words.next()? == "deny").then_some((priority, r))
+        })
+        .min_by_key(|(p, _)| *p)
+}
+
+/// header_for returns the header of the ACL block for the operation with
+/// the priority in the audit fields, looked up in the Acls in order, or a new
+/// header if the block is not found.
+///
+pub fn header_for(acls: &[&Acl], operation: &str, attrs: &BTreeMap<String, String>) -> String {
+    let priority: u16 = attrs
+        .get("priority")
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(0);
+    acls.iter()
+        .flat_map(|acl| sorted_blocks(acl))
+        .find(|b| b.priority == priority && b.op.as_str() == operation)
+        .map(|b| b.header)
+        .unwrap_or(format!("{} acl {}", priority, operation))
+}
+
+/// matching_group returns the name of the string_group with a member
+/// matching the path, preferring the group whose matched member is the
+/// longest.
+///
+pub fn matching_group<'a>(acl: &'a Acl, path: &str) -> Option<&'a String> {
+    let mut best: Option<(usize, &String)> = None;
+    for (name, members) in &acl.data.string_group {
+        for m in members {
+            let better = best.is_none_or(|(len, n)| m.len() > len || (m.len() == len && name < n));
+            if better && PathPattern::new(m).is_ok_and(|p| p.is_match(path)) {
+                best = Some((m.len(), name));
+            }
+        }
+    }
+    best.map(|(_, name)| name)
+}
+
+/// generalize_path replaces a path with a matching string_group, or with
+/// wildcards for numeric, hexadecimal and user directory components.
+///
+fn generalize_path(acl: &Acl, path: &str) -> String {
+    if let Some(name) = matching_group(acl, path) {
+        return format!("@{}", name);
+    }
+
+    let components: Vec<&str> = path.split('/').collect();
+    components
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let is_hex = c.len() >= 8
+                && c.chars().all(|c| c.is_ascii_hexdigit())
+                && c.chars().any(|c| c.is_ascii_digit());
+            if i == 2 && components[1] == "home" && components.len() > 3 {
+                "\\*"
+            } else if !c.is_empty() && c.chars().all(|c| c.is_ascii_digit()) {
+                "\\$"
+            } else if is_hex {
+                "\\X"
+            } else {
+                c
+            }
+        })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+/// collapse_siblings replaces paths of requests, which are the same except
+/// the name of `path` in a directory, with `dir/\*` if there are at least
+/// `min` names.
+///
+fn collapse_siblings(requests: Vec<Request>, min: usize) -> Vec<Request> {
+    let mut siblings: BTreeMap<Request, BTreeSet<String>> = BTreeMap::new();
+    let mut res = vec![];
+    for r in requests {
+        let dir = r
+            .iter()
+            .find(|(k, v)| k == "path" && !v.starts_with('@'))
+            .and_then(|(_, v)| v.rsplit_once('/'))
+            .map(|(d, n)| (d.to_string(), n.to_string()));
+        match dir {
+            Some((d, n)) => {
+                let key = r
+                    .iter()
+                    .map(|(k, v)| match k == "path" {
+                        true => (k.clone(), d.clone()),
+                        false => (k.clone(), v.clone()),
+                    })
+                    .collect();
+                siblings.entry(key).or_default().insert(n);
+            }
+            None => res.push(r),
+        }
+    }
+    for (key, names) in siblings {
+        let expand = |name: &str| {
+            key.iter()
+                .map(|(k, v)| match k == "path" {
+                    true => (k.clone(), format!("{}/{}", v, name)),
+                    false => (k.clone(), v.clone()),
+                })
+                .collect()
+        };
+        match names.len() >= min {
+            true => res.push(expand("\\*")),
+            false => res.extend(names.iter().map(|n| expand(n))),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const GROUPS: &str = "string_group ETC /etc/\\*\n\
+        string_group ETC_SSH /etc/ssh/\\*\n\
+        100 acl read\n    50 deny path=\"/root/\\*\"\n\
+        100 acl write\n    0 deny\n";
+
+    fn acl() -> Acl {
+        Acl::from_str(GROUPS).unwrap()
+    }
+
+    fn request(attrs: &[(&str, &str)]) -> Request {
+        attrs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn block(rules: &[&str]) -> BlockLines {
+        BlockLines {
+            priority: 100,
+            op: aclneko::syntax::Op::from("read"),
+            header: String::from("100 acl read"),
+            rules: rules.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn generalize_with_groups() {
+        // the group of the longest matched member is preferred
+        assert_eq!(generalize_path(&acl(), "/etc/ssh/sshd_config"), "@ETC_SSH");
+        assert_eq!(generalize_path(&acl(), "/etc/passwd"), "@ETC");
+    }
+
+    #[test]
+    fn generalize_components() {
+        let acl = Acl::new();
+        assert_eq!(
+            generalize_path(&acl, "/proc/1234/status"),
+            "/proc/\\$/status"
+        );
+        assert_eq!(
+            generalize_path(&acl, "/tmp/cache/0123abcd9f"),
+            "/tmp/cache/\\X"
+        );
+        // short or digitless hexadecimal names are kept
+        assert_eq!(generalize_path(&acl, "/tmp/0123abc"), "/tmp/0123abc");
+        assert_eq!(
+            generalize_path(&acl, "/tmp/deadbeefcafe"),
+            "/tmp/deadbeefcafe"
+        );
+        assert_eq!(
+            generalize_path(&acl, "/home/alice/.bashrc"),
+            "/home/\\*/.bashrc"
+        );
+        assert_eq!(generalize_path(&acl, "/home/alice"), "/home/alice");
+        assert_eq!(generalize_path(&acl, "/usr/bin/curl"), "/usr/bin/curl");
+    }
+
+    #[test]
+    fn collapse_sibling_paths() {
+        let cat = |p: &str| request(&[("task.exe", "/bin/cat"), ("path", p)]);
+        let requests = vec![
+            cat("/var/log/a.log"),
+            cat("/var/log/b.log"),
+            cat("/var/log/c.log"),
+            cat("/var/tmp/a"),
+            cat("@ETC"),
+            request(&[("task.exe", "/bin/less"), ("path", "/var/log/a.log")]),
+        ];
+        let mut res = collapse_siblings(requests, 3);
+        res.sort();
+        assert_eq!(
+            res,
+            vec![
+                cat("/var/log/\\*"),
+                cat("/var/tmp/a"),
+                cat("@ETC"),
+                request(&[("task.exe", "/bin/less"), ("path", "/var/log/a.log")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn allow_priorities() {
+        assert_eq!(allow_priority(&block(&[]), 10), 10);
+        assert_eq!(
+            allow_priority(&block(&["100 allow", "5 deny path=\"/tmp/\\*\""]), 10),
+            4
+        );
+        assert_eq!(allow_priority(&block(&["50 deny", "20 deny"]), 10), 10);
+        assert_eq!(allow_priority(&block(&["1 deny"]), 10), 0);
+        assert_eq!(allow_priority(&block(&["0 deny"]), 10), 0);
+    }
+
+    #[test]
+    fn propose_generalized_rules() {
+        let mut learner = Learner::new(10, 3);
+        for p in [
+            "/var/log/a.log",
+            "/var/log/b.log",
+            "/var/log/c.log",
+            "/etc/ssh/ssh_config",
+        ] {
+            let attrs = BTreeMap::from([
+                ("task.exe".to_string(), "/bin/cat".to_string()),
+                ("path".to_string(), p.to_string()),
+                ("task.pid".to_string(), "4242".to_string()),
+            ]);
+            learner.observe("100 acl read", &attrs);
+        }
+        let attrs = BTreeMap::from([("path".to_string(), "/home/alice/1234".to_string())]);
+        learner.observe("100 acl write", &attrs);
+        learner.observe("200 acl write", &attrs);
+
+        assert_eq!(learner.count(), 6);
+        assert_eq!(
+            learner.propose(&acl()),
+            "100 acl read\n\
+            \x20   10 allow task.exe=\"/bin/cat\" path=\"/var/log/\\*\"\n\
+            \x20   10 allow task.exe=\"/bin/cat\" path=@ETC_SSH\n\
+            100 acl write\n\
+            \x20   0 allow path=\"/home/\\*/\\$\"\n\
+            200 acl write\n\
+            \x20   10 allow path=\"/home/\\*/\\$\"\n"
+        );
+        assert_eq!(
+            learner.preceding_denies(&acl()),
+            vec![("100 acl write".to_string(), "0 deny".to_string())]
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use aclneko::syntax::{Category, Cond, Matcher, Resource, Verb};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Severity is the level of a lint finding.
+///
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// Finding is a problem found in a policy text at the line (1-origin).
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct Finding {
+    pub line: usize,
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+}
+
+struct RuleLine {
+    line: usize,
+    priority: u16,
+    verb: Verb,
+    unconditional: bool,
+    text: String,
+}
+
+struct BlockLine {
+    line: usize,
+    header: String,
+    audit: Option<(usize, u16)>,
+    rules: Vec<RuleLine>,
+}
+
+/// lint_policy analyzes a policy text and returns findings ordered by line.
+///
+/// Acl does not keep line numbers, so the text is parsed line by line with
+/// Matcher. The following problems are reported:
+///
+/// - `syntax`: a line which is not a valid policy line
+/// - `shadowed-rule`: a rule evaluated after an unconditional allow/deny in
+///   the same ACL block, which never takes effect
+/// - `duplicate-rule`: a rule which appears twice in the same ACL block
+/// - `duplicate-header`: an ACL header defined twice, whose rules are merged
+/// - `undefined-group`: a reference to a group which is not defined
+/// - `empty-acl`: an ACL block without rules
+/// - `audit-quota`: an audit index without `quota audit[n]`, checked only
+///   for a policy having quota lines
+///
+pub fn lint_policy(text: &str) -> Vec<Finding> {
+    let m = Matcher::new();
+    let mut res = vec![];
+    let mut blocks: Vec<BlockLine> = vec![];
+    let mut groups: BTreeSet<String> = BTreeSet::new();
+    let mut references: Vec<(usize, String)> = vec![];
+    let mut audit_quota: BTreeSet<u16> = BTreeSet::new();
+    let mut has_quota = false;
+
+    for (i, line) in text.lines().enumerate() {
+        let n = i + 1;
+        match m.parse_category(line) {
+            Category::Quota => {
+                has_quota = true;
+                if let Some(idx) = line
+                    .strip_prefix("quota audit[")
+                    .and_then(|l| l.split(']').next())
+                    .and_then(|l| l.parse().ok())
+                {
+                    audit_quota.insert(idx);
+                }
+            }
+            Category::StringGroupDef => {
+                groups.insert(m.parse_string_group(line).0);
+            }
+            Category::NumberGroupDef => {
+                groups.insert(m.parse_number_group(line).0);
+            }
+            Category::IpGroupDef => {
+                groups.insert(m.parse_ip_group(line).0);
+            }
+            Category::Header => {
+                let (_, _, attr) = m.parse_acl_header(line);
+                references.extend(group_references(n, &attr));
+                blocks.push(BlockLine {
+                    line: n,
+                    header: line.trim().to_string(),
+                    audit: None,
+                    rules: vec![],
+                });
+            }
+            Category::Audit => match blocks.last_mut() {
+                Some(b) => b.audit = Some((n, m.parse_acl_audit_sequence(line))),
+                None => res.push(orphan(n)),
+            },
+            Category::Rule => {
+                let (priority, verb, attr) = m.parse_acl_rule(line);
+                references.extend(group_references(n, &attr));
+                match blocks.last_mut() {
+                    Some(b) => b.rules.push(RuleLine {
+                        line: n,
+                        priority,
+                        verb,
+                        unconditional: attr.is_empty(),
+                        text: line.split_whitespace().collect::<Vec<&str>>().join(" "),
+                    }),
+                    None => res.push(orphan(n)),
+                }
+            }
+            Category::Error => res.push(Finding {
+                line: n,
+                severity: Severity::Error,
+                code: "syntax",
+                message: format!("invalid policy line: {}", line.trim()),
+            }),
+            _ => {}
+        }
+    }
+
+    let mut headers: HashMap<&str, usize> = HashMap::new();
+    for b in &blocks {
+        match headers.get(b.header.as_str()) {
+            Some(first) => res.push(Finding {
+                line: b.line,
+                severity: Severity::Info,
+                code: "duplicate-header",
+                message: format!(
+                    "ACL header is also defined at line {}; rules are merged",
+                    first
+                ),
+            }),
+            None => {
+                headers.insert(&b.header, b.line);
+            }
+        }
+        lint_block(b, has_quota, &audit_quota, &mut res);
+    }
+
+    for (n, name) in references {
+        if !groups.contains(&name) {
+            res.push(Finding {
+                line: n,
+                severity: Severity::Error,
+                code: "undefined-group",
+                message: format!("group @{} is not defined", name),
+            });
+        }
+    }
+
+    res.sort_by_key(|f| f.line);
+    res
+}
+
+fn lint_block(b: &BlockLine, has_quota: bool, audit_quota: &BTreeSet<u16>, res: &mut Vec<Finding>) {
+    if b.rules.is_empty() {
+        res.push(Finding {
+            line: b.line,
+            severity: Severity::Warning,
+            code: "empty-acl",
+            message: String::from("ACL block has no rules"),
+        });
+    }
+
+    // audit 0 disables audit logs for the block
+    if let Some((n, idx)) = b.audit {
+        if has_quota && idx != 0 && !audit_quota.contains(&idx) {
+            res.push(Finding {
+                line: n,
+                severity: Severity::Warning,
+                code: "audit-quota",
+                message: format!("audit {} has no quota audit[{}] line", idx, idx),
+            });
+        }
+    }
+
+    // The first unconditional rule in the priority order decides every
+    // request reaching the block, and rules after it, having higher priority
+    // values or the same value defined later, are never evaluated.
+    let mut ordered: Vec<&RuleLine> = b.rules.iter().collect();
+    ordered.sort_by_key(|r| (r.priority, r.line));
+    if let Some(i) = ordered.iter().position(|r| r.unconditional) {
+        let u = ordered[i];
+        for r in &ordered[i + 1..] {
+            res.push(Finding {
+                line: r.line,
+                severity: Severity::Warning,
+                code: "shadowed-rule",
+                message: format!(
+                    "rule is shadowed by `{} {}` at line {}",
+                    u.priority,
+                    u.verb.as_str(),
+                    u.line
+                ),
+            });
+        }
+    }
+
+    let mut seen: BTreeMap<&str, usize> = BTreeMap::new();
+    for r in &b.rules {
+        match seen.get(r.text.as_str()) {
+            Some(first) => res.push(Finding {
+                line: r.line,
+                severity: Severity::Warning,
+                code: "duplicate-rule",
+                message: format!("rule is a duplicate of line {}", first),
+            }),
+            None => {
+                seen.insert(&r.text, r.line);
+            }
+        }
+    }
+}
+
+fn group_references(line: usize, attr: &[(Resource, Cond, String)]) -> Vec<(usize, String)> {
+    attr.iter()
+        .filter_map(|a| a.2.strip_prefix('@'))
+        .map(|g| (line, g.to_string()))
+        .collect()
+}
+
+fn orphan(line: usize) -> Finding {
+    Finding {
+        line,
+        severity: Severity::Error,
+        code: "syntax",
+        message: String::from("rule line without ACL header"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINT: &str = include_str!("../../fixtures/sample-lint.acl");
+
+    fn found(text: &str, code: &str) -> Vec<usize> {
+        lint_policy(text)
+            .into_iter()
+            .filter(|f| f.code == code)
+            .map(|f| f.line)
+            .collect()
+    }
+
+    #[test]
+    fn syntax() {
+        assert_eq!(found(LINT, "syntax"), Vec::<usize>::new());
+        let text = "    0 deny\n100 acl read path=\"/etc/shadow\"\n    0 deny\nunknown line\n";
+        assert_eq!(found(text, "syntax"), [1, 4]);
+    }
+
+    #[test]
+    fn shadowed_rule() {
+        assert_eq!(found(LINT, "shadowed-rule"), [18]);
+        let text = "100 acl read path=\"/etc/shadow\"\n    \
+            10 allow task.uid=0\n    10 deny\n    10 allow task.uid=1\n    0 allow task.uid=2\n";
+        assert_eq!(found(text, "shadowed-rule"), [4]);
+    }
+
+    #[test]
+    fn duplicate_rule() {
+        assert_eq!(found(LINT, "duplicate-rule"), [13]);
+    }
+
+    #[test]
+    fn duplicate_header() {
+        assert_eq!(found(LINT, "duplicate-header"), [23]);
+    }
+
+    #[test]
+    fn undefined_group() {
+        assert_eq!(found(LINT, "undefined-group"), [15]);
+    }
+
+    #[test]
+    fn empty_acl() {
+        assert_eq!(found(LINT, "empty-acl"), [20]);
+    }
+
+    #[test]
+    fn audit_quota() {
+        assert_eq!(found(LINT, "audit-quota"), [16]);
+        // checked only for a policy having quota lines
+        let text = "100 acl read path=\"/etc/shadow\"\n    audit 3\n    0 deny\n";
+        assert_eq!(found(text, "audit-quota"), Vec::<usize>::new());
+    }
+}
@@ -0,0 +1,400 @@
+use regex::Regex;
+
+/// PathPattern is a compiled caitsith string pattern such as
+/// `/home/\*/\(\*\)/\*` or `/etc/\*\-passwd`.
+///
+/// The pattern is translated into a regex:
+///
+/// - `\*` zero or more characters except `/`
+/// - `\@` zero or more characters except `/` and `.`
+/// - `\?` a character except `/`
+/// - `\$` and `\+` one or more and one decimal digit
+/// - `\X` and `\x` one or more and one hexadecimal digit
+/// - `\A` and `\a` one or more and one alphabet
+/// - `\{dir\}/` one or more and `\(dir\)/` zero or more directories
+/// - `\\` a backslash and `\ooo` a character in octal
+///
+/// `A\-B` matches a path component which matches A but not B. Subtraction is
+/// evaluated for the component matched by the regex, so it is not retried
+/// for other ways of matching the component.
+///
+/// Each wildcard, directory group, subtracted component and run of literal
+/// characters is a token captured by its own group, so that the text
+/// consumed by every token can be explained.
+///
+#[derive(Clone, Debug)]
+pub struct PathPattern {
+    regex: Regex,
+    tokens: Vec<Token>,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    source: String,
+    regex: String,
+    literal: bool,
+    excludes: Vec<Regex>,
+}
+
+/// Explanation is the text consumed by each token of a pattern. For a string
+/// which does not match, `parts` is the longest prefix of tokens which
+/// matched, `failed` is the token which did not match and `rest` is the text
+/// left for the token, or left after the whole pattern if `failed` is None.
+/// If the whole string matched but a subtraction excluded a component,
+/// `excluded` is set with the subtracted token.
+///
+#[derive(Clone, Debug)]
+pub struct Explanation {
+    pub matched: bool,
+    pub parts: Vec<(String, String)>,
+    pub failed: Option<String>,
+    pub excluded: bool,
+    pub rest: String,
+}
+
+impl PathPattern {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid pattern: {}", pattern);
+        let mut tokens: Vec<Token> = vec![];
+        let mut closes_group = false;
+        for (i, component) in split_components(pattern).iter().enumerate() {
+            // the separator after `\}` or `\)` is a part of the directory group
+            if i != 0 && !closes_group {
+                tokens.push(Token::literal("/", regex::escape("/")));
+            }
+            let last = component.last().map(|c| c.as_str()).unwrap_or("");
+            closes_group = last.ends_with("\\}") || last.ends_with("\\)");
+
+            if component.len() > 1 || closes_group {
+                let mut parts = component.iter().map(|c| translate(c).ok_or_else(invalid));
+                let head = parts.next().unwrap_or(Ok(String::new()))?;
+                let excludes = parts
+                    .map(|n| n.and_then(|n| Regex::new(&format!("^{}$", n)).map_err(|_| invalid())))
+                    .collect::<Result<_, _>>()?;
+                tokens.push(Token {
+                    source: component.join("\\-") + if closes_group { "/" } else { "" },
+                    regex: head,
+                    literal: false,
+                    excludes,
+                });
+                continue;
+            }
+            for t in split_tokens(last) {
+                let re = translate(&t).ok_or_else(invalid)?;
+                match is_wildcard(&t) {
+                    true => tokens.push(Token {
+                        source: t,
+                        regex: re,
+                        literal: false,
+                        excludes: vec![],
+                    }),
+                    false => tokens.push(Token::literal(&t, re)),
+                }
+            }
+        }
+
+        // adjacent literals and separators are explained as a single part
+        let mut merged: Vec<Token> = vec![];
+        for t in tokens {
+            match merged.last_mut() {
+                Some(l) if l.literal && t.literal => {
+                    l.source += &t.source;
+                    l.regex += &t.regex;
+                }
+                _ => merged.push(t),
+            }
+        }
+        Ok(PathPattern {
+            regex: compile(&merged, true).ok_or_else(invalid)?,
+            tokens: merged,
+        })
+    }
+
+    /// is_match tests whether the string matches the pattern.
+    ///
+    pub fn is_match(&self, s: &str) -> bool {
+        let cap = match self.regex.captures(s) {
+            Some(c) => c,
+            None => return false,
+        };
+        self.tokens
+            .iter()
+            .enumerate()
+            .all(|(i, t)| !t.excludes.iter().any(|e| e.is_match(&cap[i + 1])))
+    }
+
+    /// explain matches the string with the pattern and returns the text
+    /// consumed by each token.
+    ///
+    pub fn explain(&self, s: &str) -> Explanation {
+        if let Some(cap) = self.regex.captures(s) {
+            let parts: Vec<(String, String)> = self
+                .tokens
+                .iter()
+                .enumerate()
+                .map(|(i, t)| (t.source.clone(), cap[i + 1].to_string()))
+                .collect();
+            let failed = self
+                .tokens
+                .iter()
+                .zip(&parts)
+                .find(|(t, p)| t.excludes.iter().any(|e| e.is_match(&p.1)))
+                .map(|(t, _)| t.source.clone());
+            return Explanation {
+                matched: failed.is_none(),
+                parts,
+                excluded: failed.is_some(),
+                failed,
+                rest: String::new(),
+            };
+        }
+
+        for n in (0..=self.tokens.len()).rev() {
+            let cap = match compile(&self.tokens[..n], false).and_then(|r| r.captures(s)) {
+                Some(c) => c,
+                None => continue,
+            };
+            return Explanation {
+                matched: false,
+                parts: (0..n)
+                    .map(|i| (self.tokens[i].source.clone(), cap[i + 1].to_string()))
+                    .collect(),
+                failed: self.tokens.get(n).map(|t| t.source.clone()),
+                excluded: false,
+                rest: s[cap[0].len()..].to_string(),
+            };
+        }
+        Explanation {
+            matched: false,
+            parts: vec![],
+            failed: None,
+            excluded: false,
+            rest: s.to_string(),
+        }
+    }
+}
+
+impl Token {
+    fn literal(source: &str, regex: String) -> Self {
+        Token {
+            source: source.to_string(),
+            regex,
+            literal: true,
+            excludes: vec![],
+        }
+    }
+}
+
+/// compile joins the regexes of tokens, each of which is captured. An
+/// unanchored regex matches a prefix of the string.
+///
+fn compile(tokens: &[Token], anchored: bool) -> Option<Regex> {
+    let mut re = String::from("^");
+    for t in tokens {
+        re += &format!("({})", t.regex);
+    }
+    if anchored {
+        re.push('$');
+    }
+    Regex::new(&re).ok()
+}
+
+/// split_components splits a pattern into path components, each of which is
+/// split again with the subtraction operator `\-`. Escape sequences are kept
+/// as they are.
+///
+fn split_components(pattern: &str) -> Vec<Vec<String>> {
+    let mut res = vec![vec![String::new()]];
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '/' => res.push(vec![String::new()]),
+            '\\' => match chars.next() {
+                Some('-') => res.last_mut().unwrap().push(String::new()),
+                Some(n) => {
+                    let s = res.last_mut().unwrap().last_mut().unwrap();
+                    s.push('\\');
+                    s.push(n);
+                }
+                None => res.last_mut().unwrap().last_mut().unwrap().push('\\'),
+            },
+            _ => res.last_mut().unwrap().last_mut().unwrap().push(c),
+        }
+    }
+    res
+}
+
+/// split_tokens splits a path component into wildcards and runs of literal
+/// characters, including escaped backslashes and octal characters.
+///
+fn split_tokens(component: &str) -> Vec<String> {
+    let mut res: Vec<String> = vec![];
+    let mut chars = component.chars();
+    while let Some(c) = chars.next() {
+        let t = match c {
+            '\\' => match chars.next() {
+                Some(d @ '0'..='3') => {
+                    format!("\\{}{}", d, chars.by_ref().take(2).collect::<String>())
+                }
+                Some(n) => format!("\\{}", n),
+                None => String::from("\\"),
+            },
+            c => c.to_string(),
+        };
+        match res.last_mut() {
+            Some(l) if !is_wildcard(l) && !is_wildcard(&t) => *l += &t,
+            _ => res.push(t),
+        }
+    }
+    res
+}
+
+fn is_wildcard(token: &str) -> bool {
+    match token.strip_prefix('\\') {
+        Some(t) => !t.starts_with('\\') && !t.starts_with(|c: char| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+/// translate translates a part of a path component into a regex. A directory
+/// group `\{` `\}` or `\(` `\)` spans the separator after it, which is
+/// emitted with the closing token.
+///
+fn translate(part: &str) -> Option<String> {
+    let mut res = String::new();
+    let mut chars = part.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res += &regex::escape(&c.to_string());
+            continue;
+        }
+        match chars.next()? {
+            '*' => res += "[^/]*",
+            '@' => res += "[^/.]*",
+            '?' => res += "[^/]",
+            '$' => res += "[0-9]+",
+            '+' => res += "[0-9]",
+            'X' => res += "[0-9a-fA-F]+",
+            'x' => res += "[0-9a-fA-F]",
+            'A' => res += "[a-zA-Z]+",
+            'a' => res += "[a-zA-Z]",
+            '\\' => res += r"\\",
+            '{' | '(' => res += "(?:",
+            '}' => res += "/)+",
+            ')' => res += "/)*",
+            d @ '0'..='3' => {
+                let oct: String = [Some(d), chars.next(), chars.next()]
+                    .into_iter()
+                    .collect::<Option<String>>()?;
+                let ch = char::from(u8::from_str_radix(&oct, 8).ok()?);
+                res += &regex::escape(&ch.to_string());
+            }
+            _ => return None,
+        }
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, s: &str) -> bool {
+        PathPattern::new(pattern).unwrap().is_match(s)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches(r"/home/\*/.bashrc", "/home/user/.bashrc"));
+        assert!(!matches(r"/home/\*/.bashrc", "/home/a/b/.bashrc"));
+        assert!(matches(r"/tmp/\@.log", "/tmp/x.log"));
+        assert!(!matches(r"/tmp/\@.log", "/tmp/x.y.log"));
+        assert!(matches(r"/proc/\$/status", "/proc/123/status"));
+        assert!(!matches(r"/proc/\$/status", "/proc/self/status"));
+        assert!(matches(r"/dev/tty\+", "/dev/tty1"));
+        assert!(!matches(r"/dev/tty\+", "/dev/tty12"));
+        assert!(matches(r"/tmp/\X", "/tmp/dead00BEEF"));
+        assert!(matches(r"/dev/sd\a", "/dev/sda"));
+        assert!(!matches(r"/dev/sd\a", "/dev/sd1"));
+    }
+
+    #[test]
+    fn directory_groups() {
+        let one_or_more = r"/usr/\{\*\}/lib";
+        assert!(matches(one_or_more, "/usr/local/lib"));
+        assert!(matches(one_or_more, "/usr/a/b/lib"));
+        assert!(!matches(one_or_more, "/usr/lib"));
+
+        let zero_or_more = r"/usr/\(\*\)/lib";
+        assert!(matches(zero_or_more, "/usr/lib"));
+        assert!(matches(zero_or_more, "/usr/a/b/lib"));
+        assert!(!matches(zero_or_more, "/var/lib"));
+    }
+
+    #[test]
+    fn subtraction() {
+        let pattern = r"/etc/\*\-shadow\-gshadow";
+        assert!(matches(pattern, "/etc/passwd"));
+        assert!(!matches(pattern, "/etc/shadow"));
+        assert!(!matches(pattern, "/etc/gshadow"));
+        assert!(!matches(pattern, "/etc/ssh/sshd_config"));
+
+        let e = PathPattern::new(pattern).unwrap().explain("/etc/shadow");
+        assert!(!e.matched);
+        assert!(e.excluded);
+        assert_eq!(e.failed.as_deref(), Some(r"\*\-shadow\-gshadow"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches(r"/tmp/a\040b", "/tmp/a b"));
+        assert!(!matches(r"/tmp/a\040b", "/tmp/a\\040b"));
+        assert!(matches(r"/tmp/back\\slash", "/tmp/back\\slash"));
+        assert!(matches("/tmp/a.b", "/tmp/a.b"));
+        assert!(!matches("/tmp/a.b", "/tmp/axb"));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(PathPattern::new(r"/tmp/\q").is_err());
+        assert!(PathPattern::new(r"/tmp/\4").is_err());
+        assert!(PathPattern::new(r"/tmp/\01").is_err());
+    }
+
+    #[test]
+    fn explain_match() {
+        let e = PathPattern::new(r"/home/\*/.ssh/\*")
+            .unwrap()
+            .explain("/home/user/.ssh/id_rsa");
+        assert!(e.matched);
+        assert!(!e.excluded);
+        assert_eq!(
+            e.parts,
+            vec![
+                ("/home/".to_string(), "/home/".to_string()),
+                (r"\*".to_string(), "user".to_string()),
+                ("/.ssh/".to_string(), "/.ssh/".to_string()),
+                (r"\*".to_string(), "id_rsa".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn explain_failure() {
+        let pattern = PathPattern::new(r"/etc/\*/\*.conf").unwrap();
+
+        let e = pattern.explain("/etc/nginx/nginx.cfg");
+        assert!(!e.matched);
+        assert!(!e.excluded);
+        assert_eq!(e.parts.len(), 4);
+        assert_eq!(e.parts[1], (r"\*".to_string(), "nginx".to_string()));
+        assert_eq!(e.parts[3], (r"\*".to_string(), "nginx.cfg".to_string()));
+        assert_eq!(e.failed.as_deref(), Some(".conf"));
+        assert_eq!(e.rest, "");
+
+        let e = pattern.explain("/var/log");
+        assert!(e.parts.is_empty());
+        assert_eq!(e.failed.as_deref(), Some("/etc/"));
+        assert_eq!(e.rest, "/var/log");
+    }
+}
@@ -0,0 +1,154 @@
+use regex::Regex;
+use serde::Serialize;
+
+/// PendingQuery is a query read from the query interface of caitsith:
+///
+/// ```text
+/// Q12-0
+/// #2026/10/18 09:12:01# global-pid=3720 result=denied priority=1000 / write path="/tmp/a" ...
+/// ```
+///
+/// `retry` is the number after `-`, which is incremented each time the query
+/// is asked again, e.g. after it is answered with re-evaluation.
+///
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct PendingQuery {
+    pub id: String,
+    pub retry: u32,
+    pub message: String,
+}
+
+/// QueryBatch is the result of parsing text read from the query interface.
+/// `notices` are lines which do not belong to any query.
+///
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct QueryBatch {
+    pub queries: Vec<PendingQuery>,
+    pub notices: Vec<String>,
+}
+
+impl QueryBatch {
+    fn push(&mut self, q: PendingQuery) {
+        match self.queries.iter_mut().find(|p| p.id == q.id) {
+            Some(p) if q.retry >= p.retry => *p = q,
+            Some(_) => {}
+            None => self.queries.push(q),
+        }
+    }
+}
+
+/// parse_queries parses text read from the query interface, which may hold
+/// any number of queries. A query starts with a `Q<id>-<retry>` line, and the
+/// following lines up to the next query are its audit message, joined with
+/// spaces. Entries may be separated with NUL characters.
+///
+/// If a query appears more than once, only the one with the largest retry
+/// number is kept at the position of its first appearance.
+///
+pub fn parse_queries(text: &str) -> QueryBatch {
+    let query_line = Regex::new(r"^Q(?P<id>\d+)(-(?P<retry>\d+))?$").unwrap();
+    let mut batch = QueryBatch::default();
+    let mut current: Option<(String, u32, Vec<&str>)> = None;
+    for line in text.split(['\n', '\0']).map(|l| l.trim()) {
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(cap) = query_line.captures(line) {
+            if let Some((id, retry, body)) = current.take() {
+                batch.push(PendingQuery {
+                    id,
+                    retry,
+                    message: body.join(" "),
+                });
+            }
+            let retry = cap
+                .name("retry")
+                .and_then(|r| r.as_str().parse().ok())
+                .unwrap_or(0);
+            current = Some((cap["id"].to_string(), retry, vec![]));
+            continue;
+        }
+        match current.as_mut() {
+            Some((_, _, body)) => body.push(line),
+            None => batch.notices.push(line.to_string()),
+        }
+    }
+    if let Some((id, retry, body)) = current {
+        batch.push(PendingQuery {
+            id,
+            retry,
+            message: body.join(" "),
+        });
+    }
+    batch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(id: &str, retry: u32, message: &str) -> PendingQuery {
+        PendingQuery {
+            id: id.to_string(),
+            retry,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn multiple_queries() {
+        let batch = parse_queries(
+            "Q1-0\n#t# result=denied / read path=\"/a\"\nQ2-0\n#t# result=denied / write path=\"/b\"\n",
+        );
+        assert_eq!(
+            batch.queries,
+            vec![
+                query("1", 0, "#t# result=denied / read path=\"/a\""),
+                query("2", 0, "#t# result=denied / write path=\"/b\""),
+            ]
+        );
+        assert!(batch.notices.is_empty());
+    }
+
+    #[test]
+    fn message_lines_are_joined() {
+        let batch = parse_queries("Q3-1\n#t# result=denied\n/ read path=\"/a\"\n");
+        assert_eq!(
+            batch.queries,
+            vec![query("3", 1, "#t# result=denied / read path=\"/a\"")]
+        );
+    }
+
+    #[test]
+    fn nul_separated_entries() {
+        let batch = parse_queries("Q1-0\n/ read\0Q2\n/ write\0\0");
+        assert_eq!(
+            batch.queries,
+            vec![query("1", 0, "/ read"), query("2", 0, "/ write")]
+        );
+    }
+
+    #[test]
+    fn latest_retry_is_kept() {
+        let batch =
+            parse_queries("Q1-0\n/ read\nQ2-0\n/ write\nQ1-2\n/ read again\nQ1-1\n/ stale\n");
+        assert_eq!(
+            batch.queries,
+            vec![query("1", 2, "/ read again"), query("2", 0, "/ write")]
+        );
+    }
+
+    #[test]
+    fn notices_before_queries() {
+        let batch = parse_queries("caitsith: query interface busy\n\nQ5-0\n/ read\n");
+        assert_eq!(
+            batch.notices,
+            vec!["caitsith: query interface busy".to_string()]
+        );
+        assert_eq!(batch.queries, vec![query("5", 0, "/ read")]);
+
+        let batch = parse_queries("Qx-0\n");
+        assert!(batch.queries.is_empty());
+        assert_eq!(batch.notices, vec!["Qx-0".to_string()]);
+    }
+}
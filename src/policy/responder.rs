@@ -0,0 +1,309 @@
+use clap::ValueEnum;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+/// RESPONDER_LOG is the decision log under the state directory, used if the
+/// rule file does not give a log.
+///
+pub const RESPONDER_LOG: &str = "responder.log";
+
+/// Answer is an answer for a query, sent to the query interface as 1
+/// (permit), 2 (deny) or 3 (re-evaluate).
+///
+#[derive(ValueEnum, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Answer {
+    Permit,
+    Deny,
+    Reevaluate,
+}
+
+impl Answer {
+    pub fn code(&self) -> u8 {
+        match self {
+            Answer::Permit => 1,
+            Answer::Deny => 2,
+            Answer::Reevaluate => 3,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Answer::Permit => "permit",
+            Answer::Deny => "deny",
+            Answer::Reevaluate => "reevaluate",
+        }
+    }
+}
+
+/// RuleFile is a set of responder rules written in TOML:
+///
+/// ```toml
+/// default = "deny"
+/// log = "/var/log/acquery-responder.log"
+///
+/// [[rule]]
+/// name = "curl may connect to the internet"
+/// operation = "^inet_stream_connect$"
+/// exe = "^/usr/bin/curl$"
+/// answer = "permit"
+/// limit = { count = 10, seconds = 60 }
+/// ```
+///
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RuleFile {
+    #[serde(default = "default_answer")]
+    pub default: Answer,
+    pub log: Option<String>,
+    #[serde(default)]
+    pub rule: Vec<RuleDef>,
+}
+
+fn default_answer() -> Answer {
+    Answer::Deny
+}
+
+/// RuleDef is a responder rule. Every given field is a regex which must
+/// match the field of the audit message; `line` matches the whole message.
+///
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RuleDef {
+    pub name: Option<String>,
+    pub line: Option<String>,
+    pub operation: Option<String>,
+    pub exe: Option<String>,
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    pub uid: Option<String>,
+    pub answer: Answer,
+    pub limit: Option<RateLimit>,
+}
+
+/// RateLimit limits the number of answers by a rule in a sliding window.
+/// A rule over the limit is skipped, so that later rules or the default
+/// answer the query.
+///
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub count: usize,
+    pub seconds: u64,
+}
+
+/// Decision is an answer for a query with the rule deciding it. `rule` is
+/// None for the default answer.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct Decision {
+    pub answer: Answer,
+    pub rule: Option<String>,
+    pub rate_limited: bool,
+}
+
+struct Rule {
+    name: String,
+    conds: Vec<(&'static str, Regex)>,
+    answer: Answer,
+    limit: Option<RateLimit>,
+    answered: VecDeque<u64>,
+}
+
+/// Responder answers queries with the first matching rule in the rule file.
+///
+pub struct Responder {
+    pub default: Answer,
+    pub log: Option<String>,
+    rules: Vec<Rule>,
+}
+
+impl Responder {
+    /// new compiles regexes of the rule file. Rules without a name are
+    /// named by their index.
+    ///
+    pub fn new(file: RuleFile) -> Result<Self, String> {
+        let mut rules = vec![];
+        for (i, r) in file.rule.into_iter().enumerate() {
+            let name = r.name.unwrap_or(format!("rule #{}", i + 1));
+            let mut conds = vec![];
+            for (field, pattern) in [
+                ("line", r.line),
+                ("operation", r.operation),
+                ("task.exe", r.exe),
+                ("task.domain", r.domain),
+                ("path", r.path),
+                ("task.uid", r.uid),
+            ] {
+                if let Some(p) = pattern {
+                    let re = Regex::new(&p).map_err(|e| format!("{}: {}", name, e))?;
+                    conds.push((field, re));
+                }
+            }
+            if r.limit.is_some_and(|l| l.count == 0 || l.seconds == 0) {
+                return Err(format!("{}: limit must be positive", name));
+            }
+            rules.push(Rule {
+                name,
+                conds,
+                answer: r.answer,
+                limit: r.limit,
+                answered: VecDeque::new(),
+            });
+        }
+        Ok(Responder {
+            default: file.default,
+            log: file.log,
+            rules,
+        })
+    }
+
+    /// decide answers a query with the audit message, its operation and its
+    /// `key=value` fields at the time in seconds. A rule matches if all of
+    /// its regexes match, and a rule over its rate limit is skipped.
+    ///
+    pub fn decide(
+        &mut self,
+        message: &str,
+        operation: Option<&str>,
+        attrs: &BTreeMap<String, String>,
+        now: u64,
+    ) -> Decision {
+        let mut rate_limited = false;
+        for r in self.rules.iter_mut() {
+            let matched = r.conds.iter().all(|(field, re)| {
+                let value = match *field {
+                    "line" => Some(message),
+                    "operation" => operation,
+                    f => attrs.get(f).map(|v| v.as_str()),
+                };
+                value.is_some_and(|v| re.is_match(v))
+            });
+            if !matched {
+                continue;
+            }
+            if let Some(l) = r.limit {
+                while r.answered.front().is_some_and(|t| t + l.seconds <= now) {
+                    r.answered.pop_front();
+                }
+                if r.answered.len() >= l.count {
+                    rate_limited = true;
+                    continue;
+                }
+                r.answered.push_back(now);
+            }
+            return Decision {
+                answer: r.answer,
+                rule: Some(r.name.clone()),
+                rate_limited: false,
+            };
+        }
+        Decision {
+            answer: self.default,
+            rule: None,
+            rate_limited,
+        }
+    }
+}
+
+/// parse_rule_file parses a responder rule file in TOML.
+///
+pub fn parse_rule_file(text: &str) -> Result<RuleFile, String> {
+    toml::from_str(text).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = include_str!("../../fixtures/sample-responder.toml");
+
+    fn attrs(fields: &[(&str, &str)]) -> BTreeMap<String, String> {
+        fields
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn responder(text: &str) -> Responder {
+        Responder::new(parse_rule_file(text).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn decide_matching_rule() {
+        let mut r = responder(RULES);
+        let home = attrs(&[("path", "/home/alice/.bashrc"), ("task.uid", "1000")]);
+        let d = r.decide("", Some("read"), &home, 0);
+        assert_eq!(d.answer, Answer::Permit);
+        assert_eq!(d.rule.as_deref(), Some("normal users may read their home"));
+
+        // every regex of the rule must match
+        let root = attrs(&[("path", "/home/alice/.bashrc"), ("task.uid", "0")]);
+        let d = r.decide("", Some("read"), &root, 0);
+        assert_eq!((d.answer, d.rule), (Answer::Deny, None));
+
+        // a field missing in the query does not match
+        let d = r.decide("", Some("read"), &attrs(&[("task.uid", "1000")]), 0);
+        assert_eq!((d.answer, d.rule), (Answer::Deny, None));
+
+        let line = "#2024/01/01 00:00:00# global-pid=1 result=denied task.domain=\"<apt>\"";
+        let d = r.decide(line, Some("execute"), &BTreeMap::new(), 0);
+        assert_eq!(d.answer, Answer::Reevaluate);
+        assert_eq!(d.rule.as_deref(), Some("retry package updates"));
+    }
+
+    #[test]
+    fn decide_rate_limit_window() {
+        let mut r = responder(RULES);
+        let curl = attrs(&[("task.exe", "/usr/bin/curl")]);
+        for t in 0..10 {
+            let d = r.decide("", Some("inet_stream_connect"), &curl, t);
+            assert_eq!((d.answer, d.rate_limited), (Answer::Permit, false));
+        }
+
+        // the rule over the limit falls through to the default
+        let d = r.decide("", Some("inet_stream_connect"), &curl, 59);
+        assert_eq!(
+            (d.answer, d.rule, d.rate_limited),
+            (Answer::Deny, None, true)
+        );
+
+        // the answer at 0 leaves the window of 60 seconds at 60
+        let d = r.decide("", Some("inet_stream_connect"), &curl, 60);
+        assert_eq!((d.answer, d.rate_limited), (Answer::Permit, false));
+        let d = r.decide("", Some("inet_stream_connect"), &curl, 60);
+        assert!(d.rate_limited);
+    }
+
+    #[test]
+    fn decide_default() {
+        let mut r = responder(
+            "default = \"reevaluate\"\n[[rule]]\nexe = \"^/bin/ls$\"\nanswer = \"permit\"\n",
+        );
+        let d = r.decide("", Some("read"), &attrs(&[("task.exe", "/bin/cat")]), 0);
+        assert_eq!(
+            (d.answer, d.rule, d.rate_limited),
+            (Answer::Reevaluate, None, false)
+        );
+        let d = r.decide("", Some("read"), &attrs(&[("task.exe", "/bin/ls")]), 0);
+        assert_eq!(d.rule.as_deref(), Some("rule #1"));
+
+        let mut r = responder("");
+        assert_eq!(r.default, Answer::Deny);
+        assert_eq!(r.decide("", None, &BTreeMap::new(), 0).answer, Answer::Deny);
+    }
+
+    #[test]
+    fn invalid_rule_file() {
+        assert!(parse_rule_file("[[rule]]\nanswer = \"allow\"\n").is_err());
+        assert!(parse_rule_file("[[rule]]\nanswer = \"permit\"\nuser = \"root\"\n").is_err());
+
+        let file = parse_rule_file("[[rule]]\nexe = \"(\"\nanswer = \"permit\"\n").unwrap();
+        assert!(Responder::new(file).is_err());
+        let file =
+            parse_rule_file("[[rule]]\nanswer = \"permit\"\nlimit = { count = 0, seconds = 60 }\n")
+                .unwrap();
+        assert!(Responder::new(file).is_err());
+    }
+}
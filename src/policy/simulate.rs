@@ -0,0 +1,249 @@
+use aclneko::acl::Acl;
+
+/// apply_acl computes the policy resulting from writing a patch to the
+/// policy interface. Rules of a patch are appended to the ACL block with the
+/// same header, and a header missing in the policy makes a new ACL block.
+/// Rules which already exist in the block are not duplicated.
+///
+/// Headers are compared with their text, since AclHeader equality does not
+/// take attribute values into account.
+///
+pub fn apply_acl(patch: &Acl, from: &Acl) -> Acl {
+    let mut res = from.clone();
+    for b in patch.data.values() {
+        res.raw_header_add(&b.header);
+        for r in &b.rule {
+            let exists = res
+                .data
+                .values()
+                .any(|d| d.header.to_string() == b.header.to_string() && d.rule.contains(r));
+            if !exists {
+                res.raw_rule_add(&b.header, r.clone());
+            }
+        }
+    }
+    res
+}
+
+/// applied_part returns the part of a patch which apply_acl adds to the
+/// policy: ACL blocks whose header is missing in the policy, and rules
+/// missing in the existing blocks. Removing them returns the policy to `from`.
+///
+pub fn applied_part(patch: &Acl, from: &Acl) -> (Acl, Acl) {
+    let (mut blocks, mut rules) = (Acl::new(), Acl::new());
+    for b in patch.data.values() {
+        let header = b.header.to_string();
+        match from.data.values().find(|d| d.header.to_string() == header) {
+            None => {
+                blocks.raw_header_add(&b.header);
+                for r in &b.rule {
+                    blocks.raw_rule_add(&b.header, r.clone());
+                }
+            }
+            Some(d) => {
+                for r in b.rule.iter().filter(|r| !d.rule.contains(r)) {
+                    rules.raw_header_add(&b.header);
+                    rules.raw_rule_add(&b.header, r.clone());
+                }
+            }
+        }
+    }
+    (blocks, rules)
+}
+
+/// apply_acl_atomic is the simulation of `aclneko::io::apply_acl_atomic`.
+///
+pub fn apply_acl_atomic(patch: &Acl, from: &Acl) -> Result<Acl, String> {
+    match patch.is_atomic() {
+        true => Ok(apply_acl(patch, from)),
+        false => Err(String::from("Selected ACL is not atomic")),
+    }
+}
+
+/// clear_acl computes the policy where ACL blocks specified with the headers
+/// in the patch are deleted.
+///
+pub fn clear_acl(patch: &Acl, from: &Acl) -> Acl {
+    let mut res = from.clone();
+    let mut table = res.data.as_table_ref().clone();
+    table.retain(|_, b| !patch.has_header(&b.header));
+    res.data.set_table(table);
+    res
+}
+
+/// remove_acl is the simulation of `aclneko::io::remove_acl`, which fails for
+/// a patch with a header not defined in the policy.
+///
+pub fn remove_acl(patch: &Acl, from: &Acl) -> Result<Acl, String> {
+    ensure_headers(patch, from)?;
+    Ok(clear_acl(patch, from))
+}
+
+/// remove_acl_atomic is the simulation of `aclneko::io::remove_acl_atomic`.
+///
+pub fn remove_acl_atomic(patch: &Acl, from: &Acl) -> Result<Acl, String> {
+    match patch.is_atomic() {
+        true => remove_acl(patch, from),
+        false => Err(String::from("Selected ACL is not atomic")),
+    }
+}
+
+/// clear_rule computes the policy where rules contained in the patch are
+/// removed from ACL blocks with the same header. Other rules in the blocks
+/// are kept.
+///
+pub fn clear_rule(patch: &Acl, from: &Acl) -> Acl {
+    let mut res = from.clone();
+    let mut table = res.data.as_table_ref().clone();
+    for d in table.values_mut() {
+        let header = d.header.to_string();
+        for b in patch
+            .data
+            .values()
+            .filter(|b| b.header.to_string() == header)
+        {
+            d.rule.retain(|r| !b.rule.contains(r));
+        }
+    }
+    res.data.set_table(table);
+    res
+}
+
+/// unmerge_acl is the simulation of `aclneko::io::unmerge_acl`, which fails
+/// for a patch with a header not defined in the policy.
+///
+pub fn unmerge_acl(patch: &Acl, from: &Acl) -> Result<Acl, String> {
+    ensure_headers(patch, from)?;
+    Ok(clear_rule(patch, from))
+}
+
+fn ensure_headers(patch: &Acl, from: &Acl) -> Result<(), String> {
+    for h in patch.parse_acl_headers() {
+        if !from.has_header(&h) {
+            return Err(format!("no such header: {}", h));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::sorted_blocks;
+    use std::str::FromStr;
+
+    const POLICY: &str = include_str!("../../fixtures/sample-policy.acl");
+    const INET: &str = include_str!("../../fixtures/sample-patch-inet.acl");
+    const INET2: &str = include_str!("../../fixtures/sample-patch-inet2.acl");
+    const PATH: &str = include_str!("../../fixtures/sample-patch-path.acl");
+    const READ: &str = include_str!("../../fixtures/sample-patch-read.acl");
+
+    const CURL: &str = r#"10 acl inet_stream_connect task.exe="/usr/bin/curl""#;
+
+    fn acl(text: &str) -> Acl {
+        Acl::from_str(text).unwrap()
+    }
+
+    fn rules_of(acl: &Acl, header: &str) -> Option<Vec<String>> {
+        sorted_blocks(acl)
+            .into_iter()
+            .find(|b| b.header == header)
+            .map(|b| b.rules)
+    }
+
+    fn headers(acl: &Acl) -> Vec<String> {
+        sorted_blocks(acl).into_iter().map(|b| b.header).collect()
+    }
+
+    #[test]
+    fn apply_adds_a_new_block() {
+        let policy = acl(POLICY);
+        let res = apply_acl(&acl(INET), &policy);
+        assert_eq!(
+            rules_of(&res, CURL),
+            Some(vec!["0 allow port=443".to_string(), "0 deny".to_string()])
+        );
+        assert_eq!(res.len(), policy.len() + 1);
+        for h in headers(&policy) {
+            assert_eq!(rules_of(&res, &h), rules_of(&policy, &h));
+        }
+    }
+
+    #[test]
+    fn apply_does_not_duplicate_rules() {
+        let applied = apply_acl(&acl(INET), &acl(POLICY));
+        let again = apply_acl(&acl(INET2), &apply_acl(&acl(INET), &applied));
+        assert_eq!(rules_of(&again, CURL), rules_of(&applied, CURL));
+
+        let path = acl(PATH);
+        let header = headers(&path).remove(0);
+        let res = apply_acl(&path, &acl(POLICY));
+        let rules = rules_of(&res, &header).unwrap();
+        let denies = rules
+            .iter()
+            .filter(|r| r.starts_with("20 deny path=@u-savoir"))
+            .count();
+        assert_eq!(denies, 1);
+        assert_eq!(rules.len(), rules_of(&path, &header).unwrap().len() - 1);
+    }
+
+    #[test]
+    fn apply_atomic_requires_a_single_block() {
+        let policy = acl(POLICY);
+        let atomic = apply_acl_atomic(&acl(INET), &policy).unwrap();
+        assert_eq!(headers(&atomic), headers(&apply_acl(&acl(INET), &policy)));
+
+        let patch = acl(&format!("{}{}", INET, READ));
+        assert!(apply_acl_atomic(&patch, &policy).is_err());
+        assert!(remove_acl_atomic(&patch, &apply_acl(&patch, &policy)).is_err());
+    }
+
+    #[test]
+    fn remove_deletes_blocks() {
+        let policy = acl(POLICY);
+        assert!(remove_acl(&acl(READ), &policy).is_err());
+
+        let applied = apply_acl(&acl(INET), &policy);
+        let removed = remove_acl(&acl(INET2), &applied).unwrap();
+        assert_eq!(rules_of(&removed, CURL), None);
+        assert_eq!(headers(&removed), headers(&policy));
+
+        let atomic = remove_acl_atomic(&acl(INET), &applied).unwrap();
+        assert_eq!(headers(&atomic), headers(&policy));
+    }
+
+    #[test]
+    fn unmerge_removes_rules_of_the_patch() {
+        let policy = acl(POLICY);
+        assert!(unmerge_acl(&acl(INET2), &policy).is_err());
+
+        let applied = apply_acl(&acl(INET), &policy);
+        let res = unmerge_acl(&acl(INET2), &applied).unwrap();
+        assert_eq!(rules_of(&res, CURL), Some(vec!["0 deny".to_string()]));
+        assert_eq!(res.len(), applied.len());
+    }
+
+    #[test]
+    fn clear_rule_ignores_missing_headers() {
+        let policy = acl(POLICY);
+        let res = clear_rule(&acl(INET2), &policy);
+        for h in headers(&policy) {
+            assert_eq!(rules_of(&res, &h), rules_of(&policy, &h));
+        }
+
+        let applied = apply_acl(&acl(INET), &policy);
+        let res = clear_rule(&acl(INET), &applied);
+        assert_eq!(rules_of(&res, CURL), Some(vec![]));
+    }
+
+    #[test]
+    fn applied_part_of_a_patch() {
+        let policy = apply_acl(&acl(INET2), &acl(POLICY));
+        let (blocks, rules) = applied_part(&acl(&format!("{}{}", INET, READ)), &policy);
+        assert_eq!(
+            headers(&blocks),
+            vec![r#"9008 acl read path="/home/\*/cd.sh""#]
+        );
+        assert_eq!(rules_of(&rules, CURL), Some(vec!["0 deny".to_string()]));
+    }
+}
@@ -0,0 +1,383 @@
+use super::diff::{diff_acls, BlockChange};
+use super::sorted_blocks;
+use super::stat::stat_acl;
+use crate::clock::{format_compact, now};
+use aclneko::acl::Acl;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// STATE_DIR is the directory where acquery keeps its state, resolved under
+/// the root directory of a backend.
+///
+pub const STATE_DIR: &str = "/var/lib/acquery";
+
+/// MAX_AUTO_SNAPSHOTS is the number of automatic snapshots to be kept.
+/// Older ones are pruned when a new automatic snapshot is saved.
+///
+pub const MAX_AUTO_SNAPSHOTS: usize = 20;
+
+const AUTO_PREFIX: &str = "auto-";
+
+/// SnapshotMeta is the metadata saved with a snapshot of the policy.
+///
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotMeta {
+    pub name: String,
+    pub created: u64,
+    pub source: String,
+    pub acls: usize,
+    pub rules: usize,
+    pub message: Option<String>,
+}
+
+impl SnapshotMeta {
+    pub fn is_auto(&self) -> bool {
+        self.name.starts_with(AUTO_PREFIX)
+    }
+}
+
+/// SnapshotStore manages snapshots of the policy under a directory.
+/// A snapshot is saved as a pair of `<name>.acl` holding the policy text and
+/// `<name>.json` holding SnapshotMeta.
+///
+pub struct SnapshotStore {
+    dir: PathBuf,
+}
+
+impl SnapshotStore {
+    pub fn new(state_dir: &Path) -> Self {
+        SnapshotStore {
+            dir: state_dir.join("snapshots"),
+        }
+    }
+
+    /// save saves the policy text as a snapshot. It is an error if a
+    /// snapshot of the given name exists. A name is generated from the
+    /// current time if not given, with a numbered suffix when the name of
+    /// the same second is taken.
+    ///
+    pub fn save(
+        &self,
+        name: Option<&str>,
+        text: &str,
+        source: &str,
+        message: Option<String>,
+    ) -> Result<SnapshotMeta, String> {
+        let created = now();
+        match name {
+            Some(n) => self.save_new(n, false, created, text, source, message),
+            None => self.save_new(
+                &format_compact(created),
+                true,
+                created,
+                text,
+                source,
+                message,
+            ),
+        }
+    }
+
+    /// save_auto saves an automatic snapshot taken before a policy
+    /// modification, and prunes old automatic snapshots.
+    ///
+    pub fn save_auto(
+        &self,
+        text: &str,
+        source: &str,
+        reason: &str,
+    ) -> Result<SnapshotMeta, String> {
+        let created = now();
+        let name = format!("{}{}-{}", AUTO_PREFIX, format_compact(created), reason);
+        let meta = self.save_new(
+            &name,
+            true,
+            created,
+            text,
+            source,
+            Some(format!("before {}", reason)),
+        )?;
+        let mut auto: Vec<SnapshotMeta> =
+            self.list()?.into_iter().filter(|m| m.is_auto()).collect();
+        while auto.len() > MAX_AUTO_SNAPSHOTS {
+            let old = auto.remove(0);
+            self.remove(&old.name)?;
+        }
+        Ok(meta)
+    }
+
+    /// list returns metadata of snapshots ordered by their creation time.
+    ///
+    pub fn list(&self) -> Result<Vec<SnapshotMeta>, String> {
+        let mut res = vec![];
+        let d = match fs::read_dir(&self.dir) {
+            Ok(d) => d,
+            Err(_) => return Ok(res),
+        };
+        for f in d.flatten() {
+            let path = f.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                let text = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+                match serde_json::from_str::<SnapshotMeta>(&text) {
+                    Ok(m) => res.push(m),
+                    Err(e) => eprintln!("{}: {}", path.display(), e),
+                }
+            }
+        }
+        // a numbered name taken within the same second is longer than the
+        // names taken before it
+        res.sort_by(|v, w| {
+            (v.created, v.name.len(), &v.name).cmp(&(w.created, w.name.len(), &w.name))
+        });
+        Ok(res)
+    }
+
+    /// read returns metadata and the policy text of a snapshot.
+    ///
+    pub fn read(&self, name: &str) -> Result<(SnapshotMeta, String), String> {
+        validate_name(name)?;
+        let json = fs::read_to_string(self.dir.join(format!("{}.json", name)))
+            .map_err(|_| format!("no such snapshot: {}", name))?;
+        let meta = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+        let text = fs::read_to_string(self.dir.join(format!("{}.acl", name)))
+            .map_err(|e| format!("{}: {}", name, e))?;
+        Ok((meta, text))
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), String> {
+        validate_name(name)?;
+        for ext in ["json", "acl"] {
+            let path = self.dir.join(format!("{}.{}", name, ext));
+            fs::remove_file(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    //save_new saves a snapshot without overwriting an existing one. The
+    //policy file is created first to take the name, and the metadata file is
+    //renamed into place so that `list` never reads a partial one. When unique
+    //is set, `-2`, `-3`, ... is appended to the name until a free one is found.
+    fn save_new(
+        &self,
+        name: &str,
+        unique: bool,
+        created: u64,
+        text: &str,
+        source: &str,
+        message: Option<String>,
+    ) -> Result<SnapshotMeta, String> {
+        validate_name(name)?;
+        let acl = Acl::from_str(text)?;
+        fs::create_dir_all(&self.dir).map_err(|e| format!("{}: {}", self.dir.display(), e))?;
+
+        let mut n = 1;
+        let (name, mut f) = loop {
+            let candidate = match n {
+                1 => name.to_string(),
+                _ => format!("{}-{}", name, n),
+            };
+            let path = self.dir.join(format!("{}.acl", candidate));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(f) => break (candidate, f),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists && unique => n += 1,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    return Err(format!("snapshot already exists: {}", candidate))
+                }
+                Err(e) => return Err(format!("{}: {}", path.display(), e)),
+            }
+        };
+
+        let meta = SnapshotMeta {
+            name: name.clone(),
+            created,
+            source: source.to_string(),
+            acls: acl.len(),
+            rules: stat_acl(&acl).rules,
+            message,
+        };
+        let json = serde_json::to_string_pretty(&meta).map_err(|e| e.to_string())?;
+        let tmp = format!(".{}.json.tmp", name);
+        let written = f
+            .write_all(text.as_bytes())
+            .map_err(|e| format!("{}.acl: {}", name, e))
+            .and_then(|_| self.write(&tmp, &json))
+            .and_then(|_| {
+                let path = self.dir.join(format!("{}.json", name));
+                fs::rename(self.dir.join(&tmp), &path)
+                    .map_err(|e| format!("{}: {}", path.display(), e))
+            });
+        if written.is_err() {
+            let _ = fs::remove_file(self.dir.join(&tmp));
+            let _ = fs::remove_file(self.dir.join(format!("{}.acl", name)));
+        }
+        written.map(|_| meta)
+    }
+
+    fn write(&self, file: &str, text: &str) -> Result<(), String> {
+        let path = self.dir.join(file);
+        fs::write(&path, text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(format!("invalid snapshot name: {}", name));
+    }
+    Ok(())
+}
+
+/// rollback_plan computes lines to be written to the policy interface for
+/// turning the policy `from` into `to`.
+///
+/// Removed ACL blocks are deleted and added ones are applied. A changed block
+/// which only lacks rules gets the missing rules appended, and any other
+/// changed block is deleted and applied again. Group definitions are added or
+/// deleted line by line.
+///
+pub fn rollback_plan(from: &Acl, to: &Acl) -> Vec<String> {
+    let mut res = vec![];
+
+    let (from_groups, to_groups) = (group_lines(from), group_lines(to));
+    for g in from_groups.difference(&to_groups) {
+        res.push(format!("delete {}", g));
+    }
+    for g in to_groups.difference(&from_groups) {
+        res.push(g.clone());
+    }
+
+    let targets: HashMap<String, Vec<String>> = sorted_blocks(to)
+        .into_iter()
+        .map(|b| (b.header, b.rules))
+        .collect();
+    for b in diff_acls(from, to).blocks {
+        match b.change {
+            BlockChange::Removed => res.push(format!("delete {}", b.header)),
+            BlockChange::Added => {
+                res.push(b.header.clone());
+                res.extend(b.added_rules.iter().map(|r| format!("    {}", r)));
+            }
+            BlockChange::Changed => {
+                let rules = match b.removed_rules.is_empty() {
+                    true => &b.added_rules,
+                    false => {
+                        res.push(format!("delete {}", b.header));
+                        &targets[&b.header]
+                    }
+                };
+                res.push(b.header.clone());
+                res.extend(rules.iter().map(|r| format!("    {}", r)));
+            }
+        }
+    }
+    res
+}
+
+fn group_lines(acl: &Acl) -> BTreeSet<String> {
+    let mut res = BTreeSet::new();
+    for (kind, groups) in [
+        ("string_group", &acl.data.string_group),
+        ("number_group", &acl.data.number_group),
+        ("ip_group", &acl.data.ip_group),
+    ] {
+        for (name, members) in groups {
+            for m in members {
+                res.insert(format!("{} {} {}", kind, name, m));
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::backend::{sample_emulator, PolicyBackend};
+    use crate::policy::{format_blocks, temp_root};
+
+    const INET: &str = include_str!("../../fixtures/sample-patch-inet.acl");
+
+    const SMALL: &str = "string_group LOGS /var/log/\\*\n\
+        100 acl read path=\"/etc/shadow\"\n    audit 1\n    0 allow task.uid=0\n    10 deny\n";
+
+    #[test]
+    fn save_counts_allow_and_deny() {
+        let store = SnapshotStore::new(&temp_root("snapshot-count"));
+        let meta = store.save(Some("small"), SMALL, "test", None).unwrap();
+        assert_eq!((meta.acls, meta.rules), (1, 2));
+
+        let (read, text) = store.read("small").unwrap();
+        assert_eq!((read.name.as_str(), read.rules), ("small", 2));
+        assert_eq!(text, SMALL);
+    }
+
+    #[test]
+    fn save_name_collision() {
+        let store = SnapshotStore::new(&temp_root("snapshot-collision"));
+        store.save(Some("base"), SMALL, "test", None).unwrap();
+        assert!(store.save(Some("base"), SMALL, "test", None).is_err());
+
+        let names: Vec<String> = (0..3)
+            .map(|_| {
+                store
+                    .save_new("same", true, 1, SMALL, "test", None)
+                    .unwrap()
+                    .name
+            })
+            .collect();
+        assert_eq!(names, ["same", "same-2", "same-3"]);
+        assert!(store.save(Some(".hidden"), SMALL, "test", None).is_err());
+        assert!(store.save(Some("a/b"), SMALL, "test", None).is_err());
+    }
+
+    #[test]
+    fn save_auto_prunes_oldest() {
+        let store = SnapshotStore::new(&temp_root("snapshot-prune"));
+        store.save(Some("manual"), SMALL, "test", None).unwrap();
+        let names: Vec<String> = (0..MAX_AUTO_SNAPSHOTS + 3)
+            .map(|_| store.save_auto(SMALL, "test", "apply").unwrap().name)
+            .collect();
+
+        let list = store.list().unwrap();
+        assert_eq!(list.len(), MAX_AUTO_SNAPSHOTS + 1);
+        assert!(list.iter().any(|m| m.name == "manual"));
+        let kept: Vec<&String> = list
+            .iter()
+            .filter(|m| m.is_auto())
+            .map(|m| &m.name)
+            .collect();
+        assert_eq!(kept, names[3..].iter().collect::<Vec<_>>());
+        assert!(store.read(&names[0]).is_err());
+    }
+
+    #[test]
+    fn rollback_restores_policy() {
+        let mut e = sample_emulator("snapshot-rollback");
+        let before = e.read_policy().unwrap();
+
+        e.apply_acl(&Acl::from_str(INET).unwrap()).unwrap();
+        e.write_policy("string_group NEW /tmp/\\*\n").unwrap();
+        let applied = e.read_policy().unwrap();
+
+        let plan = rollback_plan(&applied, &before);
+        assert!(plan.contains(&"delete string_group NEW /tmp/\\*".to_string()));
+        assert!(plan
+            .iter()
+            .any(|l| l.starts_with("delete 10 acl inet_stream_connect")));
+        e.write_policy(&plan.join("\n")).unwrap();
+        assert_eq!(
+            format_blocks(&e.read_policy().unwrap()),
+            format_blocks(&before)
+        );
+
+        let plan = rollback_plan(&before, &applied);
+        e.write_policy(&plan.join("\n")).unwrap();
+        assert_eq!(
+            format_blocks(&e.read_policy().unwrap()),
+            format_blocks(&applied)
+        );
+        assert!(rollback_plan(&applied, &applied).is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+use aclneko::acl::Acl;
+use aclneko::syntax::{Op, Resource, Verb};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// StatCounter is a counter line in the policy preamble with the timestamp
+/// of its last update, e.g. `stat Requests denied: 22994 (Last: ...)`.
+///
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct StatCounter {
+    pub count: u64,
+    pub last: Option<String>,
+}
+
+/// AuditQuota is a `quota audit[n]` line in the policy preamble.
+///
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct AuditQuota {
+    pub allowed: u64,
+    pub denied: u64,
+    pub unmatched: u64,
+}
+
+/// Preamble holds `stat` and `quota` lines which are emitted by caitsith
+/// ahead of ACL definitions. The lines are not kept in Acl, so they are
+/// parsed from the raw policy text.
+///
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Preamble {
+    pub version: Option<u32>,
+    pub policy_updated: Option<StatCounter>,
+    pub requests_denied: Option<StatCounter>,
+    pub memory_used: BTreeMap<String, u64>,
+    pub memory_quota: BTreeMap<String, u64>,
+    pub audit_quota: BTreeMap<u16, AuditQuota>,
+}
+
+impl Preamble {
+    pub fn is_empty(&self) -> bool {
+        self.version.is_none()
+            && self.policy_updated.is_none()
+            && self.requests_denied.is_none()
+            && self.memory_used.is_empty()
+            && self.memory_quota.is_empty()
+            && self.audit_quota.is_empty()
+    }
+}
+
+/// parse_preamble parses the version, `stat` and `quota` lines in a policy
+/// text. Lines of other categories are ignored.
+///
+pub fn parse_preamble(text: &str) -> Preamble {
+    let version = Regex::new(r"^POLICY_VERSION=(\d+)").unwrap();
+    let counter =
+        Regex::new(r"^stat\s+(?P<name>[^:]+):\s+(?P<count>\d+)(\s+\(Last:\s+(?P<last>[^)]+)\))?")
+            .unwrap();
+    let memory_used = Regex::new(r"^stat\s+Memory used by (?P<kind>\w+):\s+(?P<size>\d+)").unwrap();
+    let memory_quota = Regex::new(r"^quota\s+memory\s+(?P<kind>\w+)\s+(?P<size>\d+)").unwrap();
+    let audit_quota = Regex::new(
+        r"^quota\s+audit\[(?P<idx>\d+)\]\s+allowed=(?P<a>\d+)\s+denied=(?P<d>\d+)\s+unmatched=(?P<u>\d+)",
+    )
+    .unwrap();
+
+    let mut res = Preamble::default();
+    for line in text.lines() {
+        if let Some(cap) = version.captures(line) {
+            res.version = cap[1].parse().ok();
+        } else if let Some(cap) = memory_used.captures(line) {
+            if let Ok(n) = cap["size"].parse() {
+                res.memory_used.insert(cap["kind"].to_string(), n);
+            }
+        } else if let Some(cap) = counter.captures(line) {
+            let c = StatCounter {
+                count: cap["count"].parse().unwrap_or(0),
+                last: cap.name("last").map(|m| m.as_str().to_string()),
+            };
+            match &cap["name"] {
+                "Policy updated" => res.policy_updated = Some(c),
+                "Requests denied" => res.requests_denied = Some(c),
+                _ => {}
+            }
+        } else if let Some(cap) = memory_quota.captures(line) {
+            if let Ok(n) = cap["size"].parse() {
+                res.memory_quota.insert(cap["kind"].to_string(), n);
+            }
+        } else if let Some(cap) = audit_quota.captures(line) {
+            if let Ok(idx) = cap["idx"].parse() {
+                res.audit_quota.insert(
+                    idx,
+                    AuditQuota {
+                        allowed: cap["a"].parse().unwrap_or(0),
+                        denied: cap["d"].parse().unwrap_or(0),
+                        unmatched: cap["u"].parse().unwrap_or(0),
+                    },
+                );
+            }
+        }
+    }
+    res
+}
+
+/// AclStat is the statistics report for ACL blocks in a policy. `rules` is
+/// the number of allow and deny rules, and `audit` lines are counted apart.
+///
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct AclStat {
+    pub acls: usize,
+    pub rules: usize,
+    pub allow: usize,
+    pub deny: usize,
+    pub audit: usize,
+    pub operations: BTreeMap<String, usize>,
+    pub resources: BTreeMap<String, usize>,
+}
+
+/// stat_acl counts ACL blocks and rules in the Acl, allow and deny rules,
+/// headers for each operation and headers for each resource in their
+/// attributes.
+///
+pub fn stat_acl(acl: &Acl) -> AclStat {
+    let mut res = AclStat {
+        acls: acl.len(),
+        ..Default::default()
+    };
+
+    // Acl::rule_count_verb indexes its result with the rule position, which
+    // overruns for a policy having a block with more rules than blocks.
+    for b in acl.data.values() {
+        for r in &b.rule {
+            match r.verb {
+                Verb::Allow => res.allow += 1,
+                Verb::Deny => res.deny += 1,
+                Verb::Audit => res.audit += 1,
+                _ => {}
+            }
+        }
+    }
+    res.rules = res.allow + res.deny;
+
+    for o in Op::list() {
+        let op_count = acl.count_op(o);
+        if op_count != 0 {
+            res.operations.insert(o.as_str().to_string(), op_count);
+        }
+    }
+
+    for r in Resource::list() {
+        let res_count = acl.count(|header| header.attr.iter().any(|attr| attr.0 == r));
+        if res_count != 0 {
+            res.resources.insert(r.as_str().to_string(), res_count);
+        }
+    }
+    res
+}
@@ -0,0 +1,202 @@
+use super::learn::{allow_priority, format_attrs, matching_group, LEARNED_ATTRS, PATH_ATTRS};
+use super::BlockLines;
+use aclneko::acl::Acl;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// DEFAULT_PRIORITY is the priority of suggested rules in a block without
+/// deny rules.
+///
+const DEFAULT_PRIORITY: u16 = 10;
+
+/// Suggestion is a candidate rule allowing a violated request, with the
+/// scope of requests it allows.
+///
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Suggestion {
+    pub scope: &'static str,
+    pub rule: String,
+}
+
+/// suggest_rules returns candidate rules for the request in the ACL block,
+/// ranked from the narrowest scope:
+///
+/// - `exact`: the executable and the object as requested
+/// - `directory`: the executable and any name in the directory of `path`
+/// - `group`: the executable and the object replaced by string_groups of the
+///   Acl which match them
+/// - `executable`: any object for the executable
+/// - `domain`: any request of the domain
+///
+/// Scopes unavailable for the request, and rules same as a narrower one, are
+/// skipped. Rules are prioritized before the deny rules of the block, which
+/// is impossible for a deny rule at priority 0; preview warns about it.
+///
+pub fn suggest_rules(
+    acl: &Acl,
+    block: Option<&BlockLines>,
+    attrs: &BTreeMap<String, String>,
+) -> Vec<Suggestion> {
+    let requested: Vec<(String, String)> = LEARNED_ATTRS
+        .iter()
+        .filter_map(|k| attrs.get(*k).map(|v| (k.to_string(), v.clone())))
+        .collect();
+    let exe: Vec<(String, String)> = requested
+        .iter()
+        .filter(|(k, _)| k == "task.exe")
+        .cloned()
+        .collect();
+
+    let directory: Vec<(String, String)> = requested
+        .iter()
+        .filter_map(|(k, v)| match k.as_str() {
+            "path" => v
+                .rsplit_once('/')
+                .filter(|(dir, _)| !dir.is_empty())
+                .map(|(dir, _)| (k.clone(), format!("{}/\\*", dir))),
+            _ => Some((k.clone(), v.clone())),
+        })
+        .collect();
+    let group: Vec<(String, String)> = requested
+        .iter()
+        .map(
+            |(k, v)| match k == "task.exe" || PATH_ATTRS.contains(&k.as_str()) {
+                true => match matching_group(acl, v) {
+                    Some(name) => (k.clone(), format!("@{}", name)),
+                    None => (k.clone(), v.clone()),
+                },
+                false => (k.clone(), v.clone()),
+            },
+        )
+        .collect();
+    let domain: Vec<(String, String)> = attrs
+        .get("task.domain")
+        .map(|d| vec![(String::from("task.domain"), d.clone())])
+        .unwrap_or_default();
+
+    let priority = block
+        .map(|b| allow_priority(b, DEFAULT_PRIORITY))
+        .unwrap_or(DEFAULT_PRIORITY);
+    let mut res: Vec<Suggestion> = vec![];
+    for (scope, attrs) in [
+        ("exact", requested.clone()),
+        ("directory", directory),
+        ("group", group),
+        ("executable", exe),
+        ("domain", domain),
+    ] {
+        if attrs.is_empty() || (scope != "exact" && attrs == requested) {
+            continue;
+        }
+        let rule = format!("{} allow{}", priority, format_attrs(&attrs));
+        if !res.iter().any(|s| s.rule == rule) {
+            res.push(Suggestion { scope, rule });
+        }
+    }
+    res
+}
+
+/// preview returns the lines of the ACL block with the rule added in the
+/// order of priorities. The added line is marked with `+`.
+///
+/// A rule is added after the rules with the same priority. If an allow rule
+/// comes after a deny rule, a warning marked with `!` is appended, since the
+/// deny rule is evaluated first.
+///
+pub fn preview(header: &str, block: Option<&BlockLines>, rule: &str) -> Vec<String> {
+    let rule = rule.trim();
+    let priority_of = |r: &str| -> Option<u16> { r.split_whitespace().next()?.parse().ok() };
+    let rules = block.map(|b| b.rules.as_slice()).unwrap_or_default();
+    let at = match priority_of(rule) {
+        Some(p) => rules
+            .iter()
+            .position(|r| priority_of(r).is_some_and(|q| q > p))
+            .unwrap_or(rules.len()),
+        None => rules.len(),
+    };
+    let mut res = vec![header.to_string()];
+    res.extend(rules[..at].iter().map(|r| format!("    {}", r)));
+    res.push(format!("+   {}", rule));
+    res.extend(rules[at..].iter().map(|r| format!("    {}", r)));
+
+    let is_allow = rule.split_whitespace().nth(1) == Some("allow");
+    let deny = rules[..at]
+        .iter()
+        .find(|r| r.split_whitespace().nth(1) == Some("deny"));
+    if let (true, Some(d)) = (is_allow, deny) {
+        let hint = match priority_of(d) {
+            Some(0) => "no priority precedes it",
+            _ => "lower the priority to precede it",
+        };
+        res.push(format!(
+            "!   `{}` is evaluated before the rule; {}",
+            d, hint
+        ));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aclneko::syntax::Op;
+    use std::str::FromStr;
+
+    fn block(rules: &[&str]) -> BlockLines {
+        BlockLines {
+            priority: 1010,
+            op: Op::from("rename"),
+            header: String::from("1010 acl rename"),
+            rules: rules.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    fn attrs() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("task.exe".to_string(), "/bin/mv".to_string()),
+            ("old_path".to_string(), "/tmp/a".to_string()),
+        ])
+    }
+
+    #[test]
+    fn suggestions_precede_deny_rules() {
+        let b = block(&["2 deny old_path=\"/etc/\\*\"", "50 allow"]);
+        let s = suggest_rules(&Acl::from_str("").unwrap(), Some(&b), &attrs());
+        assert_eq!(s[0].scope, "exact");
+        assert_eq!(
+            s[0].rule,
+            "1 allow task.exe=\"/bin/mv\" old_path=\"/tmp/a\""
+        );
+        assert_eq!(s.last().unwrap().scope, "executable");
+
+        let p = preview(&b.header, Some(&b), &s[0].rule);
+        assert_eq!(p[1], format!("+   {}", s[0].rule));
+        assert!(!p.iter().any(|l| l.starts_with('!')));
+    }
+
+    #[test]
+    fn preview_warns_deny_at_priority_zero() {
+        let b = block(&["0 deny", "50 allow"]);
+        let s = suggest_rules(&Acl::from_str("").unwrap(), Some(&b), &attrs());
+        assert!(s[0].rule.starts_with("0 allow"));
+
+        let p = preview(&b.header, Some(&b), &s[0].rule);
+        assert_eq!(p[1], "    0 deny");
+        assert_eq!(
+            p.last().unwrap(),
+            "!   `0 deny` is evaluated before the rule; no priority precedes it"
+        );
+    }
+
+    #[test]
+    fn preview_warns_lower_deny() {
+        let b = block(&["5 deny"]);
+        let p = preview(&b.header, Some(&b), "20 allow");
+        assert_eq!(
+            p.last().unwrap(),
+            "!   `5 deny` is evaluated before the rule; lower the priority to precede it"
+        );
+        assert_eq!(preview(&b.header, Some(&b), "4 allow").len(), 3);
+        assert_eq!(preview(&b.header, Some(&b), "20 deny").len(), 3);
+    }
+}
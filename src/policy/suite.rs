@@ -0,0 +1,152 @@
+use super::check::{check_request, CheckResult, Request};
+use aclneko::acl::Acl;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// TestFile is a set of test cases written in TOML:
+///
+/// ```toml
+/// [[test]]
+/// name = "curl cannot connect to the internet"
+/// operation = "inet_stream_connect"
+/// attrs = { ip = "8.8.8.8", port = 443, "task.exe" = "/usr/bin/curl" }
+/// expect = "deny"
+/// expect_rule = "20 deny"
+/// ```
+///
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TestFile {
+    #[serde(default)]
+    pub test: Vec<TestCase>,
+}
+
+/// TestCase is a hypothetical request with the expected verdict. The deciding
+/// ACL header and rule and the transition are checked if given. An expected
+/// rule or header ending with `...` is compared as a prefix.
+///
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TestCase {
+    pub name: Option<String>,
+    pub operation: String,
+    #[serde(default)]
+    pub attrs: BTreeMap<String, toml::Value>,
+    pub expect: String,
+    pub expect_rule: Option<String>,
+    pub expect_acl: Option<String>,
+    pub expect_transition: Option<String>,
+}
+
+impl TestCase {
+    /// title returns the name of the case, or the request if not named.
+    ///
+    pub fn title(&self) -> String {
+        match &self.name {
+            Some(n) => n.clone(),
+            None => {
+                let mut res = self.operation.clone();
+                for a in self.attr_args() {
+                    res += &format!(" {}", a);
+                }
+                res
+            }
+        }
+    }
+
+    fn attr_args(&self) -> Vec<String> {
+        self.attrs
+            .iter()
+            .map(|(k, v)| match v {
+                toml::Value::String(s) => format!("{}={}", k, s),
+                v => format!("{}={}", k, v),
+            })
+            .collect()
+    }
+}
+
+/// CaseResult is the result of a test case. `failures` is empty for a passed
+/// case, and `result` is None if the request could not be evaluated.
+///
+#[derive(Clone, Debug)]
+pub struct CaseResult {
+    pub file: String,
+    pub title: String,
+    pub result: Option<CheckResult>,
+    pub failures: Vec<String>,
+}
+
+impl CaseResult {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// parse_test_file parses a test file in TOML.
+///
+pub fn parse_test_file(text: &str) -> Result<TestFile, String> {
+    toml::from_str(text).map_err(|e| e.to_string())
+}
+
+/// run_case evaluates the request of a test case with the policy and
+/// compares the result with the expectations.
+///
+pub fn run_case(acl: &Acl, file: &str, case: &TestCase) -> CaseResult {
+    let mut res = CaseResult {
+        file: file.to_string(),
+        title: case.title(),
+        result: None,
+        failures: vec![],
+    };
+    if case.expect != "allow" && case.expect != "deny" {
+        res.failures
+            .push(format!("expect must be allow or deny: {}", case.expect));
+        return res;
+    }
+    let req = match Request::parse(&case.operation, &case.attr_args()) {
+        Ok(r) => r,
+        Err(e) => {
+            res.failures.push(e);
+            return res;
+        }
+    };
+
+    let r = check_request(acl, &req);
+    if r.verdict.as_str() != case.expect {
+        res.failures.push(format!(
+            "expected {}, but got {}",
+            case.expect,
+            r.verdict.as_str()
+        ));
+    }
+    for (what, expected, actual) in [
+        ("rule", &case.expect_rule, &r.rule),
+        ("ACL", &case.expect_acl, &r.acl),
+        ("transition", &case.expect_transition, &r.transition),
+    ] {
+        if let Some(e) = expected {
+            if !matches_expectation(e, actual.as_deref()) {
+                res.failures.push(format!(
+                    "expected {} `{}`, but got `{}`",
+                    what,
+                    e,
+                    actual.as_deref().unwrap_or("none")
+                ));
+            }
+        }
+    }
+    res.result = Some(r);
+    res
+}
+
+fn matches_expectation(expected: &str, actual: Option<&str>) -> bool {
+    let normalize = |s: &str| s.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let actual = match actual {
+        Some(a) => normalize(a),
+        None => return false,
+    };
+    match expected.trim_end().strip_suffix("...") {
+        Some(prefix) => actual.starts_with(&normalize(prefix)),
+        None => actual == normalize(expected),
+    }
+}
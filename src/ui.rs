@@ -1,2 +1,4 @@
-pub mod query;
 pub mod audit;
+pub mod output;
+pub mod query;
+pub mod theme;
@@ -1,42 +1,51 @@
-use aclneko::syntax::AuditMatcher;
+use super::theme::{epaint, Style};
+use crate::policy::audit::{AuditField, AuditRecord, FieldKind};
 
 /// style_audit_message build a string for styled audit message.
 /// An input should be a valid line for audit log of caitsith.
+/// Fields are grouped by their categories, a line for each of the header,
+/// the request, `task.*`, `path.*`, `path.parent.*` and the transition,
+/// and painted with the `audit.*` styles of the palette.
 ///
-/// Any newline position is hardcoded at now.
+/// A message which cannot be parsed is returned as is.
 ///
 pub fn style_audit_message(x: String) -> String {
-    let mut res = String::new();
-    let mut word_count: usize;
-    let line_break = vec![4, 7, 13, 19, 22, 26, 30, 34, 38, 42, 46, 50];
-    let audit = AuditMatcher::new();
-    word_count = 0;
-    for s in x.split_whitespace() {
-        if line_break.contains(&word_count) {
-            res += "  \x1B[0m\n  ";
+    let record = match AuditRecord::parse(&x) {
+        Ok(r) => r,
+        Err(_) => return x + "\n",
+    };
+    let mut lines = vec![];
+    for kind in FieldKind::list() {
+        let mut words = vec![];
+        match kind {
+            FieldKind::Header => words.extend(record.time.as_ref().map(|t| format!("#{}#", t))),
+            FieldKind::Request => words.push(record.operation.clone()),
+            _ => {}
         }
-        if audit.is_uid(&s) {
-            res += "\x1B[33m";
-        } else if audit.is_task_exe(&s) {
-            res += "\x1B[31m";
-        } else if audit.is_task_ugid(&s) {
-            res += "\x1B[33m";
-        } else if audit.is_task_xpid(&s) {
-            res += "\x1B[34m";
-        } else if audit.is_task_domain(&s) {
-            res += "\x1B[31m";
-        } else if audit.is_path_single(&s) {
-            res += "\x1B[7m\x1B[33m";
-        } else if audit.is_path_ugid(&s) || audit.is_path_mod(&s) {
-            res += "\x1B[33m";
-        } else if audit.is_path_parent_ugid(&s) || audit.is_path_parent_mod(&s) {
-            res += "\x1B[33m";
-        } else if audit.is_transition(&s) {
-            res += "\x1B[5m\x1B[31m"
+        words.extend(record.fields_of(kind).map(style_field));
+        if !words.is_empty() {
+            lines.push(words.join(" "));
         }
-        res += s;
-        res += "\x1B[0m ";
-        word_count += 1;
     }
-    res + "\x1B[0m "
+    lines.join("\n  ") + "\n"
+}
+
+/// style_field paints a field with the style for its key.
+///
+fn style_field(f: &AuditField) -> String {
+    let style = match (f.kind, f.key.as_str()) {
+        (FieldKind::Task, "task.exe") => Some(Style::AuditExe),
+        (FieldKind::Task, "task.domain") => Some(Style::AuditDomain),
+        (FieldKind::Task, "task.pid" | "task.ppid") => Some(Style::AuditXpid),
+        (FieldKind::Task, k) if k.ends_with("uid") || k.ends_with("gid") => Some(Style::AuditUgid),
+        (FieldKind::Path | FieldKind::PathParent, _) => Some(Style::AuditPathAttr),
+        (FieldKind::Request, "uid") => Some(Style::AuditUid),
+        (FieldKind::Request, k) if k.ends_with("path") || k == "exec" => Some(Style::AuditPath),
+        (FieldKind::Transition, _) => Some(Style::AuditTransition),
+        _ => None,
+    };
+    match style {
+        Some(style) => epaint(style, f.text()),
+        None => f.text(),
+    }
 }
@@ -0,0 +1,34 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::json;
+
+/// OutputFormat is the format of the output for scripts. Text is the format
+/// for humans, which may be colored.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Yaml,
+}
+
+impl OutputFormat {
+    pub fn is_structured(&self) -> bool {
+        *self != OutputFormat::Text
+    }
+}
+
+/// print_structured prints a value as a line of JSON or a YAML document, so
+/// that values printed one after another can be read as a stream.
+///
+pub fn print_structured<T: Serialize>(format: OutputFormat, value: &T) -> Result<(), String> {
+    match format {
+        OutputFormat::Json => println!("{}", json!(value)),
+        OutputFormat::Yaml => print!(
+            "---\n{}",
+            serde_yaml::to_string(value).map_err(|e| e.to_string())?
+        ),
+        OutputFormat::Text => {}
+    }
+    Ok(())
+}
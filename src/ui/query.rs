@@ -1,28 +1,122 @@
 use super::audit::*;
+use super::output::{print_structured, OutputFormat};
+use super::theme::{epaint, paint, Style};
+use crate::clock::{format_timestamp, now};
+use crate::policy::audit::AuditRecord;
+use crate::policy::backend::{read_policy_path, PolicyBackend};
+use crate::policy::journal::{summarize, Journal, JournalAction, JournalEntry};
+use crate::policy::learn::header_for;
+use crate::policy::protocol::{parse_queries, PendingQuery};
+use crate::policy::responder::{Answer, Decision, Responder};
+use crate::policy::suggest::{preview, suggest_rules, Suggestion};
+use crate::policy::{sorted_blocks, BlockLines};
 use aclneko::acl::Acl;
-use aclneko::io::*;
 use aclneko::syntax::{AuditMatcher, Matcher, Op};
-use nix::poll::{self, PollTimeout};
-use std::fs::{File, OpenOptions};
-use std::io::prelude::{Read, Write};
+use nix::errno::Errno;
+use nix::poll::{self, PollFd, PollFlags, PollTimeout};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::termios::{self, LocalFlags, SetArg};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::c_int;
+use std::fs::OpenOptions;
+use std::io::prelude::Write;
+use std::io::{stderr, stdin, IsTerminal};
 use std::os::fd::AsFd;
-use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
 use std::str;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant};
 
-use console::Term;
+use console::{measure_text_width, Key, Term};
+
+mod tui;
+
+/// TICK is the interval to check the query interface and requests to stop
+/// the session while waiting.
+///
+const TICK: Duration = Duration::from_millis(200);
+
+/// STOP_SIGNAL is the number of the signal which requested to stop the
+/// session, or 0.
+///
+static STOP_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// COMMAND_PROMPT is the prompt for a command to handle a query.
+///
+const COMMAND_PROMPT: &str =
+    "command: (Y)es / (N)o / (A)dd / (R)etry / (S)how / (F)ilter / (O)pt-in / (Q)uit : ";
 
 /// Query represents a query request interface with its internal state.
 /// query id is not held on this struct and should be supplied for a method
 /// as an argument.
 ///
+/// If `answer_timeout` is given, a query without a command in the duration
+/// is answered with `on_timeout`. Queries left when the session stops are
+/// answered with `on_exit`.
+///
 pub struct Query {
     pub styled: bool,
-    pub query_interface: File,
-    pub policy_interface: File,
+    pub output: OutputFormat,
+    pub responder: Option<Responder>,
+    pub decision_log: Option<PathBuf>,
+    pub journal: Option<Journal>,
+    pub transcript: Option<PathBuf>,
+    pub answer_timeout: Option<Duration>,
+    pub on_timeout: Answer,
+    pub on_exit: Answer,
+    pub backend: Box<dyn PolicyBackend>,
     pub filter: regex::Regex,
     pub optin_filter: Vec<regex::Regex>,
     rule_addition_history: Vec<String>,
+    session: Vec<JournalEntry>,
+    stopped: Option<String>,
+}
+
+/// Violation is a policy violation notified by the query interface, with
+/// `key=value` fields of the audit message. The operation is the word after
+/// ` / ` in the message.
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct Violation {
+    pub id: String,
+    pub retry: u32,
+    pub operation: Option<String>,
+    pub attrs: BTreeMap<String, String>,
+    pub message: String,
+}
+
+impl Violation {
+    pub fn parse(id: &str, message: &str) -> Self {
+        let record = AuditRecord::parse(message).ok();
+        Violation {
+            id: id.to_string(),
+            retry: 0,
+            operation: record.as_ref().map(|r| r.operation.clone()),
+            attrs: record.map(|r| r.attrs()).unwrap_or_default(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Violation {
+    pub fn from_query(q: &PendingQuery) -> Self {
+        Violation {
+            retry: q.retry,
+            ..Violation::parse(&q.id, &q.message)
+        }
+    }
+}
+
+/// DecisionLog is a line of the decision log of the responder.
+///
+#[derive(Serialize)]
+struct DecisionLog<'a> {
+    time: String,
+    #[serde(flatten)]
+    decision: &'a Decision,
+    violation: &'a Violation,
 }
 
 // query internal
@@ -30,19 +124,7 @@ impl Query {
     /// query_policy_violation wait for policy violations and supply interactive
     /// treatment for them.
     ///
-    pub fn new(filter_pattern: &str) -> Result<Query, String> {
-        let query_interface = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(QUERY_INTERFACE_PATH)
-            .map_err(|e| e.to_string())?;
-
-        let policy_interface = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(POLICY_INTERFACE_PATH)
-            .map_err(|e| e.to_string())?;
-
+    pub fn new(backend: Box<dyn PolicyBackend>, filter_pattern: &str) -> Result<Query, String> {
         let mut operations = String::from("^(");
         for o in Op::list() {
             if operations.len() > 2 {
@@ -54,38 +136,45 @@ impl Query {
 
         let rule_addition_history: Vec<String> = vec![String::new(); 100];
         let filter = regex::Regex::new(filter_pattern).map_err(|e| e.to_string())?;
-        let optin_filter = vec![regex::Regex::new("$$^^").unwrap(); 10];
+        let optin_filter = vec![];
         Ok(Query {
             styled: true,
-            query_interface,
-            policy_interface,
+            output: OutputFormat::Text,
+            responder: None,
+            decision_log: None,
+            journal: None,
+            transcript: None,
+            answer_timeout: None,
+            on_timeout: Answer::Deny,
+            on_exit: Answer::Deny,
+            backend,
             filter,
             optin_filter,
             rule_addition_history,
+            session: vec![],
+            stopped: None,
         })
     }
 
     //deny simply denies policy violation on demand.
     //
     fn deny(&mut self, query_id: &str) {
-        let ans = format!("A{}=2\n", query_id);
-        _ = self.query_interface.write(ans.as_bytes());
-        eprintln!("\x1B[7m\x1B[33mrejected\x1B[0m\n");
+        _ = self.backend.answer_query(query_id, 2);
+        eprintln!("{}\n", epaint(Style::Rejected, "rejected"));
     }
 
     //show_query shows the ACL block which is related to the policy violation,
     //which violate at least one rule with a `deny` operand in the ACL block.
     //
     fn show_query(&mut self, query_id: &str) {
-        let mut buf = vec![];
-        let query = format!("Q={}\n", query_id);
-        _ = self.policy_interface.write(query.as_bytes());
-        _ = self.policy_interface.read_to_end(&mut buf);
-        eprintln!("\n---------------------\n\x1B[47m\x1B[30m[selected policy]\x1B[0m");
-        let msg = String::from_utf8(buf).unwrap();
+        let msg = self.backend.select_query(query_id).unwrap_or_default();
+        eprintln!(
+            "\n---------------------\n{}",
+            epaint(Style::Selected, "[selected policy]")
+        );
         for s in msg.split("\n") {
             if !s.starts_with("#") && !s.is_empty() {
-                eprintln!("\x1B[32m{}\x1B[0m", s);
+                eprintln!("{}", epaint(Style::Added, s));
             }
         }
         eprintln!("---------------------");
@@ -94,9 +183,8 @@ impl Query {
     //permit temporary permits pending query for a policy violation.
     //
     fn permit(&mut self, query_id: &str) {
-        let ans = format!("A{}=1\n", query_id);
-        _ = self.query_interface.write(ans.as_bytes());
-        eprintln!("y: \x1B[42m\x1B[30mpermit\x1B[0m");
+        _ = self.backend.answer_query(query_id, 1);
+        eprintln!("y: {}", epaint(Style::Permitted, "permit"));
         eprintln!();
     }
 
@@ -104,9 +192,8 @@ impl Query {
     //It is useful for policy patching from the other process.
     //
     fn reevaluate(&mut self, query_id: &str) {
-        let ans = format!("A{}=3\n", query_id);
-        _ = self.query_interface.write(ans.as_bytes());
-        eprintln!("r: \x1B[44m\x1B[30mre-evaluate\x1B[0m");
+        _ = self.backend.answer_query(query_id, 3);
+        eprintln!("r: {}", epaint(Style::Reevaluated, "re-evaluate"));
         eprintln!();
     }
 
@@ -119,17 +206,16 @@ impl Query {
     fn reset_filter(&mut self) {
         eprint!("\nfilter pattern: ");
         let term = Term::stdout();
-        loop {
-            if let Ok(n) = term.read_line_initial_text(self.filter.as_str()) {
-                match regex::Regex::new(n.as_str()) {
-                    Ok(p) => {
-                        self.filter = p;
-                        self.optin_filter.clear();
-                        break;
-                    }
-                    Err(e) => {
-                        eprintln!("{}", e);
-                    }
+        // read errors such as ^C leave the filter unchanged
+        while let Ok(n) = term.read_line_initial_text(self.filter.as_str()) {
+            match regex::Regex::new(n.as_str()) {
+                Ok(p) => {
+                    self.filter = p;
+                    self.optin_filter.clear();
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
                 }
             }
         }
@@ -146,101 +232,236 @@ impl Query {
     fn add_optin_filter(&mut self) {
         eprint!("\nopt-in query pattern: ");
         let term = Term::stdout();
-        loop {
-            if let Ok(n) = term.read_line_initial_text(self.filter.as_str()) {
-                match regex::Regex::new(n.as_str()) {
-                    Ok(p) => {
-                        self.optin_filter.push(p);
+        while let Ok(n) = term.read_line_initial_text(self.filter.as_str()) {
+            match regex::Regex::new(n.as_str()) {
+                Ok(p) => {
+                    self.optin_filter.push(p);
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+        //eprintln!("\n{}", audit_message);
+    }
+
+    //is_targeted returns true if the message is matched for the filter or
+    //any of the opt-in filters. The message is the audit line as read from
+    //the query interface, without styles, in both the line mode and the TUI.
+    //
+    fn is_targeted(&self, message: &str) -> bool {
+        self.filter.is_match(message) || self.optin_filter.iter().any(|f| f.is_match(message))
+    }
+
+    //default_rule_text returns the priority and the operand of the last rule
+    //added, such as `10 allow`, to start a rule typed by the operator.
+    //
+    fn default_rule_text(&self) -> String {
+        match self.rule_addition_history.last() {
+            Some(l) => {
+                let mut i = 0;
+                let mut res = String::new();
+                for w in l.split_whitespace() {
+                    i += 1;
+                    if i > 1 {
+                        res += " ";
+                        res += w;
+                        res += " ";
                         break;
+                    } else {
+                        res += w;
                     }
-                    Err(e) => {
-                        eprintln!("{}", e);
+                }
+                res
+            }
+            _ => String::new(),
+        }
+    }
+
+    //suggest returns the header of the ACL block for the violation, the
+    //block in the related ACL, and rules suggested from the audit record.
+    //String groups are looked up in the current policy.
+    //
+    fn suggest(&mut self, v: &Violation) -> (String, Option<BlockLines>, Vec<Suggestion>) {
+        let buf = self.backend.select_query(&v.id).unwrap_or_default();
+        let selected = Acl::from_str(&buf).unwrap_or(Acl::new());
+        let op = v.operation.as_deref().unwrap_or_default();
+        let header = header_for(&[&selected], op, &v.attrs);
+        let block = sorted_blocks(&selected)
+            .into_iter()
+            .find(|b| b.header == header);
+        let policy = self.backend.read_policy().unwrap_or(Acl::new());
+        let suggestions = suggest_rules(&policy, block.as_ref(), &v.attrs);
+        (header, block, suggestions)
+    }
+
+    //select_suggestion shows the suggested rules and returns the one
+    //selected with its number, with the preview of the ACL block. A number
+    //is selected when no more digits can follow it, or with Enter. 0 or
+    //Enter without a number selects none, and it returns None for Escape.
+    //
+    fn select_suggestion(
+        &self,
+        header: &str,
+        block: Option<&BlockLines>,
+        suggestions: &[Suggestion],
+    ) -> Option<Option<String>> {
+        eprintln!("\n\n{}", epaint(Style::Selected, "[suggested rules]"));
+        for (i, s) in suggestions.iter().enumerate() {
+            eprintln!("  {}) {:<10} {}", i + 1, s.scope, s.rule);
+        }
+        eprint!(
+            "select a rule [1-{}] (0 or Enter to type a rule): ",
+            suggestions.len()
+        );
+        let term = Term::stdout();
+        let mut typed = String::new();
+        let selected = loop {
+            match term.read_key() {
+                Ok(Key::Enter) if typed.is_empty() => {
+                    eprintln!();
+                    return Some(None);
+                }
+                Ok(Key::Char('0')) if typed.is_empty() => {
+                    eprintln!();
+                    return Some(None);
+                }
+                Ok(Key::Enter) => break &suggestions[typed.parse::<usize>().unwrap() - 1],
+                Ok(Key::Backspace) => {
+                    if typed.pop().is_some() {
+                        eprint!("\x08 \x08");
+                    }
+                }
+                Ok(Key::Char(c)) if c.is_ascii_digit() => {
+                    let n: usize = format!("{}{}", typed, c).parse().unwrap_or(usize::MAX);
+                    if n > suggestions.len() {
+                        continue;
+                    }
+                    typed.push(c);
+                    eprint!("{}", c);
+                    if n * 10 > suggestions.len() {
+                        break &suggestions[n - 1];
                     }
                 }
+                Ok(Key::Escape) | Err(_) => {
+                    eprintln!();
+                    return None;
+                }
+                Ok(_) => {}
+            }
+        };
+        eprintln!(" {}\n", selected.scope);
+        for l in preview(header, block, &selected.rule) {
+            match l.chars().next() {
+                Some('+') => eprintln!("{}", epaint(Style::Added, l)),
+                Some('!') => eprintln!("{}", epaint(Style::Warning, l)),
+                _ => eprintln!("{}", l),
             }
         }
-        //eprintln!("\n{}", audit_message);
+        Some(Some(selected.rule.clone()))
     }
 
     //add_new_rule appends a rule line into the ACL block violated.
     //Any rule line should have valid rule syntax which is composed of
     //priority(uint), operation(allow/deny) and attributes(key=val)
     //
+    //Rules suggested from the audit record are shown with numbers, and the
+    //prompt is pre-filled with the rule selected, or with the priority and
+    //the operand of the last rule added.
+    //
     //Invalid lines are ignored without any messages.
+    //It returns the ACL header and the rule line added.
     //
-    fn add_new_rule(&mut self, query_id: &str) {
-        let term = Term::stdout();
-        let acl_parser = Matcher::new();
-        let query = format!("Q={}\n", query_id);
-        _ = self.policy_interface.write(query.as_bytes());
-        let mut buf = vec![];
-        _ = self.policy_interface.read_to_end(&mut buf);
-
-        eprint!("\nenter a new rule: ");
-        loop {
-            let default_text = match self.rule_addition_history.last() {
-                Some(l) => {
-                    let mut i = 0;
-                    let mut res = String::new();
-                    for w in l.split_whitespace() {
-                        i += 1;
-                        if i > 1 {
-                            res += " ";
-                            res += w;
-                            break;
-                        } else {
-                            res += w;
-                        }
-                    }
-                    res
-                }
-                _ => String::new(),
-            };
+    fn add_new_rule(&mut self, v: &Violation) -> Option<(String, String)> {
+        let (header, block, suggestions) = self.suggest(v);
+        let mut initial = self.default_rule_text();
+        if !suggestions.is_empty() {
+            if let Some(rule) = self.select_suggestion(&header, block.as_ref(), &suggestions)? {
+                initial = rule;
+            }
+        }
 
-            if let Ok(n) = term.read_line() {
-                self.rule_addition_history.push(n.clone());
-                let n = String::from(" ") + n.as_str() + "\n";
-                if acl_parser.is_acl_rule(&n) {
-                    let audit = AuditMatcher::new();
-                    if audit.is_ip(&n) || audit.is_port(&n) {
-                        _ = self.policy_interface.write(query.as_bytes());
-                        let buf = &str::from_utf8(&buf).unwrap();
-                        //debug
-                        //eprintln!("\n\x1B[33mrawbuf\x1B[0m\n{}",buf);
-                        let acl = Acl::from_str(buf).unwrap();
-                        let h = acl.parse_acl_headers();
-                        if h.len() == 0 {
-                            eprintln!("ACL header for the violation not detected");
-                            break;
-                        } else if !h[0].op.is_net() {
-                            eprintln!("ACL header is not inet ACL");
-                            break;
-                        }
+        eprintln!();
+        let n = edit_line("enter a new rule: ", &initial)?;
+        self.rule_addition_history.push(n.clone());
+        match self.apply_rule(v, &n) {
+            Ok(header) => {
+                eprintln!(" {}", epaint(Style::Permitted, "added"));
+                Some((header, n.trim().to_string()))
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        }
+    }
 
-                        let v = format!("{}\n  {}", h[0], n);
-                        if let Ok(new_acl_line) = Acl::from_str(v.as_str()) {
-                            //debug
-                            //eprintln!("\n\x1B[33mnewacl\x1B[0m\n{:?}",new_acl_line);
-                            match apply_acl(new_acl_line) {
-                                Err(e) => {
-                                    eprintln!("{}", e);
-                                    continue;
-                                }
-                                _ => _ = term.write_line(" \x1B[42m\x1B[30madded\x1B[0m"),
-                            }
-                        } else {
-                            eprintln!("invalid acl line input");
-                            continue;
-                        }
-                    } else {
-                        _ = self.policy_interface.write(n.as_bytes());
-                        _ = term.write_line(" \x1B[42m\x1B[30madded\x1B[0m");
-                    }
-                } else {
-                    eprintln!("invalid rule syntax");
-                }
+    //apply_rule appends a rule line into the ACL block violated and returns
+    //the ACL header. A rule with `ip` or `port` is applied with the header of
+    //the inet ACL, and others are added via the query interface.
+    //
+    fn apply_rule(&mut self, v: &Violation, line: &str) -> Result<String, String> {
+        let acl_parser = Matcher::new();
+        let buf = self.backend.select_query(&v.id).unwrap_or_default();
+        let n = String::from(" ") + line.trim() + "\n";
+        if !acl_parser.is_acl_rule(&n) {
+            return Err(String::from("invalid rule syntax"));
+        }
+        let audit = AuditMatcher::new();
+        if audit.is_ip(&n) || audit.is_port(&n) {
+            let acl = Acl::from_str(&buf)?;
+            let h = acl.parse_acl_headers();
+            if h.is_empty() {
+                return Err(String::from("ACL header for the violation not detected"));
+            } else if !h[0].op.is_net() {
+                return Err(String::from("ACL header is not inet ACL"));
             }
-            break;
+            let new_acl_line = Acl::from_str(&format!("{}\n  {}", h[0], n))
+                .map_err(|_| String::from("invalid acl line input"))?;
+            self.backend.apply_acl(&new_acl_line)?;
+            Ok(h[0].to_string())
+        } else {
+            self.backend.add_query_rule(&v.id, &n)?;
+            let selected = Acl::from_str(&buf).unwrap_or(Acl::new());
+            let op = v.operation.as_deref().unwrap_or_default();
+            Ok(header_for(&[&selected], op, &v.attrs))
+        }
+    }
+
+    //journal appends the action for the violation to the query journal and
+    //to the entries of the session. `added` is the ACL header and the rule
+    //line added, and `by` is what decided the action without the operator.
+    //
+    fn journal(
+        &mut self,
+        v: &Violation,
+        action: JournalAction,
+        added: Option<(String, String)>,
+        by: Option<String>,
+    ) -> Result<(), String> {
+        let (header, rule) = added.unzip();
+        let entry = JournalEntry {
+            time: format_timestamp(now()),
+            query_id: v.id.clone(),
+            record: v.message.clone(),
+            operation: v.operation.clone(),
+            action,
+            header,
+            rule,
+            operator: self
+                .journal
+                .as_ref()
+                .map(|j| j.operator.clone())
+                .unwrap_or_default(),
+            by,
+        };
+        // the query is answered already, so it is counted even if the
+        // journal cannot be written
+        self.session.push(entry);
+        match &self.journal {
+            Some(j) => j.append(self.session.last().unwrap()),
+            None => Ok(()),
         }
     }
 
@@ -249,22 +470,23 @@ impl Query {
     //
     //Any user-defined patches are assumed to be in /etc/caitsith/patch.
     //
-    fn select_applied_patch(&self) {
+    #[allow(dead_code)]
+    fn select_applied_patch(&mut self) {
         let term = Term::stdout();
         _ = term.write_line("");
-        _ = list_registered_patches();
+        _ = self.backend.list_registered_patches();
         _ = term.write_line("");
         _ = write!(&term, "patch to apply: ");
         if let Ok(f) = term.read_line() {
-            let patch = match read_policy_file(format!("{}/{}", PATCH_DIR, f).as_str()) {
+            let patch = match read_policy_path(&self.backend.patch_dir().join(f)) {
                 Ok(f) => f,
                 Err(e) => {
                     eprintln!("{}", e);
                     return;
                 }
             };
-            match apply_acl(patch) {
-                Ok(_) => _ = term.write_line("\x1B[42m\x1B[30mapplied\x1B[0m"),
+            match self.backend.apply_acl(&patch) {
+                Ok(_) => _ = term.write_line(&paint(Style::Permitted, "applied")),
                 Err(e) => {
                     eprintln!("{}", e)
                 }
@@ -277,134 +499,508 @@ impl Query {
     //
     // Any patches are assumed to be in /etc/caitsith/patch.
     //
-    fn select_removed_patch(&self) {
+    #[allow(dead_code)]
+    fn select_removed_patch(&mut self) {
         let term = Term::stdout();
         _ = term.write_line("");
-        _ = list_registered_patches();
+        _ = self.backend.list_registered_patches();
         _ = term.write_line("");
         _ = write!(&term, "patch to unmerge: ");
         if let Ok(f) = term.read_line() {
-            let patch = match read_policy_file(format!("{}/{}", PATCH_DIR, f).as_str()) {
+            let patch = match read_policy_path(&self.backend.patch_dir().join(f)) {
                 Ok(f) => f,
                 Err(e) => {
                     eprintln!("{}", e);
                     return;
                 }
             };
-            match unmerge_acl(&patch, &read_policy_file(POLICY_INTERFACE_PATH).unwrap()) {
-                Ok(_) => _ = term.write_line("\x1B[42m\x1B[30mremoved\x1B[0m"),
+            let current = match self.backend.read_policy() {
+                Ok(a) => a,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            };
+            match self.backend.unmerge_acl(&patch, &current) {
+                Ok(_) => _ = term.write_line(&paint(Style::Permitted, "removed")),
                 Err(e) => eprintln!("{}", e),
             }
         }
     }
 
+    //respond answers the policy violation with the responder instead of
+    //waiting for a command, and appends the decision to the decision log.
+    //
+    fn respond(&mut self, v: &Violation) -> Result<(), String> {
+        let responder = match self.responder.as_mut() {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let d = responder.decide(&v.message, v.operation.as_deref(), &v.attrs, now());
+        self.backend.answer_query(&v.id, d.answer.code())?;
+        eprintln!(
+            "{} by {}{}\n",
+            epaint(answer_style(d.answer), d.answer.as_str()),
+            d.rule.as_deref().unwrap_or("default"),
+            if d.rate_limited {
+                " (rate limited)"
+            } else {
+                ""
+            }
+        );
+        if let Some(path) = &self.decision_log {
+            let line = serde_json::to_string(&DecisionLog {
+                time: format_timestamp(now()),
+                decision: &d,
+                violation: v,
+            })
+            .map_err(|e| e.to_string())?;
+            let mut fp = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            writeln!(fp, "{}", line).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        let by = format!("rules: {}", d.rule.as_deref().unwrap_or("default"));
+        self.journal(v, d.answer.into(), None, Some(by))
+    }
+
     //wait_command_key provides command interface for policy violation handling.
     //
-    fn wait_command_key(&mut self, query_id: &str) -> Result<(), String> {
+    //Answers and rules added are appended to the query journal.
+    //
+    //If the answer timeout is given, the seconds left are shown after the
+    //prompt and the query is answered with the verdict for timeout when no
+    //command is given in time.
+    //
+    //It returns true if the query is answered. The quit command, SIGINT and
+    //SIGTERM stop the session without answering.
+    //
+    fn wait_command_key(&mut self, v: &Violation) -> Result<bool, String> {
         let term = Term::stdout();
+        let query_id = v.id.as_str();
+        let deadline = self.answer_timeout.map(|t| Instant::now() + t);
+        let mut shown = None;
         loop {
+            if self.stop_requested() {
+                eprintln!();
+                return Ok(false);
+            }
+            let wait = match deadline {
+                Some(d) => {
+                    let left = d.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        self.time_out(v)?;
+                        return Ok(true);
+                    }
+                    let secs = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+                    if shown != Some(secs) {
+                        self.show_countdown(secs, shown.is_none());
+                        shown = Some(secs);
+                    }
+                    // wake up when the seconds left change
+                    left - Duration::from_secs(secs - 1)
+                }
+                None => TICK,
+            };
+            if !poll_stdin(wait)? {
+                continue;
+            }
             if let Ok(c) = term.read_char() {
                 match c {
                     's' => self.show_query(query_id),
-                    'y' | 'Y' => self.permit(query_id),
-                    'n' | 'N' => self.deny(query_id),
-                    'r' | 'R' => self.reevaluate(query_id),
+                    'y' | 'Y' => {
+                        self.permit(query_id);
+                        self.journal(v, JournalAction::Permit, None, None)?;
+                        return Ok(true);
+                    }
+                    'n' | 'N' => {
+                        self.deny(query_id);
+                        self.journal(v, JournalAction::Deny, None, None)?;
+                        return Ok(true);
+                    }
+                    'r' | 'R' => {
+                        self.reevaluate(query_id);
+                        self.journal(v, JournalAction::Reevaluate, None, None)?;
+                        return Ok(true);
+                    }
                     'f' | 'F' => self.reset_filter(),
                     'o' | 'O' => self.add_optin_filter(),
                     'q' | 'Q' => {
                         eprintln!();
-                        std::process::exit(0);
+                        self.stopped = Some(String::from("quit"));
+                    }
+                    'a' | 'A' => {
+                        if let Some(added) = self.add_new_rule(v) {
+                            self.journal(v, JournalAction::Add, Some(added), None)?;
+                        }
                     }
-                    'a' | 'A' => self.add_new_rule(query_id),
                     _ => continue,
                 }
-                break;
+                return Ok(false);
             }
         }
-        Ok(())
+    }
+
+    //show_countdown shows the seconds left to answer after the command
+    //prompt. On terminals, the countdown is rewritten in place.
+    //
+    fn show_countdown(&self, secs: u64, first: bool) {
+        let text = epaint(
+            Style::Notice,
+            format!("[{} in {}s]", self.on_timeout.as_str(), secs),
+        );
+        match (first, stderr().is_terminal()) {
+            (true, true) => eprint!("\x1B7{} ", text),
+            (true, false) => eprint!("{} ", text),
+            (false, true) => eprint!("\x1B8\x1B[K{} ", text),
+            (false, false) => {}
+        }
+    }
+
+    //time_out answers the query with the verdict for timeout, and journals
+    //it with the timeout.
+    //
+    fn time_out(&mut self, v: &Violation) -> Result<(), String> {
+        let answer = self.on_timeout;
+        self.backend.answer_query(&v.id, answer.code())?;
+        eprintln!(
+            "{}: {}\n",
+            epaint(Style::Notice, "timed out"),
+            epaint(answer_style(answer), answer.as_str())
+        );
+        let secs = self.answer_timeout.unwrap_or_default().as_secs();
+        self.journal(v, answer.into(), None, Some(format!("timeout: {}s", secs)))
     }
 
     //listen_policy_violation wait for a new policy violation and show query
     //information to handle it interactively.
     //
-    //To terminate the process, use `quit` command in the interactive policy
-    //violation handling.
+    //The session stops with `quit` command in the interactive policy
+    //violation handling, SIGINT or SIGTERM, and queries left are answered
+    //with the verdict for exit.
     pub fn listen_policy_violation(&mut self) -> Result<(), String> {
-        let qi_readonly = self
-            .query_interface
-            .try_clone()
-            .map_err(|e| e.to_string())?;
-        let pfd = poll::PollFd::new(qi_readonly.as_fd(), poll::PollFlags::POLLIN);
-        let query_output_pattern = regex::Regex::new(r"^Q(?P<id>\d+)(-\d+)?").unwrap();
-
+        catch_signals()?;
         eprintln!("monitoring policy violation...");
-        let pfd_ref = &mut [pfd];
 
-        loop {
-            // poll eternally
-            poll::poll(pfd_ref, PollTimeout::MAX).map_err(|e| e.to_string())?;
-            let mut buf = vec![];
-            let mut qbuf = vec![];
-
-            //do query
-            self.query_interface
-                .read_to_end(&mut buf)
-                .map_err(|e| e.to_string())?;
-            let mut i = 0;
-            for s in String::from_utf8(buf.clone()).unwrap().split("\n") {
-                if i % 2 == 1 {
-                    buf = s.into();
-                    break;
-                } else {
-                    qbuf = match query_output_pattern.captures(s) {
-                        Some(p) => p["id"].into(),
-                        None => {
-                            //display audit announcement
-                            eprintln!("{}", &s);
-                            continue;
+        let mut left = vec![];
+        let res = self.listen_loop(&mut left);
+        self.finish(left, res)
+    }
+
+    //listen_loop handles queries until the session is requested to stop.
+    //The violations of the batch not answered yet are kept in left, so that
+    //they are answered when the loop stops or fails.
+    //
+    fn listen_loop(&mut self, left: &mut Vec<Violation>) -> Result<(), String> {
+        while !self.stop_requested() {
+            let text = match self.backend.poll_query(TICK)? {
+                Some(t) => t,
+                None => continue,
+            };
+            let batch = parse_queries(&text);
+            *left = batch.queries.iter().map(Violation::from_query).collect();
+            self.record(&text)?;
+            //display audit announcement
+            for n in &batch.notices {
+                eprintln!("{}", n);
+            }
+            for q in &batch.queries {
+                let journaled = self.session.len();
+                let answered = match self.handle_query(q) {
+                    Ok(a) => a,
+                    Err(e) => {
+                        // a query is journaled only after it is answered
+                        if self.session.len() > journaled {
+                            left.remove(0);
                         }
+                        return Err(e);
                     }
+                };
+                let stop = self.stop_requested();
+                if answered || !stop {
+                    left.remove(0);
+                }
+                if stop {
+                    return Ok(());
                 }
-                i += 1;
             }
+        }
+        Ok(())
+    }
 
-            //extract query_id
-            let query_id = str::from_utf8(&qbuf).unwrap();
-            let mut audit_message = String::from_utf8(buf.clone()).unwrap();
-            if self.styled {
-                audit_message = style_audit_message(audit_message);
-            } else {
-                audit_message += "\n";
-            }
+    //finish ends the session whether it stopped or failed: the violations
+    //left are answered with the verdict for exit and the session summary is
+    //printed. The error of the session is returned first, if any.
+    //
+    fn finish(&mut self, left: Vec<Violation>, res: Result<(), String>) -> Result<(), String> {
+        if res.is_err() && self.stopped.is_none() {
+            self.stopped = Some(String::from("error"));
+        }
+        let drained = self.drain(left);
+        let summary = self.print_summary();
+        res.and(drained).and(summary)
+    }
 
-            eprintln!("qseq: {}", query_id);
-            eprintln!("{}", audit_message);
+    //stop_requested returns true if the session is requested to stop with
+    //the quit command or a signal.
+    //
+    fn stop_requested(&mut self) -> bool {
+        let sig = STOP_SIGNAL.swap(0, Ordering::SeqCst);
+        if self.stopped.is_none() && sig != 0 {
+            self.stopped = Some(match Signal::try_from(sig) {
+                Ok(s) => s.as_str().to_string(),
+                Err(_) => sig.to_string(),
+            });
+        }
+        self.stopped.is_some()
+    }
+
+    //drain answers the violations left and the queries still pending in the
+    //query interface with the verdict for exit, when the session stops.
+    //
+    fn drain(&mut self, mut left: Vec<Violation>) -> Result<(), String> {
+        let reason = self.stopped.clone().unwrap_or(String::from("quit"));
+        if let Some(text) = self.backend.poll_query(Duration::ZERO)? {
+            self.record(&text)?;
+            for q in parse_queries(&text).queries {
+                if !left.iter().any(|v| v.id == q.id) {
+                    left.push(Violation::from_query(&q));
+                }
+            }
+        }
+        if left.is_empty() {
+            return Ok(());
+        }
+        let answer = self.on_exit;
+        eprintln!(
+            "{}: answering {} pending queries with {}",
+            epaint(Style::Notice, &reason),
+            left.len(),
+            epaint(answer_style(answer), answer.as_str())
+        );
+        let mut res = Ok(());
+        for v in &left {
+            if let Err(e) = self.backend.answer_query(&v.id, answer.code()) {
+                eprintln!("Q{}: {}", v.id, e);
+                continue;
+            }
+            let journaled = self.journal(
+                v,
+                answer.into(),
+                None,
+                Some(format!("shutdown: {}", reason)),
+            );
+            // keep answering the rest even if the journal cannot be written
+            res = res.and(journaled);
+        }
+        res
+    }
 
-            eprint!(
-                "command: (Y)es / (N)o / (A)dd / (R)etry / (S)how / (F)ilter / (O)pt-in / (Q)uit : "
+    //print_summary prints the numbers of queries and actions in the
+    //session, by what decided them.
+    //
+    fn print_summary(&self) -> Result<(), String> {
+        let summary = summarize(&self.session);
+        if self.output.is_structured() {
+            return print_structured(self.output, &summary);
+        }
+        eprintln!(
+            "\n{} {} queries",
+            epaint(Style::Bold, "session summary:"),
+            summary.queries
+        );
+        for (action, by) in &summary.actions {
+            let detail: Vec<String> = by.iter().map(|(k, n)| format!("{} {}", k, n)).collect();
+            eprintln!(
+                "  {:<10} {:>4}  ({})",
+                action.as_str(),
+                by.values().sum::<usize>(),
+                detail.join(", ")
             );
+        }
+        Ok(())
+    }
 
-            let m2 = audit_message.clone();
+    //handle_query shows a pending query and answers it with the responder,
+    //a command, or automatically if it is unmatched for the filter. It
+    //returns true if the query is answered.
+    //
+    fn handle_query(&mut self, q: &PendingQuery) -> Result<bool, String> {
+        let violation = Violation::from_query(q);
+        if self.output.is_structured() {
+            print_structured(self.output, &violation)?;
+        }
+        let audit_message = match self.styled {
+            true => style_audit_message(q.message.clone()),
+            false => q.message.clone() + "\n",
+        };
 
-            if self.filter.is_match(&audit_message)
-                || || -> bool {
-                    for i in 0..self.optin_filter.len() {
-                        if self.optin_filter[i].is_match(&m2) {
-                            return true;
-                        }
-                    }
-                    false
-                }()
-            {
-                match self.wait_command_key(query_id) {
-                    Ok(_) => continue,
-                    Err(_) => break,
-                };
-            } else {
-                eprint!("\n\x1B[7m\x1B[33mautomatically ");
-                self.deny(query_id);
-            };
+        match q.retry {
+            0 => eprintln!("qseq: {}", q.id),
+            n => eprintln!("qseq: {} (retry {})", q.id, n),
         }
-        Ok(())
+        eprintln!("{}", audit_message);
+
+        if self.responder.is_some() {
+            self.respond(&violation)?;
+            return Ok(true);
+        }
+
+        eprint!("{}", COMMAND_PROMPT);
+
+        if self.is_targeted(&q.message) {
+            self.wait_command_key(&violation)
+        } else {
+            eprint!("\n{}", epaint(Style::Rejected, "automatically "));
+            self.deny(&q.id);
+            self.journal(
+                &violation,
+                JournalAction::Deny,
+                None,
+                Some(String::from("filter")),
+            )?;
+            Ok(true)
+        }
+    }
+
+    //record appends text read from the query interface to the transcript,
+    //which can be parsed again with `query --transcript`.
+    //
+    fn record(&self, text: &str) -> Result<(), String> {
+        let path = match &self.transcript {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let mut fp = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        fp.write_all(text.as_bytes())
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+/// answer_style returns the style to show the answer.
+///
+fn answer_style(answer: Answer) -> Style {
+    match answer {
+        Answer::Permit => Style::Permitted,
+        Answer::Deny => Style::Rejected,
+        Answer::Reevaluate => Style::Reevaluated,
+    }
+}
+
+/// edit_line reads a line from the terminal after the prompt, starting with
+/// the text which can be edited. The line is redrawn for every key, so that
+/// it can be edited across wrapped rows. The cursor is moved with the arrow
+/// keys, Home and End, and Escape cancels the input.
+///
+fn edit_line(prompt: &str, initial: &str) -> Option<String> {
+    let term = Term::stdout();
+    let cols = (Term::stderr().size().1 as usize).max(1);
+    let mut buf: Vec<char> = initial.chars().collect();
+    let mut pos = buf.len();
+    let mut rows = 0;
+    loop {
+        if rows > 0 {
+            eprint!("\x1B[{}A", rows);
+        }
+        let text = format!("{}{}", prompt, buf.iter().collect::<String>());
+        eprint!("\r\x1B[J{}", text);
+        // the last row of the text, where the cursor stays in the last
+        // column when the text fills the row
+        let last = measure_text_width(&text).saturating_sub(1) / cols;
+        rows = last;
+        if pos < buf.len() {
+            // move the cursor back onto the character at pos
+            let head = format!("{}{}", prompt, buf[..pos].iter().collect::<String>());
+            let width = measure_text_width(&head);
+            rows = width / cols;
+            if last > rows {
+                eprint!("\x1B[{}A", last - rows);
+            }
+            eprint!("\r");
+            let col = width % cols;
+            if col > 0 {
+                eprint!("\x1B[{}C", col);
+            }
+        }
+        let key = term.read_key();
+        if matches!(key, Ok(Key::Enter | Key::Escape) | Err(_)) && last > rows {
+            eprint!("\x1B[{}B", last - rows);
+        }
+        match key {
+            Ok(Key::Char(c)) if !c.is_control() => {
+                buf.insert(pos, c);
+                pos += 1;
+            }
+            Ok(Key::Backspace) if pos > 0 => {
+                pos -= 1;
+                buf.remove(pos);
+            }
+            Ok(Key::Del) if pos < buf.len() => _ = buf.remove(pos),
+            Ok(Key::ArrowLeft) => pos = pos.saturating_sub(1),
+            Ok(Key::ArrowRight) => pos = (pos + 1).min(buf.len()),
+            Ok(Key::Home) => pos = 0,
+            Ok(Key::End) => pos = buf.len(),
+            Ok(Key::Enter) => {
+                eprintln!();
+                return Some(buf.into_iter().collect());
+            }
+            Ok(Key::Escape) | Err(_) => {
+                eprintln!();
+                return None;
+            }
+            Ok(_) => {}
+        }
+    }
+}
+
+/// catch_signals installs handlers of SIGINT and SIGTERM which request to
+/// stop the session. Blocking calls are interrupted without restarting, so
+/// that the request is handled without waiting for input.
+///
+fn catch_signals() -> Result<(), String> {
+    extern "C" fn request_stop(sig: c_int) {
+        STOP_SIGNAL.store(sig, Ordering::SeqCst);
+    }
+    let action = SigAction::new(
+        SigHandler::Handler(request_stop),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for sig in [Signal::SIGINT, Signal::SIGTERM] {
+        // SAFETY: the handler only stores the signal number to an atomic
+        unsafe { signal::sigaction(sig, &action) }.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// poll_stdin waits for input from the terminal up to the timeout, and
+/// returns true if input is available. Line buffering and echo are disabled
+/// while waiting, so that a key is available without Enter. A wait
+/// interrupted by a signal returns false.
+///
+fn poll_stdin(timeout: Duration) -> Result<bool, String> {
+    let input = stdin();
+    let original = termios::tcgetattr(&input).ok();
+    if let Some(o) = &original {
+        let mut t = o.clone();
+        t.local_flags.remove(LocalFlags::ICANON | LocalFlags::ECHO);
+        _ = termios::tcsetattr(&input, SetArg::TCSANOW, &t);
+    }
+    let pfd = PollFd::new(input.as_fd(), PollFlags::POLLIN);
+    let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+    let res = poll::poll(&mut [pfd], timeout);
+    if let Some(o) = &original {
+        _ = termios::tcsetattr(&input, SetArg::TCSANOW, o);
+    }
+    match res {
+        Ok(n) => Ok(n > 0),
+        Err(Errno::EINTR) => Ok(false),
+        Err(e) => Err(e.to_string()),
     }
 }
@@ -0,0 +1,493 @@
+use super::{catch_signals, poll_stdin, Query, Violation, TICK};
+use crate::clock::now;
+use crate::policy::audit::OBJECT_ATTRS;
+use crate::policy::journal::JournalAction;
+use crate::policy::protocol::parse_queries;
+use crate::policy::responder::Answer;
+use crate::policy::suggest::{preview, Suggestion};
+use crate::policy::BlockLines;
+use crate::ui::audit::style_audit_message;
+use crate::ui::theme::{epaint, Style};
+use console::{truncate_str, Key, Term};
+use nix::sys::termios::{self, LocalFlags, SetArg, Termios};
+use std::io::{stderr, stdin, ErrorKind, IsTerminal, Write};
+use std::time::Duration;
+
+const HELP: &str =
+    "up/down select  y permit  n deny  r re-evaluate  a add rule  s reload ACL  f filter  o opt-in  q quit";
+
+/// Pending is a query waiting for an answer, with the time of its arrival and
+/// the related ACL blocks fetched on demand.
+///
+struct Pending {
+    violation: Violation,
+    arrived: u64,
+    acl: Option<String>,
+}
+
+/// Prompt is the line edited in the bottom row.
+///
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Prompt {
+    Rule,
+    Filter,
+    Optin,
+}
+
+impl Prompt {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Prompt::Rule => "enter a new rule: ",
+            Prompt::Filter => "filter pattern: ",
+            Prompt::Optin => "opt-in query pattern: ",
+        }
+    }
+}
+
+/// Draft is the ACL block and the suggested rules for the rule entered in
+/// the prompt.
+///
+struct Draft {
+    header: String,
+    block: Option<BlockLines>,
+    suggestions: Vec<Suggestion>,
+    selected: usize,
+}
+
+#[derive(Default)]
+struct Screen {
+    pending: Vec<Pending>,
+    selected: usize,
+    prompt: Option<(Prompt, String)>,
+    draft: Option<Draft>,
+    status: String,
+    answered: usize,
+    auto_denied: usize,
+    timed_out: usize,
+}
+
+/// TerminalGuard switches the terminal to the alternate screen with
+/// non-canonical input, and restores it when dropped.
+///
+struct TerminalGuard {
+    original: Termios,
+}
+
+impl TerminalGuard {
+    fn new() -> Result<Self, String> {
+        let original = termios::tcgetattr(stdin()).map_err(|e| e.to_string())?;
+        let mut t = original.clone();
+        t.local_flags.remove(LocalFlags::ICANON | LocalFlags::ECHO);
+        termios::tcsetattr(stdin(), SetArg::TCSANOW, &t).map_err(|e| e.to_string())?;
+        eprint!("\x1B[?1049h\x1B[?25l");
+        Ok(TerminalGuard { original })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        eprint!("\x1B[?25h\x1B[?1049l");
+        _ = termios::tcsetattr(stdin(), SetArg::TCSANOW, &self.original);
+    }
+}
+
+impl Query {
+    /// run_tui handles policy violations in a full-screen interface. Pending
+    /// queries are listed in arrival order and can be answered in any order,
+    /// while new queries are added to the list.
+    ///
+    /// When the session stops with `q`, SIGINT or SIGTERM, the terminal is
+    /// restored and pending queries are answered with the verdict for exit.
+    ///
+    pub fn run_tui(&mut self) -> Result<(), String> {
+        if !stdin().is_terminal() || !stderr().is_terminal() {
+            return Err(String::from(
+                "the full-screen interface requires a terminal",
+            ));
+        }
+        catch_signals()?;
+        let mut screen = Screen::default();
+        // the terminal is restored before the queries left are answered
+        let res = TerminalGuard::new().and_then(|_guard| self.tui_loop(&mut screen));
+        let left = screen.pending.into_iter().map(|p| p.violation).collect();
+        self.finish(left, res)
+    }
+
+    //tui_loop draws the screen and handles keys until the session is
+    //requested to stop. The violations left unanswered stay in the screen.
+    //
+    fn tui_loop(&mut self, screen: &mut Screen) -> Result<(), String> {
+        let term = Term::stderr();
+        let mut drawn = None;
+        while !self.stop_requested() {
+            if let Some(text) = self.backend.poll_query(Duration::ZERO)? {
+                self.record(&text)?;
+                self.enqueue(screen, &text)?;
+                drawn = None;
+            }
+            if self.expire(screen)? {
+                drawn = None;
+            }
+            if let Some(p) = screen.pending.get_mut(screen.selected) {
+                if p.acl.is_none() {
+                    p.acl = Some(
+                        self.backend
+                            .select_query(&p.violation.id)
+                            .unwrap_or_default(),
+                    );
+                }
+            }
+            // redraw for changes and for ages of pending queries
+            if drawn != Some(now()) {
+                self.draw(screen, &term);
+                drawn = Some(now());
+            }
+
+            if !poll_stdin(TICK)? {
+                continue;
+            }
+            let key = match Term::stdout().read_key() {
+                Ok(k) => k,
+                // ^C is raised as SIGINT
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.to_string()),
+            };
+            drawn = None;
+            if !self.handle_key(screen, key)? {
+                self.stopped = Some(String::from("quit"));
+            }
+        }
+        Ok(())
+    }
+
+    //enqueue adds queries in the text read from the query interface to the
+    //pending list. A retried query replaces the pending one with the same id,
+    //and queries unmatched for the filter are denied automatically.
+    //
+    fn enqueue(&mut self, screen: &mut Screen, text: &str) -> Result<(), String> {
+        let batch = parse_queries(text);
+        if let Some(n) = batch.notices.last() {
+            screen.status = n.clone();
+        }
+        for v in batch.queries.iter().map(Violation::from_query) {
+            if let Some(p) = screen.pending.iter_mut().find(|p| p.violation.id == v.id) {
+                p.violation = v;
+                continue;
+            }
+            if self.is_targeted(&v.message) {
+                screen.pending.push(Pending {
+                    violation: v,
+                    arrived: now(),
+                    acl: None,
+                });
+            } else {
+                self.backend.answer_query(&v.id, Answer::Deny.code())?;
+                self.journal(&v, JournalAction::Deny, None, Some(String::from("filter")))?;
+                screen.auto_denied += 1;
+            }
+        }
+        Ok(())
+    }
+
+    //expire answers pending queries waiting longer than the answer timeout
+    //with the verdict for timeout. It returns true if any query expired.
+    //
+    fn expire(&mut self, screen: &mut Screen) -> Result<bool, String> {
+        let secs = match self.answer_timeout {
+            Some(t) => t.as_secs(),
+            None => return Ok(false),
+        };
+        let answer = self.on_timeout;
+        let before = screen.pending.len();
+        let mut i = 0;
+        while i < screen.pending.len() {
+            if now().saturating_sub(screen.pending[i].arrived) < secs {
+                i += 1;
+                continue;
+            }
+            let p = screen.pending.remove(i);
+            if i < screen.selected {
+                screen.selected -= 1;
+            }
+            self.backend.answer_query(&p.violation.id, answer.code())?;
+            self.journal(
+                &p.violation,
+                answer.into(),
+                None,
+                Some(format!("timeout: {}s", secs)),
+            )?;
+            screen.timed_out += 1;
+            screen.status = format!("Q{}: timed out, {}", p.violation.id, answer.as_str());
+        }
+        screen.selected = screen.selected.min(screen.pending.len().saturating_sub(1));
+        Ok(screen.pending.len() != before)
+    }
+
+    //handle_key handles a key for the list or the prompt. It returns false to
+    //quit the interface.
+    //
+    fn handle_key(&mut self, screen: &mut Screen, key: Key) -> Result<bool, String> {
+        if let Some((prompt, mut buf)) = screen.prompt.take() {
+            match key {
+                Key::Char(c) if !c.is_control() => buf.push(c),
+                Key::Backspace => _ = buf.pop(),
+                Key::Escape => {
+                    screen.draft = None;
+                    return Ok(true);
+                }
+                Key::Enter => {
+                    screen.draft = None;
+                    self.submit(screen, prompt, &buf)?;
+                    return Ok(true);
+                }
+                // select another suggestion into the prompt
+                Key::ArrowUp | Key::ArrowDown | Key::Tab | Key::BackTab => {
+                    if let Some(d) = screen.draft.as_mut().filter(|d| !d.suggestions.is_empty()) {
+                        let n = d.suggestions.len();
+                        d.selected = match key {
+                            Key::ArrowUp | Key::BackTab => (d.selected + n - 1) % n,
+                            _ => (d.selected + 1) % n,
+                        };
+                        buf = d.suggestions[d.selected].rule.clone();
+                    }
+                }
+                _ => {}
+            }
+            screen.prompt = Some((prompt, buf));
+            return Ok(true);
+        }
+
+        let last = screen.pending.len().saturating_sub(1);
+        match key {
+            Key::ArrowUp | Key::Char('k') => screen.selected = screen.selected.saturating_sub(1),
+            Key::ArrowDown | Key::Char('j') => screen.selected = (screen.selected + 1).min(last),
+            Key::Home => screen.selected = 0,
+            Key::End => screen.selected = last,
+            Key::Char('y' | 'Y') => self.answer(screen, Answer::Permit)?,
+            Key::Char('n' | 'N') => self.answer(screen, Answer::Deny)?,
+            Key::Char('r' | 'R') => self.answer(screen, Answer::Reevaluate)?,
+            Key::Char('s' | 'S') => {
+                if let Some(p) = screen.pending.get_mut(screen.selected) {
+                    p.acl = None;
+                }
+            }
+            Key::Char('a' | 'A') if !screen.pending.is_empty() => {
+                let v = screen.pending[screen.selected].violation.clone();
+                let (header, block, suggestions) = self.suggest(&v);
+                let buf = match suggestions.first() {
+                    Some(s) => s.rule.clone(),
+                    None => self.default_rule_text(),
+                };
+                screen.draft = Some(Draft {
+                    header,
+                    block,
+                    suggestions,
+                    selected: 0,
+                });
+                screen.prompt = Some((Prompt::Rule, buf));
+            }
+            Key::Char('f' | 'F') => {
+                screen.prompt = Some((Prompt::Filter, self.filter.as_str().to_string()))
+            }
+            Key::Char('o' | 'O') => screen.prompt = Some((Prompt::Optin, String::new())),
+            Key::Char('q' | 'Q') => return Ok(false),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    //answer answers the selected query and removes it from the list.
+    //
+    fn answer(&mut self, screen: &mut Screen, answer: Answer) -> Result<(), String> {
+        if screen.pending.is_empty() {
+            return Ok(());
+        }
+        let p = screen.pending.remove(screen.selected);
+        self.backend.answer_query(&p.violation.id, answer.code())?;
+        self.journal(&p.violation, answer.into(), None, None)?;
+        screen.answered += 1;
+        screen.selected = screen.selected.min(screen.pending.len().saturating_sub(1));
+        screen.status = format!("Q{}: {}", p.violation.id, answer.as_str());
+        Ok(())
+    }
+
+    //submit applies the line entered in the prompt.
+    //
+    fn submit(&mut self, screen: &mut Screen, prompt: Prompt, line: &str) -> Result<(), String> {
+        match prompt {
+            Prompt::Rule => {
+                let v = match screen.pending.get(screen.selected) {
+                    Some(p) => p.violation.clone(),
+                    None => return Ok(()),
+                };
+                self.rule_addition_history.push(line.to_string());
+                screen.status = match self.apply_rule(&v, line) {
+                    Ok(header) => {
+                        self.journal(
+                            &v,
+                            JournalAction::Add,
+                            Some((header.clone(), line.trim().to_string())),
+                            None,
+                        )?;
+                        screen.pending[screen.selected].acl = None;
+                        format!("added to {} (r to re-evaluate)", header)
+                    }
+                    Err(e) => e,
+                };
+            }
+            Prompt::Filter => {
+                screen.status = match regex::Regex::new(line) {
+                    Ok(p) => {
+                        self.filter = p;
+                        self.optin_filter.clear();
+                        String::from("filter updated")
+                    }
+                    Err(e) => e.to_string(),
+                };
+            }
+            Prompt::Optin => {
+                screen.status = match regex::Regex::new(line) {
+                    Ok(p) => {
+                        self.optin_filter.push(p);
+                        String::from("opt-in filter added")
+                    }
+                    Err(e) => e.to_string(),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    //draw draws the title, the list of pending queries, the detail of the
+    //selected query with its related ACL, the status bar and the help or the
+    //prompt. While a rule is entered, the suggested rules and the preview of
+    //the ACL block with the rule are drawn instead of the related ACL.
+    //
+    fn draw(&self, screen: &Screen, term: &Term) {
+        let (rows, cols) = term.size();
+        let (rows, cols) = (rows as usize, cols as usize);
+        let list_height = ((rows.saturating_sub(4)) / 3).max(3);
+        let mut title = format!(
+            " acquery query: {} pending, {} answered, {} denied automatically",
+            screen.pending.len(),
+            screen.answered,
+            screen.auto_denied,
+        );
+        if let Some(t) = self.answer_timeout {
+            title += &format!(
+                ", {} timed out ({} after {}s)",
+                screen.timed_out,
+                self.on_timeout.as_str(),
+                t.as_secs()
+            );
+        }
+        let mut lines = vec![epaint(
+            Style::Notice,
+            format!("{}{}", title, " ".repeat(cols)),
+        )];
+        let timeout = self.answer_timeout.map(|t| t.as_secs());
+
+        let start = (screen.selected + 1).saturating_sub(list_height);
+        for i in start..start + list_height {
+            lines.push(match screen.pending.get(i) {
+                Some(p) if i == screen.selected => epaint(
+                    Style::Selected,
+                    format!("> {}{}", summary(p, timeout), " ".repeat(cols)),
+                ),
+                Some(p) => format!("  {}", summary(p, timeout)),
+                None if screen.pending.is_empty() && i == 0 => {
+                    epaint(Style::Dim, "  waiting for policy violations...")
+                }
+                None => String::new(),
+            });
+        }
+        lines.push(epaint(Style::Dim, "-".repeat(cols)));
+
+        if let Some(p) = screen.pending.get(screen.selected) {
+            match (&screen.draft, &screen.prompt) {
+                // the record is summarized in the list to leave rows for the preview
+                (Some(d), Some((Prompt::Rule, buf))) => {
+                    lines.push(epaint(
+                        Style::Selected,
+                        "[suggested rules] up/down select  enter add  esc cancel",
+                    ));
+                    for (i, s) in d.suggestions.iter().enumerate() {
+                        let marker = if i == d.selected { ">" } else { " " };
+                        lines.push(format!("{} {:<10} {}", marker, s.scope, s.rule));
+                    }
+                    lines.push(String::new());
+                    lines.push(epaint(Style::Selected, "[preview]"));
+                    lines.extend(
+                        preview(&d.header, d.block.as_ref(), buf)
+                            .into_iter()
+                            .map(|l| match l.chars().next() {
+                                Some('+') => epaint(Style::Added, l),
+                                Some('!') => epaint(Style::Warning, l),
+                                _ => l,
+                            }),
+                    );
+                }
+                _ => {
+                    let record = style_audit_message(p.violation.message.clone());
+                    lines.extend(record.lines().map(|l| l.to_string()));
+                    lines.push(String::new());
+                    lines.push(epaint(Style::Selected, "[selected policy]"));
+                    let acl = p.acl.as_deref().unwrap_or_default();
+                    lines.extend(
+                        acl.lines()
+                            .filter(|s| !s.starts_with('#') && !s.is_empty())
+                            .map(|s| epaint(Style::Added, s)),
+                    );
+                }
+            }
+        }
+
+        let bottom = match &screen.prompt {
+            Some((prompt, buf)) => format!("{}{}_", prompt.as_str(), buf),
+            None => epaint(Style::Dim, HELP),
+        };
+        let mut status = format!("filter: {}", self.filter.as_str());
+        for f in &self.optin_filter {
+            status += &format!("  opt-in: {}", f.as_str());
+        }
+        if !screen.status.is_empty() {
+            status += &format!("  | {}", screen.status);
+        }
+        lines.resize(rows.saturating_sub(2), String::new());
+        lines.push(epaint(Style::Bold, status));
+        lines.push(bottom);
+
+        let frame: Vec<String> = lines
+            .iter()
+            .map(|l| format!("{}\x1B[K", truncate_str(l, cols, "")))
+            .collect();
+        let mut err = stderr();
+        _ = write!(err, "\x1B[H{}", frame.join("\r\n"));
+        _ = err.flush();
+    }
+}
+
+/// summary returns a line for the pending query with its age, the seconds
+/// left to the answer timeout, the operation, the executable and the object.
+///
+fn summary(p: &Pending, timeout: Option<u64>) -> String {
+    let v = &p.violation;
+    let object: Vec<String> = OBJECT_ATTRS
+        .iter()
+        .filter_map(|k| v.attrs.get(*k).map(|a| format!("{}={}", k, a)))
+        .collect();
+    let age = now().saturating_sub(p.arrived);
+    let left = match timeout {
+        Some(t) => format!("  {:>4}s left", t.saturating_sub(age)),
+        None => String::new(),
+    };
+    format!(
+        "Q{:<6} {:>4}s{}  {}  {}  {}",
+        v.id,
+        age,
+        left,
+        v.operation.as_deref().unwrap_or("-"),
+        v.attrs.get("task.exe").map(|e| e.as_str()).unwrap_or("-"),
+        object.join(" ")
+    )
+}
@@ -0,0 +1,220 @@
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::io::{stderr, stdout, IsTerminal};
+use std::sync::OnceLock;
+
+/// PALETTE_ENV is the environment variable to override the palette, with
+/// `style=SGR` pairs separated by `:` such as `audit.exe=1;31:added=34`.
+///
+pub const PALETTE_ENV: &str = "ACQUERY_COLORS";
+
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+/// ColorChoice is the color policy given with `--color`. With `auto`, colors
+/// are used for a terminal unless `NO_COLOR` is set.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+/// Style is a role of text in the output, which is painted with the SGR
+/// parameters of the palette.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Style {
+    Bold,
+    Dim,
+    Added,
+    Removed,
+    Changed,
+    Error,
+    Warning,
+    Info,
+    Notice,
+    Permitted,
+    Rejected,
+    Reevaluated,
+    Selected,
+    AuditUid,
+    AuditExe,
+    AuditUgid,
+    AuditXpid,
+    AuditDomain,
+    AuditPath,
+    AuditPathAttr,
+    AuditTransition,
+}
+
+impl Style {
+    pub fn list() -> Vec<Style> {
+        vec![
+            Style::Bold,
+            Style::Dim,
+            Style::Added,
+            Style::Removed,
+            Style::Changed,
+            Style::Error,
+            Style::Warning,
+            Style::Info,
+            Style::Notice,
+            Style::Permitted,
+            Style::Rejected,
+            Style::Reevaluated,
+            Style::Selected,
+            Style::AuditUid,
+            Style::AuditExe,
+            Style::AuditUgid,
+            Style::AuditXpid,
+            Style::AuditDomain,
+            Style::AuditPath,
+            Style::AuditPathAttr,
+            Style::AuditTransition,
+        ]
+    }
+
+    /// as_str returns the name of the style in the palette.
+    ///
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Style::Bold => "bold",
+            Style::Dim => "dim",
+            Style::Added => "added",
+            Style::Removed => "removed",
+            Style::Changed => "changed",
+            Style::Error => "error",
+            Style::Warning => "warning",
+            Style::Info => "info",
+            Style::Notice => "notice",
+            Style::Permitted => "permitted",
+            Style::Rejected => "rejected",
+            Style::Reevaluated => "reevaluated",
+            Style::Selected => "selected",
+            Style::AuditUid => "audit.uid",
+            Style::AuditExe => "audit.exe",
+            Style::AuditUgid => "audit.ugid",
+            Style::AuditXpid => "audit.xpid",
+            Style::AuditDomain => "audit.domain",
+            Style::AuditPath => "audit.path",
+            Style::AuditPathAttr => "audit.path_attr",
+            Style::AuditTransition => "audit.transition",
+        }
+    }
+
+    /// default_sgr returns the SGR parameters of the style in the default
+    /// palette.
+    ///
+    pub fn default_sgr(&self) -> &'static str {
+        match self {
+            Style::Bold => "1",
+            Style::Dim => "2",
+            Style::Added => "32",
+            Style::Removed => "31",
+            Style::Changed => "36",
+            Style::Error => "31",
+            Style::Warning => "33",
+            Style::Info => "36",
+            Style::Notice => "7",
+            Style::Permitted => "42;30",
+            Style::Rejected => "7;33",
+            Style::Reevaluated => "44;30",
+            Style::Selected => "47;30",
+            Style::AuditUid => "33",
+            Style::AuditExe => "31",
+            Style::AuditUgid => "33",
+            Style::AuditXpid => "34",
+            Style::AuditDomain => "31",
+            Style::AuditPath => "7;33",
+            Style::AuditPathAttr => "33",
+            Style::AuditTransition => "5;31",
+        }
+    }
+}
+
+/// Theme is the color policy for the standard output and the standard error
+/// with the palette.
+///
+#[derive(Clone, Debug)]
+pub struct Theme {
+    pub stdout: bool,
+    pub stderr: bool,
+    palette: HashMap<Style, String>,
+}
+
+impl Theme {
+    /// new decides the color policy for each stream and reads the palette
+    /// overridden with PALETTE_ENV. Invalid entries of the palette are
+    /// reported and ignored.
+    ///
+    pub fn new(choice: ColorChoice) -> Self {
+        let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+        let enabled = |is_terminal: bool| match choice {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => is_terminal && !no_color,
+        };
+        let mut palette: HashMap<Style, String> = Style::list()
+            .into_iter()
+            .map(|s| (s, s.default_sgr().to_string()))
+            .collect();
+        if let Ok(spec) = env::var(PALETTE_ENV) {
+            for entry in spec.split(':').filter(|e| !e.is_empty()) {
+                match parse_palette_entry(entry) {
+                    Some((style, sgr)) => _ = palette.insert(style, sgr),
+                    None => eprintln!("{}: invalid entry ignored: {}", PALETTE_ENV, entry),
+                }
+            }
+        }
+        Theme {
+            stdout: enabled(stdout().is_terminal()),
+            stderr: enabled(stderr().is_terminal()),
+            palette,
+        }
+    }
+
+    fn paint(&self, enabled: bool, style: Style, text: impl Display) -> String {
+        match enabled {
+            true => format!("\x1B[{}m{}\x1B[0m", self.palette[&style], text),
+            false => text.to_string(),
+        }
+    }
+}
+
+fn parse_palette_entry(entry: &str) -> Option<(Style, String)> {
+    let (name, sgr) = entry.split_once('=')?;
+    let style = Style::list().into_iter().find(|s| s.as_str() == name)?;
+    let valid = !sgr.is_empty()
+        && sgr
+            .split(';')
+            .all(|p| !p.is_empty() && p.len() <= 3 && p.chars().all(|c| c.is_ascii_digit()));
+    valid.then(|| (style, sgr.to_string()))
+}
+
+/// init sets the theme of the process. It is effective only for the first
+/// call, and the theme defaults to ColorChoice::Auto without the call.
+///
+pub fn init(choice: ColorChoice) {
+    _ = THEME.set(Theme::new(choice));
+}
+
+pub fn current() -> &'static Theme {
+    THEME.get_or_init(|| Theme::new(ColorChoice::Auto))
+}
+
+/// paint paints text for the standard output.
+///
+pub fn paint(style: Style, text: impl Display) -> String {
+    let t = current();
+    t.paint(t.stdout, style, text)
+}
+
+/// epaint paints text for the standard error.
+///
+pub fn epaint(style: Style, text: impl Display) -> String {
+    let t = current();
+    t.paint(t.stderr, style, text)
+}